@@ -0,0 +1,101 @@
+//! Memory usage of transactions stored in accounts.
+//!
+//! Input file is generated with `toy_trx_engine::generator` (fixed seed) and processed with simple engine, then
+//! heap memory taken by accounts is measured with counting allocator. Stored transactions of every account are
+//! also rebuilt as `TrxStore` and as `HashMap<TTrxID, TrxEntry>` (former storage) to compare both.
+//! Size of generated input may be changed with `BENCH_SIZE` environment variable (default: 1000000 records,
+//! numbers quoted in `store` module were measured also with 10000000 and 100000000 records). Number of clients
+//! is a fifth of records up to 65535 (maximal client ID), so larger input has more transactions per account.
+//! Run with: `cargo bench --bench memory`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use argh::FromArgs;
+
+use toy_trx_engine::{
+    Args, TTrxID, process,
+    accounts::TrxEntry,
+    generator::{GenConfig, TypeMix, generate},
+    store::TrxStore,
+};
+
+/// Allocator counting bytes currently allocated on heap.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// Returns heap memory taken by value built by `build`.
+fn measure<T>(build: impl FnOnce() -> T) -> usize {
+    let before = allocated();
+    let value = build();
+    let size = allocated() - before;
+    drop(value);
+    size
+}
+
+fn bench_size() -> u32 {
+    std::env::var("BENCH_SIZE").ok().and_then(|s| s.parse().ok()).unwrap_or(1_000_000)
+}
+
+fn main() {
+    let size = bench_size();
+    let path = std::env::temp_dir().join(format!("toy_trx_engine_memory_{}.csv", size));
+    let cfg = GenConfig {
+        size,
+        clients: (size / 5).clamp(1, u16::MAX as u32) as u16,
+        mix: TypeMix {deposit: 60, withdrawal: 30, dispute: 6, resolve: 3, chargeback: 1},
+        ..Default::default()
+    };
+    let mut out = BufWriter::new(File::create(&path).expect("input file created"));
+    generate(&cfg, &mut out).expect("generated input");
+    drop(out);
+    let path = path.to_str().expect("temp path is valid unicode").to_string();
+    let arg = Args::from_args(&["toy_trx_engine"], &[&path, "--engine", "simple"]).expect("correct command line");
+
+    let before = allocated();
+    let (_, accounts) = async_std::task::block_on(process(&arg)).expect("success");
+    let live = allocated() - before;
+    let stored: usize = accounts.values().map(|acct| acct.transactions.len()).sum();
+    let (mut map, mut store) = (0, 0);
+    for acct in accounts.values() {
+        map += measure(|| acct.transactions.iter().collect::<HashMap<TTrxID, TrxEntry>>());
+        store += measure(|| acct.transactions.iter().collect::<TrxStore>());
+    }
+    let _ = std::fs::remove_file(&path);
+
+    let per_trx = |bytes: usize| bytes as f64 / stored.max(1) as f64;
+    println!("records: {}, accounts: {}, stored transactions: {}", size, accounts.len(), stored);
+    println!("accounts after processing: {} bytes ({:.1} per stored transaction)", live, per_trx(live));
+    println!("HashMap<TTrxID, TrxEntry>: {} bytes ({:.1} per stored transaction)", map, per_trx(map));
+    println!("TrxStore:                  {} bytes ({:.1} per stored transaction)", store, per_trx(store));
+    println!("reduction: {:.1}x", map as f64 / store.max(1) as f64);
+}
@@ -0,0 +1,429 @@
+//! Benchmarks of processing pipeline.
+//!
+//! Input files are generated with `toy_trx_engine::generator` (fixed seed), so results are reproducible.
+//! Size of generated input may be changed with `BENCH_SIZE` environment variable (default: 100000 records).
+//! Run with: `cargo bench --bench pipeline`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+use argh::FromArgs;
+use async_std::stream::StreamExt;
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use csv_async::{AsyncReaderBuilder, ByteRecord};
+use rust_decimal::Decimal;
+
+use toy_trx_engine::{
+    Args, TClientId, process,
+    accounts::AccountState,
+    generator::{GenConfig, TypeMix, generate},
+    parser::{Record, RecordReader},
+    transactions::{Transaction, TransactionInt, TransactionRec, TransactionRecType},
+};
+
+fn bench_size() -> u32 {
+    std::env::var("BENCH_SIZE").ok().and_then(|s| s.parse().ok()).unwrap_or(100_000)
+}
+
+fn gen_input(size: u32) -> Vec<u8> {
+    let cfg = GenConfig {
+        size,
+        clients: (size / 5).clamp(1, u16::MAX as u32) as u16,
+        mix: TypeMix {deposit: 60, withdrawal: 30, dispute: 6, resolve: 3, chargeback: 1},
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    generate(&cfg, &mut out).expect("generated input");
+    out
+}
+
+fn rec(ttype: TransactionRecType, tx: u32, amount: Option<f64>) -> Transaction {
+    Transaction::try_from(TransactionRec {ttype, client: 1, tx, amount, timestamp: None, reason: None, currency: None, to_currency: None}).expect("valid transaction")
+}
+
+/// CSV parsing and conversion of records to transactions: serde only and fast path (with serde fallback).
+fn parse(c: &mut Criterion) {
+    let size = bench_size();
+    let input = gen_input(size);
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(size as u64));
+    group.sample_size(10);
+    group.bench_function("deserialize", |b| b.iter(|| {
+        async_std::task::block_on(async {
+            let mut rdr = AsyncReaderBuilder::new()
+                .trim(csv_async::Trim::All)
+                .flexible(true)
+                .create_deserializer(input.as_slice());
+            let mut records = rdr.deserialize::<TransactionRec>();
+            let mut count = 0usize;
+            while let Some(record) = records.next().await {
+                if let Ok(record) = record {
+                    count += Transaction::try_from(record).is_ok() as usize;
+                }
+            }
+            black_box(count)
+        })
+    }));
+    group.bench_function("fast-path", |b| b.iter(|| {
+        async_std::task::block_on(async {
+            let rdr = AsyncReaderBuilder::new()
+                .trim(csv_async::Trim::All)
+                .flexible(true)
+                .create_deserializer(input.as_slice());
+            let mut records = RecordReader::new(rdr).await.expect("headers read");
+            let mut count = 0usize;
+            while let Some(record) = records.next().await {
+                count += match record {
+                    Ok(Record::Transaction(_)) => 1,
+                    Ok(Record::Rec(rec)) => Transaction::try_from(rec).is_ok() as usize,
+                    Err(_) => 0,
+                };
+            }
+            black_box(count)
+        })
+    }));
+    group.finish();
+}
+
+/// Cost of single commit of each transaction type.
+fn commit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("commit");
+    
+    let mut accounts = HashMap::<TClientId,AccountState>::new();
+    let mut tx = 0u32;
+    group.bench_function("deposit", |b| b.iter(|| {
+        tx += 1;
+        rec(TransactionRecType::Deposit, tx, Some(1.0)).commit(&mut accounts)
+    }));
+
+    let mut accounts = HashMap::<TClientId,AccountState>::new();
+    accounts.insert(1, AccountState::with_balance(Decimal::MAX));
+    let mut tx = 0u32;
+    group.bench_function("withdrawal", |b| b.iter(|| {
+        tx += 1;
+        rec(TransactionRecType::Withdrawal, tx, Some(1.0)).commit(&mut accounts)
+    }));
+
+    let deposited = || {
+        let mut accounts = HashMap::<TClientId,AccountState>::new();
+        rec(TransactionRecType::Deposit, 1, Some(1.0)).commit(&mut accounts).expect("deposit");
+        accounts
+    };
+    let disputed = || {
+        let mut accounts = deposited();
+        rec(TransactionRecType::Dispute, 1, None).commit(&mut accounts).expect("dispute");
+        accounts
+    };
+    group.bench_function("dispute", |b| b.iter_batched_ref(
+        deposited, 
+        |accounts| rec(TransactionRecType::Dispute, 1, None).commit(accounts),
+        BatchSize::SmallInput));
+    group.bench_function("resolve", |b| b.iter_batched_ref(
+        disputed, 
+        |accounts| rec(TransactionRecType::Resolve, 1, None).commit(accounts),
+        BatchSize::SmallInput));
+    group.bench_function("chargeback", |b| b.iter_batched_ref(
+        disputed, 
+        |accounts| rec(TransactionRecType::Chargeback, 1, None).commit(accounts),
+        BatchSize::SmallInput));
+    group.finish();
+}
+
+/// End-to-end processing for different number of workers and queue sizes.
+fn end_to_end(c: &mut Criterion) {
+    let size = bench_size();
+    let path: PathBuf = std::env::temp_dir().join(format!("toy_trx_engine_bench_{}.csv", size));
+    std::fs::write(&path, gen_input(size)).expect("input file written");
+    let path = path.to_str().expect("temp path is valid unicode").to_string();
+    let arg0 = "toy_trx_engine";
+
+    let mut group = c.benchmark_group("process");
+    group.throughput(Throughput::Elements(size as u64));
+    group.sample_size(10);
+    for engine in ["simple", "multi"] {
+        for wrk_num in [1, 2, 4, 8] {
+            for wrk_buff in [1, 10, 100] {
+                if engine == "simple" && (wrk_num > 1 || wrk_buff > 1) {
+                    continue;
+                }
+                let id = format!("{}/wrk-num={}/wrk-buff={}", engine, wrk_num, wrk_buff);
+                let (wrk_num, wrk_buff) = (wrk_num.to_string(), wrk_buff.to_string());
+                let arg = Args::from_args(&[arg0], &[&path, "--engine", engine, "--wrk-num", &wrk_num, "--wrk-buff", &wrk_buff])
+                    .expect("correct command line");
+                group.bench_function(BenchmarkId::from_parameter(id), |b| b.iter(|| {
+                    async_std::task::block_on(process(&arg)).expect("success")
+                }));
+            }
+        }
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Capacity (in transactions) of worker queues of `dispatch` benchmarks.
+const DISPATCH_QUEUE: usize = 10_000;
+
+/// Transactions of `input` (parsed in advance for `dispatch` benchmarks).
+fn transactions(input: &[u8]) -> Vec<Transaction> {
+    async_std::task::block_on(async {
+        let rdr = AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .create_deserializer(input);
+        let mut records = RecordReader::new(rdr).await.expect("headers read");
+        let mut transactions = Vec::new();
+        while let Some(record) = records.next().await {
+            match record {
+                Ok(Record::Transaction(transaction)) => transactions.push(transaction),
+                Ok(Record::Rec(rec)) => transactions.extend(Transaction::try_from(rec).ok()),
+                Err(_) => (),
+            }
+        }
+        transactions
+    })
+}
+
+/// Starts `num` workers committing transactions received in messages of type `M`, returns their channels
+/// and handles (which return number of committed transactions).
+fn dispatch_workers<M, F>(num: usize, queue: usize, commit: F) -> (Vec<mpsc::SyncSender<M>>, Vec<thread::JoinHandle<usize>>)
+where
+    M: Send + 'static,
+    F: Fn(M, &mut HashMap<TClientId,AccountState>) -> usize + Copy + Send + 'static,
+{
+    (0..num).map(|_| {
+        let (tx, rx) = mpsc::sync_channel::<M>(queue);
+        let handle = thread::spawn(move || {
+            let mut accounts = HashMap::new();
+            rx.into_iter().map(|msg| commit(msg, &mut accounts)).sum()
+        });
+        (tx, handle)
+    }).unzip()
+}
+
+/// Sends transactions to workers one by one (as processing loop did before batching), returns number of
+/// committed transactions.
+fn dispatch_per_record(transactions: Vec<Transaction>, num_workers: usize) -> usize {
+    let (senders, handles) = dispatch_workers(num_workers, DISPATCH_QUEUE,
+        |(_, transaction): (u128, Transaction), accounts| transaction.commit(accounts).is_ok() as usize);
+    for (rec_no, transaction) in (1u128..).zip(transactions) {
+        let idx = transaction.client_id() as usize % num_workers;
+        senders[idx].send((rec_no, transaction)).expect("worker running");
+    }
+    drop(senders);
+    handles.into_iter().map(|handle| handle.join().expect("worker finished")).sum()
+}
+
+/// Sends transactions to workers in batches of `batch_size`, returns number of committed transactions.
+fn dispatch_batched(transactions: Vec<Transaction>, num_workers: usize, batch_size: usize) -> usize {
+    let (senders, handles) = dispatch_workers(num_workers, (DISPATCH_QUEUE / batch_size).max(1),
+        |batch: Vec<(u128, Transaction)>, accounts| batch.into_iter()
+            .map(|(_, transaction)| transaction.commit(accounts).is_ok() as usize)
+            .sum());
+    let mut batches: Vec<Vec<(u128, Transaction)>> = (0..num_workers).map(|_| Vec::with_capacity(batch_size)).collect();
+    for (rec_no, transaction) in (1u128..).zip(transactions) {
+        let idx = transaction.client_id() as usize % num_workers;
+        batches[idx].push((rec_no, transaction));
+        if batches[idx].len() >= batch_size {
+            let batch = std::mem::replace(&mut batches[idx], Vec::with_capacity(batch_size));
+            senders[idx].send(batch).expect("worker running");
+        }
+    }
+    for (sender, batch) in senders.iter().zip(batches) {
+        if !batch.is_empty() {
+            sender.send(batch).expect("worker running");
+        }
+    }
+    drop(senders);
+    handles.into_iter().map(|handle| handle.join().expect("worker finished")).sum()
+}
+
+/// Transactions sent to 4 workers one by one (`dispatch/per-record` - channel of single transactions, as before
+/// batching) and in batches of different sizes (`dispatch/batch-size=N`), on transactions parsed in advance, so
+/// that only dispatching and commits are measured; and end-to-end processing (multi engine, 4 workers) with
+/// different `--batch-size` (`process/batch-size=N`, batch size 1 still sends vector of one transaction).
+fn batching(c: &mut Criterion) {
+    let size = bench_size();
+    let input = gen_input(size);
+    let path: PathBuf = std::env::temp_dir().join(format!("toy_trx_engine_bench_{}.csv", size));
+    std::fs::write(&path, &input).expect("input file written");
+    let path = path.to_str().expect("temp path is valid unicode").to_string();
+    let arg0 = "toy_trx_engine";
+    assert_eq!(dispatch_per_record(transactions(&input), 4), dispatch_batched(transactions(&input), 4, 64));
+
+    let mut group = c.benchmark_group("batching");
+    group.throughput(Throughput::Elements(size as u64));
+    group.sample_size(10);
+    group.bench_function("dispatch/per-record", |b| b.iter_batched(
+        || transactions(&input),
+        |transactions| dispatch_per_record(transactions, 4),
+        BatchSize::LargeInput));
+    for batch_size in [16, 64, 256, 1024] {
+        let id = format!("dispatch/batch-size={}", batch_size);
+        group.bench_function(BenchmarkId::from_parameter(id), |b| b.iter_batched(
+            || transactions(&input),
+            |transactions| dispatch_batched(transactions, 4, batch_size),
+            BatchSize::LargeInput));
+    }
+    for batch_size in [1, 16, 64, 256, 1024] {
+        let id = format!("process/batch-size={}", batch_size);
+        let batch_size = batch_size.to_string();
+        let arg = Args::from_args(&[arg0], &[&path, "--wrk-num", "4", "--batch-size", &batch_size])
+            .expect("correct command line");
+        group.bench_function(BenchmarkId::from_parameter(id), |b| b.iter(|| {
+            async_std::task::block_on(process(&arg)).expect("success")
+        }));
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Money backend compared by `money` benchmarks.
+trait Money: Copy + Default + PartialOrd {
+    /// Parses amount field of record (without allocation).
+    fn parse(field: &[u8]) -> Option<Self>;
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn checked_sub(self, other: Self) -> Option<Self>;
+    fn half(self) -> Self;
+}
+
+/// `rust_decimal` - current `TMoney` backend.
+impl Money for Decimal {
+    fn parse(field: &[u8]) -> Option<Self> {
+        std::str::from_utf8(field).ok()?.parse().ok()
+    }
+    fn checked_add(self, other: Self) -> Option<Self> {
+        Decimal::checked_add(self, other)
+    }
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        Decimal::checked_sub(self, other)
+    }
+    fn half(self) -> Self {
+        self / Decimal::TWO
+    }
+}
+
+/// 128-bit fixed-point integer with 4 decimal places, considered in README as alternative backend.
+#[derive(Clone, Copy, Default, PartialEq, PartialOrd)]
+struct Fixed(i128);
+
+impl Money for Fixed {
+    fn parse(field: &[u8]) -> Option<Self> {
+        let (negative, field) = match field.split_first() {
+            Some((b'-', rest)) => (true, rest),
+            _ => (false, field),
+        };
+        let (int, frac) = match field.iter().position(|&b| b == b'.') {
+            Some(dot) => (&field[..dot], &field[dot + 1..]),
+            None => (field, &b""[..]),
+        };
+        if int.is_empty() || frac.len() > 4 || !int.iter().chain(frac).all(u8::is_ascii_digit) {
+            return None;
+        }
+        let digits = |digits: &[u8]| digits.iter().try_fold(0i128, |value, &b| value.checked_mul(10)?.checked_add((b - b'0') as i128));
+        let value = digits(int)?.checked_mul(10_000)?.checked_add(digits(frac)? * 10i128.pow(4 - frac.len() as u32))?;
+        Some(Fixed(if negative {-value} else {value}))
+    }
+    fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Fixed)
+    }
+    fn checked_sub(self, other: Self) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Fixed)
+    }
+    fn half(self) -> Self {
+        Fixed(self.0 / 2)
+    }
+}
+
+/// Amounts of generated input parsed with backend `M`.
+fn amounts<M: Money>(input: &[u8]) -> Vec<M> {
+    input.split(|&b| b == b'\n')
+        .filter_map(|line| line.rsplit(|&b| b == b',').next())
+        .filter_map(M::parse)
+        .collect()
+}
+
+/// Balance changes on amounts parsed in advance (no parsing and no allocation in measured loop).
+fn arithmetic<M: Money>(amounts: &[M]) -> Option<M> {
+    amounts.iter().try_fold(M::default(), |balance, amount| balance.checked_add(*amount)?.checked_sub(amount.half()))
+}
+
+/// Parses `i`-th field of record (without allocation).
+fn parse_field<T: std::str::FromStr>(record: &ByteRecord, i: usize) -> Option<T> {
+    std::str::from_utf8(record.get(i)?).ok()?.parse().ok()
+}
+
+/// Applies records of generated `input` to balances of clients with backend `M`, returns number of committed
+/// records. Simplified commit rules of transaction types (deposited transactions kept in hash map), so that
+/// backends are compared on the same processing from input bytes to final balances.
+fn replay<M: Money>(input: &[u8]) -> usize {
+    async_std::task::block_on(async {
+        let mut rdr = AsyncReaderBuilder::new()
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .create_reader(input);
+        let mut record = ByteRecord::new();
+        let mut accounts = HashMap::<TClientId, (M, M)>::new();
+        let mut deposits = HashMap::<(TClientId, u32), (M, bool)>::new();
+        let mut committed = 0usize;
+        while rdr.read_byte_record(&mut record).await.unwrap_or(false) {
+            let (Some(client), Some(tx)) = (parse_field(&record, 1), parse_field(&record, 2)) else {
+                continue;
+            };
+            let amount = record.get(3).and_then(M::parse);
+            let (available, held) = accounts.entry(client).or_default();
+            let result = match record.get(0) {
+                Some(b"deposit") => amount.and_then(|amount| {
+                    *available = available.checked_add(amount)?;
+                    deposits.insert((client, tx), (amount, false));
+                    Some(())
+                }),
+                Some(b"withdrawal") => amount.and_then(|amount| {
+                    *available = available.checked_sub(amount).filter(|rest| *rest >= M::default())?;
+                    Some(())
+                }),
+                Some(ttype) => deposits.get_mut(&(client, tx)).and_then(|(amount, disputed)| {
+                    match (ttype, *disputed) {
+                        (b"dispute", false) => (*available, *held) = (available.checked_sub(*amount)?, held.checked_add(*amount)?),
+                        (b"resolve", true) => (*available, *held) = (available.checked_add(*amount)?, held.checked_sub(*amount)?),
+                        (b"chargeback", true) => *held = held.checked_sub(*amount)?,
+                        _ => return None,
+                    }
+                    *disputed = ttype == b"dispute";
+                    Some(())
+                }),
+                None => None,
+            };
+            committed += result.is_some() as usize;
+        }
+        committed
+    })
+}
+
+/// Money backends: `rust_decimal` (current `TMoney` backend) compared with 128-bit fixed-point integers
+/// (4 decimal places) - balance arithmetic on the same amounts parsed in advance and end-to-end replay
+/// of generated input (amounts parsing and commits).
+fn money(c: &mut Criterion) {
+    let size = bench_size();
+    let input = gen_input(size);
+    let (decimals, fixed) = (amounts::<Decimal>(&input), amounts::<Fixed>(&input));
+    assert_eq!(decimals.len(), fixed.len());
+    assert_eq!(replay::<Decimal>(&input), replay::<Fixed>(&input));
+
+    let mut group = c.benchmark_group("money");
+    group.throughput(Throughput::Elements(decimals.len() as u64));
+    group.bench_function("arithmetic/decimal", |b| b.iter(|| arithmetic(black_box(&decimals))));
+    group.bench_function("arithmetic/i128", |b| b.iter(|| arithmetic(black_box(&fixed))));
+    group.finish();
+
+    let mut group = c.benchmark_group("money-end-to-end");
+    group.throughput(Throughput::Elements(size as u64));
+    group.sample_size(10);
+    group.bench_function("decimal", |b| b.iter(|| replay::<Decimal>(black_box(&input))));
+    group.bench_function("i128", |b| b.iter(|| replay::<Fixed>(black_box(&input))));
+    group.finish();
+}
+
+criterion_group!(benches, parse, commit, end_to_end, batching, money);
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+//! Drives arbitrary sequences of transaction records through `Transaction::try_from` and `commit`,
+//! checking that account invariants are preserved after every transaction.
+//! Administrative records are included (fuzzed input is treated as privileged).
+#![no_main]
+
+use std::collections::HashMap;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use toy_trx_engine::{
+    TClientId, TTrxID,
+    accounts::AccountState,
+    transactions::{Transaction, TransactionInt, TransactionRec, TransactionRecType, TransactionValid},
+};
+
+#[derive(Arbitrary, Debug)]
+enum RecType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Unlock,
+    Adjust,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Rec {
+    ttype: RecType,
+    client: u8,   // small ranges of clients and tx IDs, so that records reference each other
+    tx: u8,
+    amount: Option<f64>,
+    timestamp: Option<i64>,
+    reason: Option<String>,
+}
+
+impl From<&Rec> for TransactionRec {
+    fn from(rec: &Rec) -> Self {
+        TransactionRec {
+            ttype: match rec.ttype {
+                RecType::Deposit => TransactionRecType::Deposit,
+                RecType::Withdrawal => TransactionRecType::Withdrawal,
+                RecType::Dispute => TransactionRecType::Dispute,
+                RecType::Resolve => TransactionRecType::Resolve,
+                RecType::Chargeback => TransactionRecType::Chargeback,
+                RecType::Unlock => TransactionRecType::Unlock,
+                RecType::Adjust => TransactionRecType::Adjust,
+            },
+            client: rec.client as TClientId,
+            tx: rec.tx as TTrxID,
+            amount: rec.amount,
+            timestamp: rec.timestamp,
+            reason: rec.reason.clone(),
+            currency: None,
+            to_currency: None,
+        }
+    }
+}
+
+fuzz_target!(|recs: Vec<Rec>| {
+    let mut accounts = HashMap::<TClientId,AccountState>::new();
+    for rec in &recs {
+        let privileged = matches!(rec.ttype, RecType::Unlock | RecType::Adjust);
+        let Ok(transaction) = Transaction::try_from(TransactionRec::from(rec)) else {
+            continue;
+        };
+        if let TransactionValid::Invalid(_) = transaction.validate() {
+            continue;
+        }
+        let before = accounts.get(&transaction.client_id()).cloned();
+        let committed = transaction.commit(&mut accounts).is_ok();
+        let after = accounts.get(&transaction.client_id());
+        if !committed || (!privileged && before.as_ref().map_or(false, |acct| acct.locked)) {
+            assert_eq!(before.as_ref(), after, "rejected transaction or locked account changed state");
+        }
+        if let Some(acct) = after {
+            assert_eq!(acct.held, acct.disputed(), "held must be equal to sum of disputed amounts");
+        }
+    }
+});
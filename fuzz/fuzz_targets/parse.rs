@@ -0,0 +1,34 @@
+//! Drives arbitrary bytes through CSV reader configured the same way as in `toy_trx_engine::process`.
+//! First byte selects options: bit 0 - `--no-header`, bit 1 - `--comments`, bit 2 - `--engine simple`,
+//! rest of data is content of transactions file.
+#![no_main]
+
+use std::collections::HashMap;
+
+use argh::FromArgs;
+use libfuzzer_sys::fuzz_target;
+
+use toy_trx_engine::{Args, process_reader};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&flags, content)) = data.split_first() else {
+        return;
+    };
+    let mut options = vec!["fuzz.csv", "--wrk-num", "2", "--wrk-buff", "1"];
+    if flags & 0x01 != 0 {
+        options.push("--no-header");
+    }
+    if flags & 0x02 != 0 {
+        options.push("--comments");
+    }
+    if flags & 0x04 != 0 {
+        options.extend(["--engine", "simple"]);
+    }
+    let arg = Args::from_args(&["toy_trx_engine"], &options).expect("correct command line");
+
+    if let Ok((_, accounts)) = async_std::task::block_on(process_reader(&arg, content, HashMap::new())) {
+        for acct in accounts.values() {
+            assert_eq!(acct.held, acct.disputed(), "held must be equal to sum of disputed amounts");
+        }
+    }
+});
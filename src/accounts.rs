@@ -1,30 +1,250 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
 use rust_decimal::{self, Decimal};
+use serde::{Serialize, Deserialize};
 
-use crate::{TMoney, TTrxID};
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency, retention::EvictedIds, store::TrxStore};
 
 /// Represents state of Client Account
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct AccountState {
     
-    /// Funds available for withdraw
+    /// Funds available for withdraw (in default currency)
     pub available: TMoney, 
     
-    /// Founds Locked on account
+    /// Founds Locked on account (in default currency)
     pub held: TMoney, 
+
+    /// Balances in other currencies
+    pub currencies: BTreeMap<Currency, Balance>,
     
     /// Account is locked
     pub locked: bool,
     
     /// List of transactions
-    /// Value stands for pair (trx is under dispute, trx amount (negative if withdrawal))
-    pub transactions: HashMap<TTrxID, (bool, TMoney)>,
+    pub transactions: TrxStore,
+
+    /// Administrative operations (unlocks, adjustments and charged fees) performed on account in order of processing
+    pub admin: Vec<AdminEntry>,
+
+    /// Ordered history of operations on account (`None` - history is not recorded)
+    #[serde(default)]
+    pub history: Option<Vec<HistoryEntry>>,
+
+    /// IDs of transactions evicted by retention policy
+    #[serde(default)]
+    pub evicted: EvictedIds,
+}
+
+/// Balances of account in one currency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    /// Funds available for withdraw
+    pub available: TMoney,
+
+    /// Founds Locked on account
+    pub held: TMoney,
+}
+
+impl Balance {
+    /// Returns total balance (sum of available and locked amounts).
+    pub fn total(&self) -> TMoney {self.available + self.held}
+
+    /// Returns balance with given amounts added to `available` and `held` properties,
+    /// `None` if any of them or total balance would overflow.
+    pub fn checked_change(&self, available: TMoney, held: TMoney) -> Option<Balance> {
+        let balance = Balance {available: self.available.checked_add(available)?, held: self.held.checked_add(held)?};
+        balance.available.checked_add(balance.held)?;
+        Some(balance)
+    }
+}
+
+/// Mutable balances of account in one currency together with transactions of account,
+/// so that transaction may change both at once.
+pub struct Funds<'a> {
+    pub available: &'a mut TMoney,
+    pub held: &'a mut TMoney,
+    pub transactions: &'a mut TrxStore,
+}
+
+/// Adds given amounts to `available` and `held` balances, fails (without any change) if any of them
+/// or total balance would overflow.
+pub fn change_balance(available: &mut TMoney, held: &mut TMoney, by_available: TMoney, by_held: TMoney) -> Option<()> {
+    let balance = Balance {available: *available, held: *held}.checked_change(by_available, by_held)?;
+    (*available, *held) = (balance.available, balance.held);
+    Some(())
+}
+
+/// Transaction stored in account (deposit, withdrawal or exchange), which may be later disputed (as whole or in parts).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TrxEntry {
+    /// Part of amount currently under dispute (the same sign as `amount`)
+    pub disputed: TMoney,
+
+    /// Part of amount already charged back (the same sign as `amount`)
+    pub charged_back: TMoney,
+
+    /// Transaction amount (negative if withdrawal)
+    pub amount: TMoney,
+
+    /// Transaction time (if given in input)
+    pub timestamp: Option<TTimestamp>,
+
+    /// Transaction currency (`None` - default currency)
+    pub currency: Option<Currency>,
+
+    /// Credited leg of exchange (`amount` is its debited leg) - it is disputed, resolved and charged back 
+    /// together with transaction
+    pub linked: Option<Leg>,
+}
+
+/// Credited leg of exchange transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Leg {
+    /// Credited amount
+    pub amount: TMoney,
+
+    /// Currency of credited amount (`None` - default currency)
+    pub currency: Option<Currency>,
+}
+
+impl TrxEntry {
+    /// Creates entry of not disputed transaction in default currency.
+    pub fn new(amount: TMoney, timestamp: Option<TTimestamp>) -> Self {
+        Self {disputed: Decimal::ZERO, charged_back: Decimal::ZERO, amount, timestamp, currency: None, linked: None}
+    }
+
+    /// Returns `true` if any part of transaction is under dispute.
+    pub fn is_disputed(&self) -> bool {!self.disputed.is_zero()}
+
+    /// Returns part of amount which is neither under dispute nor charged back.
+    pub fn disputable(&self) -> TMoney {self.amount - self.disputed - self.charged_back}
+}
+
+/// Administrative operation performed on account.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AdminEntry {
+    /// ID of administrative transaction (for fee - ID of transaction fee was charged for)
+    pub tx: TTrxID,
+
+    /// Operation type
+    pub kind: AdminKind,
+
+    /// Correction of `available` (zero for unlock, negative for fee)
+    pub amount: TMoney,
+
+    /// Reason given by operator (for fee - type of charged transaction)
+    pub reason: Option<String>,
+
+    /// Operation time (if given in input)
+    pub timestamp: Option<TTimestamp>,
+
+    /// Currency of `amount` (`None` - default currency)
+    pub currency: Option<Currency>,
+}
+
+/// Type of administrative operation.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AdminKind {
+    Unlock,
+    Adjust,
+    Fee,
+}
+
+/// Operation in account history.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Number of input record (0 - opening balances and operations performed after processing)
+    pub rec_no: u128,
+
+    /// Transaction ID
+    pub tx: TTrxID,
+
+    /// Operation type
+    #[serde(rename = "type")]
+    pub kind: HistoryKind,
+
+    /// Amount of operation (for dispute, resolve and chargeback - disputed, resolved or charged back part)
+    pub amount: Option<TMoney>,
+
+    /// Currency of operation (`None` - default currency)
+    pub currency: Option<Currency>,
+
+    /// Available funds in `currency` after operation
+    pub available: TMoney,
+
+    /// Held funds in `currency` after operation
+    pub held: TMoney,
+
+    /// Disputed part of referenced transaction after dispute, resolve or chargeback
+    pub disputed: Option<TMoney>,
+
+    /// Charged back part of referenced transaction after dispute, resolve or chargeback
+    pub charged_back: Option<TMoney>,
+
+    /// Operation time (if given in input)
+    pub timestamp: Option<TTimestamp>,
+
+    /// Reason of administrative operation, fee or automatic closing of dispute
+    pub reason: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Creates entry of operation resulting in given `balance`.
+    pub fn new(rec_no: u128, tx: TTrxID, kind: HistoryKind, currency: Option<Currency>, amount: Option<TMoney>, balance: Balance) -> Self {
+        Self {
+            rec_no, tx, kind, amount, currency,
+            available: balance.available, held: balance.held,
+            disputed: None, charged_back: None, timestamp: None, reason: None,
+        }
+    }
+}
+
+/// Type of operation in account history.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryKind {
+    /// Balances of account when history recording started.
+    Opening,
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Unlock,
+    Adjust,
+    Exchange,
+    Fee,
+    Interest,
+}
+
+impl fmt::Display for HistoryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            HistoryKind::Opening => "opening",
+            HistoryKind::Deposit => "deposit",
+            HistoryKind::Withdrawal => "withdrawal",
+            HistoryKind::Dispute => "dispute",
+            HistoryKind::Resolve => "resolve",
+            HistoryKind::Chargeback => "chargeback",
+            HistoryKind::Unlock => "unlock",
+            HistoryKind::Adjust => "adjust",
+            HistoryKind::Exchange => "exchange",
+            HistoryKind::Fee => "fee",
+            HistoryKind::Interest => "interest",
+        };
+        write!(f, "{}", kind)
+    }
 }
 
 // Implemented manually for better clarity
 impl Default for AccountState {
     fn default() -> Self {
-        Self { available: Decimal::ZERO, held: Decimal::ZERO, locked: false, transactions: HashMap::new() }
+        Self { 
+            available: Decimal::ZERO, held: Decimal::ZERO, locked: false, currencies: BTreeMap::new(), 
+            transactions: TrxStore::default(), admin: Vec::new(), history: None, evicted: EvictedIds::default(),
+        }
     }
 }
 
@@ -44,9 +264,83 @@ impl AccountState {
         print!("{},{},{},{}", self.available, self.held, self.total(), self.locked);
     }
 
-    /// Returns total balance of account (sum of available and locked amounts).
+    /// Returns total balance of account (sum of available and locked amounts) in default currency.
     pub fn total(&self) -> TMoney {self.available + self.held}
 
+    /// Returns sum of disputed parts of transactions in default currency - it should be always equal to `held`.
+    pub fn disputed(&self) -> TMoney {
+        self.disputed_in(None)
+    }
+
+    /// Returns sum of disputed parts of transactions (including linked legs of disputed exchanges) in given currency
+    /// (`None` - default currency).
+    pub fn disputed_in(&self, currency: Option<Currency>) -> TMoney {
+        self.transactions.disputed()
+            .map(|(_, trx)| {
+                let own = if trx.currency == currency {trx.disputed} else {Decimal::ZERO};
+                let linked = trx.linked
+                    .filter(|leg| leg.currency == currency)
+                    .map_or(Decimal::ZERO, |leg| leg.amount);
+                own + linked
+            })
+            .sum()
+    }
+
+    /// Returns balances in given currency (`None` - default currency).
+    pub fn balance(&self, currency: Option<Currency>) -> Balance {
+        match currency {
+            None => Balance {available: self.available, held: self.held},
+            Some(currency) => self.currencies.get(&currency).copied().unwrap_or_default(),
+        }
+    }
+
+    /// Returns balances in default currency (if account has any transactions in it or non zero balance) 
+    /// and in all other currencies.
+    pub fn balances(&self) -> impl Iterator<Item = (Option<Currency>, Balance)> + '_ {
+        let default = self.balance(None);
+        let has_default = self.currencies.is_empty() || !default.available.is_zero() || !default.held.is_zero() 
+            || self.transactions.iter().any(|(_, trx)| trx.currency.is_none());
+        has_default.then_some((None, default)).into_iter()
+            .chain(self.currencies.iter().map(|(currency, balance)| (Some(*currency), *balance)))
+    }
+
+    /// Returns balances in given currency (`None` - default currency) for change, see `Funds`.
+    /// Balance in other than default currency is registered if not present.
+    pub fn funds_mut(&mut self, currency: Option<Currency>) -> Funds<'_> {
+        let (available, held) = match currency {
+            None => (&mut self.available, &mut self.held),
+            Some(currency) => {
+                let balance = self.currencies.entry(currency).or_default();
+                (&mut balance.available, &mut balance.held)
+            },
+        };
+        Funds {available, held, transactions: &mut self.transactions}
+    }
+
+    /// Returns `true` if available balance in any currency is below zero (e.g. disputed funds were already withdrawn).
+    pub fn is_negative(&self) -> bool {
+        self.balances().any(|(_, balance)| balance.available.is_sign_negative() && !balance.available.is_zero())
+    }
+
+    /// Starts recording of account history (if it is not recorded yet), current non zero balances are recorded
+    /// as opening entries.
+    pub fn start_history(&mut self) {
+        if self.history.is_none() {
+            let opening = self.balances()
+                .filter(|(_, balance)| !balance.available.is_zero() || !balance.held.is_zero())
+                .map(|(currency, balance)| HistoryEntry::new(0, 0, HistoryKind::Opening, currency, None, balance))
+                .collect();
+            self.history = Some(opening);
+        }
+    }
+
+    /// Appends entry to account history (if history is recorded).
+    pub fn record(&mut self, entry: HistoryEntry) {
+        if let Some(history) = &mut self.history {
+            history.push(entry);
+        }
+    }
+
     // pub fn deposit(&mut self, amount: TMoney) -> Result<()> {
     //     self.available += amount;
     //     Ok(())
@@ -59,3 +353,13 @@ impl AccountState {
     // }
 
 }
+
+/// Returns sorted IDs of clients which available balance is below zero.
+pub fn negative_accounts(accounts: &HashMap<TClientId, AccountState>) -> Vec<TClientId> {
+    let mut clients: Vec<_> = accounts.iter()
+        .filter(|(_, acct)| acct.is_negative())
+        .map(|(client, _)| *client)
+        .collect();
+    clients.sort_unstable();
+    clients
+}
@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{Result, Context};
+use argh::FromArgs;
+
+use toy_trx_engine::generator::{GenConfig, TypeMix, generate};
+
+#[derive(FromArgs)]
+/// Generator of random transaction files for Toy Transaction Engine.
+struct GenArgs {
+    /// number of records (default: 100000)
+    #[argh(option, default = "100_000")]
+    size: u32,
+
+    /// number of distinct clients (default: size / 5, max 65535)
+    #[argh(option)]
+    clients: Option<u16>,
+
+    /// weights of transaction types as deposit:withdrawal:dispute:resolve:chargeback (default: 1:1:1:1:1)
+    #[argh(option, default = "TypeMix::default()")]
+    mix: TypeMix,
+
+    /// fraction of deliberately invalid records 0.0 - 1.0 (default: 0.0)
+    #[argh(option, default = "0.0")]
+    invalid_ratio: f64,
+
+    /// maximum amount of deposit or withdrawal (default: 100.0)
+    #[argh(option, default = "100.0")]
+    amount_max: f64,
+
+    /// seed of random numbers generator (default: 0)
+    #[argh(option, default = "0")]
+    seed: u64,
+
+    /// path to output CSV file
+    #[argh(positional)]
+    out_file: PathBuf,
+}
+
+fn main() -> Result<()> {
+    let arg: GenArgs = argh::from_env();
+    let cfg = GenConfig {
+        size: arg.size,
+        clients: arg.clients.unwrap_or_else(|| (arg.size / 5).clamp(1, u16::MAX as u32) as u16),
+        mix: arg.mix,
+        invalid_ratio: arg.invalid_ratio,
+        amount_max: arg.amount_max,
+        seed: arg.seed,
+    };
+    let file = File::create(&arg.out_file)
+        .with_context(|| format!("creating output file: {}", arg.out_file.display()))?;
+    let mut out = BufWriter::new(file);
+    generate(&cfg, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Currency codes.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+/// Currency code - 3 ASCII letters (e.g. `EUR`), stored in upper case.
+/// Fixed size representation is used, so that currency may be copied with every transaction without allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl FromStr for Currency {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [a, b, c] if s.bytes().all(|ch| ch.is_ascii_alphabetic()) => 
+                Ok(Currency([a.to_ascii_uppercase(), b.to_ascii_uppercase(), c.to_ascii_uppercase()])),
+            _ => Err(format!("invalid currency code: {} (expected 3 letters)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // only ASCII letters are accepted by `from_str`
+        f.write_str(std::str::from_utf8(&self.0).map_err(|_| fmt::Error)?)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        let eur: Currency = "eur".parse().unwrap();
+        assert_eq!(eur, "EUR".parse().unwrap());
+        assert_eq!(eur.to_string(), "EUR");
+        assert_eq!(serde_json::to_string(&eur).unwrap(), "\"EUR\"");
+        assert_eq!(serde_json::from_str::<Currency>("\"usd\"").unwrap().to_string(), "USD");
+        assert!("EURO".parse::<Currency>().is_err());
+        assert!("E1R".parse::<Currency>().is_err());
+        assert!("".parse::<Currency>().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Dispute expiry windows.
+//!
+//! Dispute which is not resolved or charged back within given number of subsequent transactions of the same client
+//! (or given number of seconds measured with transactions timestamps) is automatically closed 
+//! (resolved or charged back according to configuration).
+//! Only disputes opened in current run are tracked - disputes loaded from snapshot stay open until closed explicitly.
+//! In seconds mode disputes without timestamp are not tracked and time of input is the latest timestamp of records
+//! of all clients read so far, so dispute expires also if its client has no more transactions.
+//!
+//! Expired disputes are closed before the next transaction of their client (balances of client do not change
+//! in the meantime, so the result is the same as if they were closed right when they expired) and disputes
+//! which expired by the end of input are closed after the last record. So closing does not depend on how clients
+//! are distributed between workers.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::{TClientId, TTrxID, TTimestamp};
+
+/// Action performed on dispute after its window expired.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpiryAction {
+    Resolve,
+    Chargeback,
+}
+
+impl FromStr for ExpiryAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "resolve" => Ok(ExpiryAction::Resolve),
+            "chargeback" => Ok(ExpiryAction::Chargeback),
+            _ => Err(format!("unknown dispute expiry action: {} (expected resolve or chargeback)", s)),
+        }
+    }
+}
+
+/// Unit of dispute window length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindowUnit {
+    /// Number of subsequent transactions of the same client.
+    Transactions,
+    /// Seconds measured with transactions timestamps.
+    Seconds,
+}
+
+impl FromStr for WindowUnit {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transactions" => Ok(WindowUnit::Transactions),
+            "seconds" => Ok(WindowUnit::Seconds),
+            _ => Err(format!("unknown dispute window unit: {} (expected transactions or seconds)", s)),
+        }
+    }
+}
+
+/// Dispute window settings.
+#[derive(Clone, Copy, Debug)]
+pub struct DisputeWindow {
+    /// Length of window within which dispute must be closed.
+    pub length: u64,
+    /// Unit of `length`.
+    pub unit: WindowUnit,
+    /// Action performed on expired dispute.
+    pub action: ExpiryAction,
+}
+
+/// Tracks open disputes of clients processed by one worker.
+#[derive(Default)]
+pub struct DisputeTracker {
+    /// Number of transactions seen per client.
+    seen: HashMap<TClientId, u64>,
+    /// Open disputes per client: (disputed transaction ID, last transaction number or time within window).
+    open: HashMap<TClientId, Vec<(TTrxID, i64)>>,
+}
+
+impl DisputeTracker {
+    /// Registers next transaction of client (`clock` - time of input), returns IDs of transactions which disputes
+    /// expired before it (in order of disputes opening).
+    pub fn next(&mut self, client: TClientId, clock: Option<TTimestamp>, window: &DisputeWindow) -> Vec<TTrxID> {
+        let seen = self.seen.entry(client).or_default();
+        *seen += 1;
+        let now = match window.unit {
+            WindowUnit::Transactions => *seen as i64,
+            WindowUnit::Seconds => match clock {
+                Some(clock) => clock,
+                None => return Vec::new(),
+            },
+        };
+        self.take_expired(client, now)
+    }
+
+    /// Returns disputes which expired by the end of input (`clock` - time of input at the end) - disputes which window
+    /// of client transactions is used up or which deadline passed, in order of clients and of disputes opening.
+    pub fn expired(&mut self, clock: Option<TTimestamp>, window: &DisputeWindow) -> Vec<(TClientId, TTrxID)> {
+        let mut clients: Vec<_> = self.open.keys().copied().collect();
+        clients.sort_unstable();
+        let mut expired = Vec::new();
+        for client in clients {
+            let now = match (window.unit, clock) {
+                (WindowUnit::Transactions, _) => self.seen.get(&client).copied().unwrap_or_default() as i64 + 1,
+                (WindowUnit::Seconds, Some(clock)) => clock,
+                (WindowUnit::Seconds, None) => break,
+            };
+            expired.extend(self.take_expired(client, now).into_iter().map(|tx| (client, tx)));
+        }
+        expired
+    }
+
+    /// Removes and returns open disputes of client which deadline is before `now`.
+    fn take_expired(&mut self, client: TClientId, now: i64) -> Vec<TTrxID> {
+        match self.open.get_mut(&client) {
+            Some(open) => {
+                let expired = open.iter().filter(|(_, deadline)| *deadline < now).map(|(tx, _)| *tx).collect();
+                open.retain(|(_, deadline)| *deadline >= now);
+                expired
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Adds disputes tracked by other tracker (of other clients).
+    pub fn merge(&mut self, other: DisputeTracker) {
+        self.seen.extend(other.seen);
+        self.open.extend(other.open);
+    }
+
+    /// Registers dispute opened by last transaction of client (repeated disputes keep original deadline).
+    pub fn opened(&mut self, client: TClientId, tx: TTrxID, timestamp: Option<TTimestamp>, window: &DisputeWindow) {
+        let length = window.length.min(i64::MAX as u64 / 1000) as i64;
+        let deadline = match window.unit {
+            WindowUnit::Transactions => self.seen.get(&client).copied().unwrap_or_default() as i64 + length,
+            WindowUnit::Seconds => match timestamp {
+                Some(timestamp) => timestamp.saturating_add(length * 1000),
+                None => return,
+            },
+        };
+        let open = self.open.entry(client).or_default();
+        if !open.iter().any(|(id, _)| *id == tx) {
+            open.push((tx, deadline));
+        }
+    }
+
+    /// Registers dispute closed by resolve or chargeback.
+    pub fn closed(&mut self, client: TClientId, tx: TTrxID) {
+        if let Some(open) = self.open.get_mut(&client) {
+            open.retain(|(id, _)| *id != tx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        let window = DisputeWindow {length: 2, unit: WindowUnit::Transactions, action: ExpiryAction::Resolve};
+        let mut tracker = DisputeTracker::default();
+        assert!(tracker.next(1, None, &window).is_empty());    // deposit tx 1
+        assert!(tracker.next(1, None, &window).is_empty());    // dispute tx 1
+        tracker.opened(1, 1, None, &window);
+        assert!(tracker.next(2, None, &window).is_empty());    // other client does not count
+        assert!(tracker.next(1, None, &window).is_empty());    // 1st subsequent
+        assert!(tracker.next(1, None, &window).is_empty());    // 2nd subsequent
+        assert_eq!(tracker.next(1, None, &window), vec![1]);   // 3rd subsequent - expired
+        assert!(tracker.next(1, None, &window).is_empty());
+    }
+
+    #[test]
+    fn closed_in_window() {
+        let window = DisputeWindow {length: 1, unit: WindowUnit::Transactions, action: ExpiryAction::Chargeback};
+        let mut tracker = DisputeTracker::default();
+        tracker.next(1, None, &window);
+        tracker.opened(1, 5, None, &window);
+        tracker.next(1, None, &window);
+        tracker.closed(1, 5);
+        assert!(tracker.next(1, None, &window).is_empty());
+        assert!(tracker.next(1, None, &window).is_empty());
+    }
+
+    #[test]
+    fn expiry_in_seconds() {
+        let window = DisputeWindow {length: 60, unit: WindowUnit::Seconds, action: ExpiryAction::Resolve};
+        let mut tracker = DisputeTracker::default();
+        tracker.next(1, Some(1_000_000), &window);
+        tracker.opened(1, 1, Some(1_000_000), &window);
+        tracker.next(1, None, &window);
+        tracker.opened(1, 2, None, &window);                            // not tracked - no timestamp
+        assert!(tracker.next(1, Some(1_060_000), &window).is_empty());  // 60s after dispute - still in window
+        assert!(tracker.next(1, None, &window).is_empty());             // no timestamp - no expiry check
+        assert_eq!(tracker.next(1, Some(1_060_001), &window), vec![1]);
+        assert!(tracker.next(1, Some(2_000_000), &window).is_empty());
+    }
+
+    #[test]
+    fn expired_at_end() {
+        let window = DisputeWindow {length: 2, unit: WindowUnit::Transactions, action: ExpiryAction::Resolve};
+        let mut tracker = DisputeTracker::default();
+        tracker.next(2, None, &window);
+        tracker.opened(2, 1, None, &window);
+        tracker.next(2, None, &window);
+        tracker.next(1, None, &window);
+        tracker.opened(1, 2, None, &window);
+        assert!(tracker.expired(None, &window).is_empty());     // 1 of 2 subsequent transactions of client 2
+        tracker.next(2, None, &window);
+        tracker.next(1, None, &window);
+        tracker.next(1, None, &window);
+        assert_eq!(tracker.expired(None, &window), vec![(1, 2), (2, 1)]);
+        assert!(tracker.expired(None, &window).is_empty());
+
+        let window = DisputeWindow {length: 60, unit: WindowUnit::Seconds, action: ExpiryAction::Resolve};
+        let mut other = DisputeTracker::default();
+        other.next(3, Some(1_000_000), &window);
+        other.opened(3, 1, Some(1_000_000), &window);
+        other.next(4, Some(1_000_000), &window);
+        other.opened(4, 2, Some(1_000_000), &window);
+        tracker.merge(other);
+        assert_eq!(tracker.expired(Some(1_060_000), &window), vec![]);
+        assert_eq!(tracker.next(3, Some(1_060_001), &window), vec![1]);  // closed before next transaction of client
+        assert_eq!(tracker.expired(Some(1_060_001), &window), vec![(4, 2)]);
+    }
+}
@@ -0,0 +1,160 @@
+//! Fee schedule - fees charged automatically when withdrawal or chargeback is committed.
+//!
+//! Simple CSV file with columns `type,flat,percent,min,max` (`min` and `max` are optional), one row per 
+//! transaction type (`withdrawal` or `chargeback`). Fee is `flat + percent% of amount`, limited to `min..=max`
+//! and rounded to 4 decimal places with banker's rounding.
+
+use std::path::Path;
+
+use anyhow::{Result, Context, bail};
+use async_std::{fs::File, stream::StreamExt};
+use csv_async::AsyncReaderBuilder;
+use futures::io::AsyncRead;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::{
+    TClientId, TMoney,
+    transactions::TransactionRecType,
+};
+
+/// Represents fee schedule record as read from input file.
+#[derive(Deserialize)]
+struct FeeRec {
+    #[serde(rename = "type")]
+    ttype: TransactionRecType,
+    flat: TMoney,
+    percent: TMoney,
+    #[serde(default)]
+    min: Option<TMoney>,
+    #[serde(default)]
+    max: Option<TMoney>,
+}
+
+/// Fee charged for one transaction type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeRule {
+    /// Flat part of fee.
+    pub flat: TMoney,
+    /// Percentage of transaction amount.
+    pub percent: TMoney,
+    /// Minimal fee.
+    pub min: Option<TMoney>,
+    /// Maximal fee.
+    pub max: Option<TMoney>,
+}
+
+impl FeeRule {
+    /// Returns fee for transaction of given (positive) `amount`, `None` if it overflows.
+    pub fn fee(&self, amount: TMoney) -> Option<TMoney> {
+        let mut fee = self.flat.checked_add(amount.checked_mul(self.percent)? / Decimal::ONE_HUNDRED)?;
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+        Some(fee.round_dp(4).max(Decimal::ZERO))
+    }
+}
+
+/// Fees charged for transaction types together with account where they are accumulated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeSchedule {
+    /// Fee charged on withdrawal.
+    pub withdrawal: Option<FeeRule>,
+    /// Fee charged on chargeback.
+    pub chargeback: Option<FeeRule>,
+    /// House account where all charged fees are accumulated.
+    pub account: TClientId,
+}
+
+/// Loads fee schedule from file, see `read` for details.
+pub async fn load(path: &Path, account: TClientId) -> Result<FeeSchedule> {
+    let file = File::open(path).await
+        .with_context(|| format!("opening fee schedule file: {}", path.display()))?;
+    read(file, account).await
+        .with_context(|| format!("loading fee schedule file: {}", path.display()))
+}
+
+/// Reads fee schedule from `data`, fees will be accumulated in house `account`.
+/// - if type is not `withdrawal` nor `chargeback` - fail.
+/// - if type occurs more than once in data - fail.
+/// - if any of amounts is negative or `min` is greater than `max` - fail.
+pub async fn read<R>(data: R, account: TClientId) -> Result<FeeSchedule>
+    where R: AsyncRead + Unpin + Send
+{
+    let mut rdr = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(data);
+    let mut records = rdr.deserialize::<FeeRec>();
+    let mut schedule = FeeSchedule {withdrawal: None, chargeback: None, account};
+    let mut rec_no = 0usize;
+    while let Some(record) = records.next().await {
+        rec_no += 1;
+        let rec = record.with_context(|| format!("Record# {} - parsing failed", rec_no))?;
+        let amounts = [Some(rec.flat), Some(rec.percent), rec.min, rec.max];
+        if amounts.iter().flatten().any(|amount| amount.is_sign_negative() && !amount.is_zero()) {
+            bail!("Record# {} - negative fee amount", rec_no)
+        }
+        if let (Some(min), Some(max)) = (rec.min, rec.max) {
+            if min > max {
+                bail!("Record# {} - min fee {} greater than max fee {}", rec_no, min, max)
+            }
+        }
+        let slot = match rec.ttype {
+            TransactionRecType::Withdrawal => &mut schedule.withdrawal,
+            TransactionRecType::Chargeback => &mut schedule.chargeback,
+            ttype => bail!("Record# {} - fees are not supported for {:?} transactions", rec_no, ttype),
+        };
+        if slot.is_some() {
+            bail!("Record# {} - fee for {:?} defined more than once", rec_no, rec.ttype)
+        }
+        *slot = Some(FeeRule {flat: rec.flat, percent: rec.percent, min: rec.min, max: rec.max});
+    }
+    Ok(schedule)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[test]
+    fn fee() {
+        let rule = FeeRule {flat: dec!(0.5), percent: dec!(1.0), min: None, max: Some(dec!(2.0))};
+        assert_eq!(rule.fee(dec!(10.0)), Some(dec!(0.6)));
+        assert_eq!(rule.fee(dec!(1000.0)), Some(dec!(2.0)));
+        let rule = FeeRule {flat: dec!(0.0), percent: dec!(0.125), min: Some(dec!(0.01)), max: None};
+        assert_eq!(rule.fee(dec!(1.0)), Some(dec!(0.01)));
+        assert_eq!(rule.fee(dec!(10.5)), Some(dec!(0.0131))); // 0.013125 rounded half to even
+    }
+
+    #[test]
+    fn fee_overflow() {
+        let rule = FeeRule {flat: dec!(0.5), percent: dec!(200), min: None, max: Some(dec!(2.0))};
+        assert_eq!(rule.fee(Decimal::MAX), None);
+        let rule = FeeRule {flat: Decimal::MAX, percent: dec!(100), min: None, max: None};
+        assert_eq!(rule.fee(dec!(1.0)), None);
+    }
+
+    #[async_std::test]
+    async fn schedule() {
+        let data = "type,flat,percent,min,max\nwithdrawal, 0.5, 1.0, , 2.0\nchargeback, 15, 0, ,\n";
+        let schedule = read(data.as_bytes(), 0).await.expect("valid schedule");
+        assert_eq!(schedule.withdrawal, Some(FeeRule {flat: dec!(0.5), percent: dec!(1.0), min: None, max: Some(dec!(2.0))}));
+        assert_eq!(schedule.chargeback, Some(FeeRule {flat: dec!(15), percent: dec!(0), min: None, max: None}));
+    }
+
+    #[async_std::test]
+    async fn invalid_schedule() {
+        let data = "type,flat,percent,min,max\ndeposit, 0.5, 1.0, , \n";
+        assert!(read(data.as_bytes(), 0).await.is_err());
+        let data = "type,flat,percent,min,max\nwithdrawal, 0.5, 1.0, , \nwithdrawal, 1, 0, , \n";
+        assert!(read(data.as_bytes(), 0).await.is_err());
+        let data = "type,flat,percent,min,max\nwithdrawal, -0.5, 1.0, , \n";
+        assert!(read(data.as_bytes(), 0).await.is_err());
+        let data = "type,flat,percent,min,max\nwithdrawal, 0.5, 1.0, 3, 2\n";
+        assert!(read(data.as_bytes(), 0).await.is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Generator of random (but coherent) transaction files for tests and benchmarks.
+//!
+//! Rules (the same as in Julia script `tests/gen_tran_file.jl`):
+//! - first records are deposits only, so that withdrawals and disputes have something to reference,
+//! - withdrawals are made by clients who already made some transaction,
+//! - disputes reference existing deposits or withdrawals of the same client, which are neither under open dispute
+//!   nor charged back,
+//! - resolves and chargebacks reference open disputes (and close them).
+
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{TClientId, TTrxID};
+
+/// Maximum number of recent transactions remembered as candidates for disputes.
+const RECENT_MAX: usize = 1 << 20;
+
+/// Weights of transaction types in generated file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TypeMix {
+    pub deposit: u32,
+    pub withdrawal: u32,
+    pub dispute: u32,
+    pub resolve: u32,
+    pub chargeback: u32,
+}
+
+impl Default for TypeMix {
+    fn default() -> Self {
+        Self {deposit: 1, withdrawal: 1, dispute: 1, resolve: 1, chargeback: 1}
+    }
+}
+
+/// Parses mix given as `deposit:withdrawal:dispute:resolve:chargeback` weights, e.g. `60:30:6:3:1`.
+impl FromStr for TypeMix {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let weights = s.split(':')
+            .map(|w| w.trim().parse::<u32>().map_err(|e| format!("invalid weight {}: {}", w, e)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        match weights[..] {
+            [deposit, withdrawal, dispute, resolve, chargeback] if deposit > 0 => 
+                Ok(Self {deposit, withdrawal, dispute, resolve, chargeback}),
+            [_, _, _, _, _] => Err("deposit weight must be greater than 0".to_string()),
+            _ => Err(format!("expected 5 weights (deposit:withdrawal:dispute:resolve:chargeback), got: {}", s)),
+        }
+    }
+}
+
+/// Parameters of generated file.
+#[derive(Clone, Debug)]
+pub struct GenConfig {
+    /// Number of records.
+    pub size: u32,
+    /// Number of distinct clients.
+    pub clients: TClientId,
+    /// Weights of transaction types.
+    pub mix: TypeMix,
+    /// Fraction of records (0.0 - 1.0) that are deliberately invalid.
+    pub invalid_ratio: f64,
+    /// Maximum amount of deposit or withdrawal.
+    pub amount_max: f64,
+    /// Seed of random numbers generator - the same seed gives the same file.
+    pub seed: u64,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        Self {size: 1000, clients: 200, mix: TypeMix::default(), invalid_ratio: 0.0, amount_max: 100.0, seed: 0}
+    }
+}
+
+/// Writes transactions file (with headers) according to passed configuration.
+pub fn generate<W: Write>(cfg: &GenConfig, out: &mut W) -> Result<()> {
+    if cfg.clients == 0 {
+        bail!("number of clients must be greater than 0")
+    }
+    if !(0.0..=1.0).contains(&cfg.invalid_ratio) {
+        bail!("invalid records ratio must be in range 0.0 - 1.0")
+    }
+    let mut rng = StdRng::seed_from_u64(cfg.seed);
+    let mut recent: VecDeque<(TClientId, TTrxID)> = VecDeque::new();  // deposits and withdrawals
+    let mut disputed: Vec<(TClientId, TTrxID)> = Vec::new();          // open disputes
+    let mut undisputable = HashSet::new();                             // under open dispute or charged back
+    let mix = &cfg.mix;
+    let total_weight = mix.deposit + mix.withdrawal + mix.dispute + mix.resolve + mix.chargeback;
+
+    writeln!(out, "type,client,tx,amount")?;
+    let mut tx: TTrxID = 0;
+    while tx < cfg.size {
+        tx += 1;
+        if cfg.invalid_ratio > 0.0 && rng.random_bool(cfg.invalid_ratio) {
+            let client = rng.random_range(1..=cfg.clients);
+            match rng.random_range(0..4) {
+                0 => writeln!(out, "deposit,{},{},", client, tx)?,
+                1 => writeln!(out, "withdrawal,{},{},-{:.4}", client, tx, rng.random::<f64>() * cfg.amount_max)?,
+                2 => writeln!(out, "transfer,{},{},{:.4}", client, tx, rng.random::<f64>() * cfg.amount_max)?,
+                _ => writeln!(out, "deposit,client{},{},{:.4}", client, tx, rng.random::<f64>() * cfg.amount_max)?,
+            }
+            continue;
+        }
+        let mut pick = if recent.is_empty() {0} else {rng.random_range(0..total_weight)};
+        if pick < mix.deposit {
+            let client = rng.random_range(1..=cfg.clients);
+            remember(&mut recent, (client, tx));
+            writeln!(out, "deposit,{},{},{:.4}", client, tx, rng.random::<f64>() * cfg.amount_max)?;
+            continue;
+        }
+        pick -= mix.deposit;
+        if pick < mix.withdrawal {
+            let (client, _) = recent[rng.random_range(0..recent.len())];
+            remember(&mut recent, (client, tx));
+            writeln!(out, "withdrawal,{},{},{:.4}", client, tx, rng.random::<f64>() * cfg.amount_max)?;
+            continue;
+        }
+        pick -= mix.withdrawal;
+        if pick < mix.dispute {
+            let (client, reference) = recent[rng.random_range(0..recent.len())];
+            if !undisputable.insert((client, reference)) {
+                tx -= 1; // already disputed or charged back - draw again
+                continue;
+            }
+            disputed.push((client, reference));
+            writeln!(out, "dispute,{},{},", client, reference)?;
+            continue;
+        }
+        pick -= mix.dispute;
+        if disputed.is_empty() {
+            tx -= 1; // nothing to resolve or charge back - draw again
+            continue;
+        }
+        let (client, reference) = disputed.swap_remove(rng.random_range(0..disputed.len()));
+        let ttype = if pick < mix.resolve {"resolve"} else {"chargeback"};
+        if ttype == "resolve" {
+            undisputable.remove(&(client, reference)); // resolved transaction may be disputed again
+        }
+        writeln!(out, "{},{},{},", ttype, client, reference)?;
+    }
+    Ok(())
+}
+
+fn remember(recent: &mut VecDeque<(TClientId, TTrxID)>, trx: (TClientId, TTrxID)) {
+    if recent.len() == RECENT_MAX {
+        recent.pop_front();
+    }
+    recent.push_back(trx);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+
+    fn generate_lines(cfg: &GenConfig) -> Vec<String> {
+        let mut out = Vec::new();
+        generate(cfg, &mut out).expect("generated file");
+        String::from_utf8(out).expect("utf8 output").lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn reproducible() {
+        let cfg = GenConfig {seed: 7, ..Default::default()};
+        assert_eq!(generate_lines(&cfg), generate_lines(&cfg));
+        let other = GenConfig {seed: 8, ..Default::default()};
+        assert_ne!(generate_lines(&cfg), generate_lines(&other));
+    }
+
+    #[test]
+    fn coherent() {
+        let cfg = GenConfig {size: 5000, clients: 50, ..Default::default()};
+        let lines = generate_lines(&cfg);
+        assert_eq!(lines[0], "type,client,tx,amount");
+        assert_eq!(lines.len(), 5001);
+        let mut existing = HashSet::new();
+        let mut disputed = HashSet::new();
+        let mut charged_back = HashSet::new();
+        let mut closures = 0;
+        for line in &lines[1..] {
+            let fields: Vec<&str> = line.split(',').collect();
+            let client: TClientId = fields[1].parse().expect("client id");
+            let tx: TTrxID = fields[2].parse().expect("tx id");
+            assert!(client >= 1 && client <= cfg.clients);
+            match fields[0] {
+                "deposit" | "withdrawal" => {
+                    assert!(fields[3].parse::<f64>().expect("amount") >= 0.0);
+                    existing.insert((client, tx));
+                },
+                "dispute" => {
+                    assert!(existing.contains(&(client, tx)), "dispute of unknown transaction: {}", line);
+                    assert!(!charged_back.contains(&(client, tx)), "dispute of charged back transaction: {}", line);
+                    assert!(disputed.insert((client, tx)), "dispute of already disputed transaction: {}", line);
+                },
+                "resolve" | "chargeback" => {
+                    assert!(disputed.remove(&(client, tx)), "{} without open dispute: {}", fields[0], line);
+                    if fields[0] == "chargeback" {
+                        charged_back.insert((client, tx));
+                    }
+                    closures += 1;
+                },
+                _ => panic!("unexpected record: {}", line),
+            }
+        }
+        assert!(closures > 0);
+    }
+
+    #[test]
+    fn invalid_records() {
+        let cfg = GenConfig {invalid_ratio: 1.0, ..Default::default()};
+        let lines = generate_lines(&cfg);
+        assert!(lines[1..].iter().all(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            fields[0] == "transfer" || fields[1].starts_with("client") || fields[3].is_empty() || fields[3].starts_with('-')
+        }));
+    }
+
+    #[test]
+    fn type_mix() {
+        assert_eq!("5:3:1:1:0".parse::<TypeMix>(), Ok(TypeMix {deposit: 5, withdrawal: 3, dispute: 1, resolve: 1, chargeback: 0}));
+        assert!("5:3:1".parse::<TypeMix>().is_err());
+        assert!("0:3:1:1:1".parse::<TypeMix>().is_err());
+        let cfg = GenConfig {mix: "1:0:0:0:0".parse().expect("mix"), ..Default::default()};
+        assert!(generate_lines(&cfg)[1..].iter().all(|line| line.starts_with("deposit,")));
+    }
+}
@@ -0,0 +1,208 @@
+//! Interest accrual - end of period operation crediting interest on `available` balances of accounts
+//! (in default currency).
+//!
+//! Interest is posted as deposit-like transaction (so it may be disputed as any deposit) with generated ID,
+//! rounded to 4 decimal places with banker's rounding. Locked accounts and accounts with `available` below
+//! minimal balance are skipped.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{
+    TClientId, TMoney, TTrxID,
+    accounts::{AccountState, HistoryEntry, HistoryKind, TrxEntry},
+    ledger::{Journal, LedgerAccount},
+};
+
+/// Rate applied to part of balance above threshold (given as `threshold:rate`, e.g. `1000:0.75`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tier {
+    /// Balance from which rate applies.
+    pub threshold: TMoney,
+    /// Interest rate in percent per period.
+    pub rate: TMoney,
+}
+
+impl FromStr for Tier {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |value: &str| value.trim().parse::<TMoney>().map_err(|e| format!("invalid tier {}: {}", s, e));
+        match s.split_once(':') {
+            Some((threshold, rate)) => Ok(Tier {threshold: parse(threshold)?, rate: parse(rate)?}),
+            None => Err(format!("expected tier as threshold:rate, got: {}", s)),
+        }
+    }
+}
+
+/// Interest accrual settings.
+#[derive(Clone, Debug, Default)]
+pub struct InterestConfig {
+    /// Interest rate in percent per period applied to balance below first tier threshold.
+    pub rate: TMoney,
+    /// Rates applied to parts of balance above thresholds.
+    pub tiers: Vec<Tier>,
+    /// Minimal `available` balance for which interest is accrued.
+    pub min_balance: TMoney,
+    /// ID of interest transactions (default: next to the greatest transaction ID of all accounts).
+    pub tx: Option<TTrxID>,
+}
+
+impl InterestConfig {
+    /// Checks settings loaded from command line:
+    /// - if any rate, tier threshold or minimal balance is negative - fail.
+    /// - if tier threshold is given more than once - fail.
+    pub fn validate(&self) -> Result<()> {
+        if self.rate.is_sign_negative() && !self.rate.is_zero() {
+            bail!("negative interest rate: {}", self.rate)
+        }
+        if self.min_balance.is_sign_negative() && !self.min_balance.is_zero() {
+            bail!("negative minimal balance: {}", self.min_balance)
+        }
+        for (i, tier) in self.tiers.iter().enumerate() {
+            if (tier.threshold.is_sign_negative() && !tier.threshold.is_zero()) || (tier.rate.is_sign_negative() && !tier.rate.is_zero()) {
+                bail!("negative threshold or rate of tier {}:{}", tier.threshold, tier.rate)
+            }
+            if self.tiers[..i].iter().any(|other| other.threshold == tier.threshold) {
+                bail!("tier threshold {} given more than once", tier.threshold)
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns interest for given `balance` - every part of balance between tier thresholds
+    /// earns rate of its tier (zero if balance is below minimal balance), `None` if it overflows.
+    pub fn interest(&self, balance: TMoney) -> Option<TMoney> {
+        if balance < self.min_balance || balance <= Decimal::ZERO {
+            return Some(Decimal::ZERO);
+        }
+        let mut tiers = self.tiers.clone();
+        tiers.sort_by_key(|tier| tier.threshold);
+        let mut interest = Decimal::ZERO;
+        let (mut from, mut rate) = (Decimal::ZERO, self.rate);
+        for tier in tiers {
+            if balance <= tier.threshold {
+                break;
+            }
+            interest = interest.checked_add(tier.threshold.checked_sub(from)?.max(Decimal::ZERO).checked_mul(rate)?)?;
+            (from, rate) = (tier.threshold.max(from), tier.rate);
+        }
+        interest = interest.checked_add(balance.checked_sub(from)?.checked_mul(rate)?)?;
+        Some((interest / Decimal::ONE_HUNDRED).round_dp_with_strategy(4, RoundingStrategy::MidpointNearestEven))
+    }
+}
+
+/// Credits interest to all not locked `accounts`, returns number of credited accounts and total interest.
+/// - if interest transaction ID is already used by credited account - fail (no account is changed).
+/// - if interest or balance with interest overflows - fail (no account is changed).
+/// - otherwise interest is added to `available`, stored as deposit transaction and recorded in `journal`
+///   (and in account history if it is recorded).
+pub fn accrue(accounts: &mut HashMap::<TClientId,AccountState>, config: &InterestConfig, journal: &mut Journal) -> Result<(usize, TMoney)> {
+    let tx = match config.tx {
+        Some(tx) => tx,
+        None => {
+            let last = accounts.values().filter_map(|acct| acct.transactions.last_id()).max().unwrap_or_default();
+            match last.checked_add(1) {
+                Some(tx) => tx,
+                None => bail!("can not generate interest transaction ID - transaction IDs exhausted"),
+            }
+        },
+    };
+    let mut credits = Vec::new();
+    let mut total = Decimal::ZERO;
+    for (client, acct) in accounts.iter().filter(|(_, acct)| !acct.locked) {
+        let interest = match config.interest(acct.available) {
+            Some(interest) if interest.is_zero() => continue,
+            Some(interest) => interest,
+            None => bail!("interest of client {} overflows", client),
+        };
+        if acct.transactions.contains(tx) {
+            bail!("interest transaction ID {} already used by client {}", tx, client)
+        }
+        let (Some(available), Some(sum)) = (acct.available.checked_add(interest), total.checked_add(interest)) else {
+            bail!("balance of client {} with interest {} overflows", client, interest)
+        };
+        credits.push((*client, interest, available));
+        total = sum;
+    }
+    for (client, interest, available) in &credits {
+        let acct = accounts.get_mut(client).expect("credited client in accounts");
+        acct.available = *available;
+        acct.transactions.insert(tx, TrxEntry::new(*interest, None));
+        acct.record(HistoryEntry::new(0, tx, HistoryKind::Interest, None, Some(*interest), acct.balance(None)));
+        journal.transfer(0, tx, None, LedgerAccount::InterestExpense, LedgerAccount::Available(*client), *interest);
+    }
+    Ok((credits.len(), total))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[test]
+    fn tiers() {
+        let config = InterestConfig {
+            rate: dec!(1.0),
+            tiers: vec!["5000:0.5".parse().unwrap(), "1000:2.0".parse().unwrap()],
+            min_balance: dec!(100),
+            tx: None,
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.interest(dec!(99.99)), Some(dec!(0)));
+        assert_eq!(config.interest(dec!(500)), Some(dec!(5)));
+        assert_eq!(config.interest(dec!(2000)), Some(dec!(30)));            // 1000 * 1% + 1000 * 2%
+        assert_eq!(config.interest(dec!(6000)), Some(dec!(95)));            // 1000 * 1% + 4000 * 2% + 1000 * 0.5%
+        assert_eq!(config.interest(dec!(123.4525)), Some(dec!(1.2345)));    // 1.234525 rounded half to even
+        assert!("1000".parse::<Tier>().is_err());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = InterestConfig {rate: dec!(-1.0), ..Default::default()};
+        assert!(config.validate().is_err());
+        let config = InterestConfig {rate: dec!(1.0), tiers: vec!["1000:-0.5".parse().unwrap()], ..Default::default()};
+        assert!(config.validate().is_err());
+        let config = InterestConfig {rate: dec!(1.0), tiers: vec!["-1000:0.5".parse().unwrap()], ..Default::default()};
+        assert!(config.validate().is_err());
+        let config = InterestConfig {rate: dec!(1.0), tiers: vec!["1000:0.5".parse().unwrap(), "1000:2.0".parse().unwrap()], ..Default::default()};
+        assert!(config.validate().is_err());
+        let config = InterestConfig {rate: dec!(1.0), min_balance: dec!(-1), ..Default::default()};
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn accrual_overflow() {
+        let mut accounts = HashMap::new();
+        accounts.insert(1, AccountState::with_balance(dec!(100)));
+        accounts.insert(2, AccountState::with_balance(Decimal::MAX - dec!(1)));
+        let config = InterestConfig {rate: dec!(10), ..Default::default()};
+        assert!(accrue(&mut accounts, &config, &mut Journal::default()).is_err());
+        assert_eq!(accounts[&1].available, dec!(100));
+        // interest itself fits, balance with interest does not
+        let config = InterestConfig {rate: dec!(1), ..Default::default()};
+        assert!(accrue(&mut accounts, &config, &mut Journal::default()).is_err());
+        assert_eq!(accounts[&1].available, dec!(100));
+        assert_eq!(accounts[&2].available, Decimal::MAX - dec!(1));
+    }
+
+    #[test]
+    fn accrual() {
+        let mut accounts = HashMap::new();
+        accounts.insert(1, AccountState::with_balance(dec!(100)));
+        accounts.get_mut(&1).unwrap().transactions.insert(7, TrxEntry::new(dec!(100), None));
+        accounts.insert(2, AccountState {locked: true, ..AccountState::with_balance(dec!(100))});
+        accounts.insert(3, AccountState::with_balance(dec!(0)));
+        let config = InterestConfig {rate: dec!(0.5), ..Default::default()};
+        assert_eq!(accrue(&mut accounts, &config, &mut Journal::default()).unwrap(), (1, dec!(0.5)));
+        assert_eq!(accounts[&1].available, dec!(100.5));
+        assert_eq!(accounts[&1].transactions.get(8), Some(TrxEntry::new(dec!(0.5), None)));
+        assert_eq!(accounts[&2].available, dec!(100));
+
+        let config = InterestConfig {rate: dec!(0.5), tx: Some(8), ..Default::default()};
+        assert!(accrue(&mut accounts, &config, &mut Journal::default()).is_err());
+        assert_eq!(accounts[&1].available, dec!(100.5));
+    }
+}
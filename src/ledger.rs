@@ -0,0 +1,269 @@
+//! Double-entry ledger - every change of client balances is recorded as balanced postings.
+//!
+//! Each entry moves money between ledger accounts: `available` and `held` funds of clients and house accounts
+//! (settlement, chargeback loss, adjustments, fee income, interest expense and opening balances).
+//! Entries are recorded by transactions themselves according to their meaning (e.g. deposit moves money
+//! from settlement to available funds, dispute from available to held funds), not derived from changed balances,
+//! so verification of journal (postings of every record sum to zero and postings of client accounts sum to final
+//! balances of clients) checks that transactions changed balances the way they should. Journal is exported as CSV with columns `rec_no,tx,account,currency,amount`
+//! (`rec_no` is 0 for opening balances and for operations performed after all records were processed).
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Result, Context};
+
+use crate::{
+    TClientId, TMoney, TTrxID,
+    accounts::AccountState,
+    currency::Currency,
+};
+
+/// Ledger account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    /// Funds of client available for withdraw.
+    Available(TClientId),
+    /// Funds of client held by disputes.
+    Held(TClientId),
+    /// Money received (deposits) and paid out (withdrawals), counterparty of exchanges.
+    Settlement,
+    /// Money returned on chargebacks.
+    ChargebackLoss,
+    /// Administrative corrections of balances.
+    Adjustment,
+    /// Fees charged and not yet credited to house account.
+    FeeIncome,
+    /// Interest credited to clients.
+    InterestExpense,
+    /// Balances accounts were seeded with (snapshot or opening balances).
+    Opening,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => write!(f, "client:{}:available", client),
+            LedgerAccount::Held(client) => write!(f, "client:{}:held", client),
+            LedgerAccount::Settlement => write!(f, "settlement"),
+            LedgerAccount::ChargebackLoss => write!(f, "chargeback-loss"),
+            LedgerAccount::Adjustment => write!(f, "adjustment"),
+            LedgerAccount::FeeIncome => write!(f, "fee-income"),
+            LedgerAccount::InterestExpense => write!(f, "interest-expense"),
+            LedgerAccount::Opening => write!(f, "opening"),
+        }
+    }
+}
+
+/// Change of ledger account balance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Posting {
+    /// Number of input record which caused posting (0 - opening balances and final operations).
+    pub rec_no: u128,
+    /// Transaction ID.
+    pub tx: TTrxID,
+    pub account: LedgerAccount,
+    /// Currency of `amount` (`None` - default currency).
+    pub currency: Option<Currency>,
+    pub amount: TMoney,
+}
+
+/// Postings of processing run in order of records. Postings are recorded only if journal is enabled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Journal {
+    pub enabled: bool,
+    pub postings: Vec<Posting>,
+}
+
+impl Journal {
+    /// Creates journal which records postings if `enabled`.
+    pub fn new(enabled: bool) -> Self {
+        Self {enabled, postings: Vec::new()}
+    }
+
+    /// Records entry moving `amount` from one ledger account to another.
+    pub fn transfer(&mut self, rec_no: u128, tx: TTrxID, currency: Option<Currency>, from: LedgerAccount, to: LedgerAccount, amount: TMoney) {
+        if !self.enabled || amount.is_zero() {
+            return;
+        }
+        self.postings.push(Posting {rec_no, tx, account: from, currency, amount: -amount});
+        self.postings.push(Posting {rec_no, tx, account: to, currency, amount});
+    }
+
+    /// Records opening balances of `accounts` (moved from opening ledger account).
+    pub fn open(&mut self, accounts: &HashMap::<TClientId,AccountState>) {
+        let mut clients: Vec<_> = accounts.keys().collect();
+        clients.sort_unstable();
+        let opening = std::mem::take(&mut self.postings);
+        for client in clients {
+            for (currency, balance) in accounts[client].balances() {
+                self.transfer(0, 0, currency, LedgerAccount::Opening, LedgerAccount::Available(*client), balance.available);
+                self.transfer(0, 0, currency, LedgerAccount::Opening, LedgerAccount::Held(*client), balance.held);
+            }
+        }
+        self.postings.extend(opening);
+    }
+
+    /// Appends postings of other (e.g. worker) journal.
+    pub fn append(&mut self, other: &Journal) {
+        self.postings.extend(other.postings.iter().cloned());
+    }
+
+    /// Orders postings by record number (order of postings of one record is kept).
+    pub fn sort(&mut self) {
+        self.postings.sort_by_key(|posting| posting.rec_no);
+    }
+
+    /// Writes journal as CSV, default currency is written as empty string.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("rec_no,tx,account,currency,amount\n");
+        for posting in &self.postings {
+            let currency = posting.currency.map(|currency| currency.to_string()).unwrap_or_default();
+            csv.push_str(&format!("{},{},{},{},{}\n", posting.rec_no, posting.tx, posting.account, currency, posting.amount));
+        }
+        csv
+    }
+
+    /// Verifies that postings of every record (and transaction) sum to zero in every currency and postings
+    /// of client accounts sum to balances of `accounts`.
+    pub fn verify(&self, accounts: &HashMap::<TClientId,AccountState>) -> Verification {
+        let mut sums = BTreeMap::<(LedgerAccount, Option<Currency>), TMoney>::new();
+        let mut totals = BTreeMap::<(u128, TTrxID, Option<Currency>), TMoney>::new();
+        for posting in &self.postings {
+            *sums.entry((posting.account, posting.currency)).or_default() += posting.amount;
+            *totals.entry((posting.rec_no, posting.tx, posting.currency)).or_default() += posting.amount;
+        }
+        let mut verification = Verification {postings: self.postings.len(), ..Default::default()};
+        for ((rec_no, tx, currency), total) in totals {
+            if !total.is_zero() {
+                verification.differences.push(format!(
+                    "postings of record {} (transaction {}) in {} sum to {}", rec_no, tx, currency_name(currency), total));
+            }
+        }
+        let mut clients: Vec<_> = accounts.keys().copied()
+            .chain(sums.keys().filter_map(|(account, _)| match account {
+                LedgerAccount::Available(client) | LedgerAccount::Held(client) => Some(*client),
+                _ => None,
+            }))
+            .collect();
+        clients.sort_unstable();
+        clients.dedup();
+        for client in clients {
+            let acct = accounts.get(&client).cloned().unwrap_or_default();
+            let mut currencies: Vec<_> = acct.balances().map(|(currency, _)| currency)
+                .chain(sums.keys().filter(|(account, _)| matches!(account,
+                    LedgerAccount::Available(cli) | LedgerAccount::Held(cli) if *cli == client)).map(|(_, currency)| *currency))
+                .collect();
+            currencies.sort_unstable();
+            currencies.dedup();
+            for currency in currencies {
+                let balance = acct.balance(currency);
+                let fields = [
+                    ("available", LedgerAccount::Available(client), balance.available),
+                    ("held", LedgerAccount::Held(client), balance.held),
+                ];
+                for (field, account, actual) in fields {
+                    let posted = sums.get(&(account, currency)).copied().unwrap_or_default();
+                    if posted != actual {
+                        verification.differences.push(format!(
+                            "client {} {} in {}: postings {} vs balance {}", client, field, currency_name(currency), posted, actual));
+                    }
+                }
+            }
+        }
+        verification
+    }
+
+    /// Saves journal to CSV file.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        async_std::fs::write(path, self.to_csv()).await
+            .with_context(|| format!("writing journal file: {}", path.display()))
+    }
+}
+
+fn currency_name(currency: Option<Currency>) -> String {
+    currency.map(|currency| currency.to_string()).unwrap_or_else(|| "default currency".to_string())
+}
+
+/// Result of journal verification.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Number of verified postings.
+    pub postings: usize,
+    /// Found inconsistencies.
+    pub differences: Vec<String>,
+}
+
+impl Verification {
+    /// Returns `true` if no inconsistencies were found.
+    pub fn is_ok(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "ledger balanced: {} postings sum to zero and match final balances", self.postings);
+        }
+        write!(f, "ledger not balanced: {} postings, {} differences", self.postings, self.differences.len())?;
+        for difference in &self.differences {
+            write!(f, "\n{}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use crate::transactions::{TransactionInt, Policy, Deposit, Dispute};
+    use super::*;
+
+    #[test]
+    fn balanced() {
+        let mut accounts = HashMap::from([(1, AccountState::with_balance(dec!(10)))]);
+        let mut journal = Journal::new(true);
+        journal.open(&accounts);
+        let acct = accounts.get_mut(&1).expect("client 1 in test accounts");
+        acct.available -= dec!(4);
+        acct.held += dec!(4);
+        journal.transfer(1, 1, None, LedgerAccount::Available(1), LedgerAccount::Held(1), dec!(4));
+        assert_eq!(journal.postings.len(), 4); // opening, then move from available to held
+        journal.transfer(2, 1, None, LedgerAccount::Available(1), LedgerAccount::FeeIncome, dec!(1));
+        assert!(!journal.verify(&accounts).is_ok());
+        accounts.get_mut(&1).expect("client 1 in test accounts").available -= dec!(1);
+        let verification = journal.verify(&accounts);
+        assert!(verification.is_ok(), "{}", verification);
+    }
+
+    #[test]
+    fn wrong_commit() {
+        let mut accounts = HashMap::new();
+        let mut journal = Journal::new(true);
+        let deposit = Deposit::new(1, 1, dec!(10));
+        assert!(deposit.commit_journaled(&mut accounts, &Policy::default(), 1, &mut journal).is_ok());
+        let dispute = Dispute::test(1, 1);
+        assert!(dispute.commit_journaled(&mut accounts, &Policy::default(), 2, &mut journal).is_ok());
+        let verification = journal.verify(&accounts);
+        assert!(verification.is_ok(), "{}", verification);
+        // dispute which holds money without taking it from available funds
+        let acct = accounts.get_mut(&1).expect("client 1 in test accounts");
+        acct.available += dec!(10);
+        let verification = journal.verify(&accounts);
+        assert_eq!(verification.differences, ["client 1 available in default currency: postings 0 vs balance 10"]);
+        // posting of unbalanced entry
+        journal.postings.pop();
+        assert!(journal.verify(&accounts).differences.iter().any(|difference| difference.starts_with("postings of record 2")));
+    }
+
+    #[test]
+    fn disabled() {
+        let accounts = HashMap::from([(1, AccountState::with_balance(dec!(10)))]);
+        let mut journal = Journal::default();
+        journal.open(&accounts);
+        journal.transfer(1, 1, None, LedgerAccount::Settlement, LedgerAccount::Available(1), dec!(1));
+        assert!(journal.postings.is_empty());
+    }
+}
@@ -1,10 +1,13 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
 use argh::FromArgs;
 use async_std::fs::File;
 use csv_async::AsyncReaderBuilder;
+use futures::io::AsyncRead;
 
 /// Type to store client ID.
 pub type TClientId = u16;
@@ -15,11 +18,38 @@ pub type TTrxID = u32;
 /// Type to store Money.
 pub type TMoney = rust_decimal::Decimal;
 
+/// Type to store transaction time (milliseconds since Unix epoch).
+pub type TTimestamp = i64;
+
 pub mod accounts;
+pub mod currency;
+pub mod snapshot;
+pub mod opening;
+pub mod parser;
+pub mod reconcile;
+pub mod generator;
+pub mod transactions;
+pub mod expiry;
+pub mod fees;
+pub mod interest;
+pub mod ledger;
+pub mod rates;
+pub mod retention;
+pub mod statement;
+pub mod store;
 mod processor;
-mod transactions;
 
 use accounts::AccountState;
+use expiry::{DisputeWindow, ExpiryAction, WindowUnit};
+use currency::Currency;
+use interest::{InterestConfig, Tier};
+use ledger::Journal;
+use parser::{ParallelReader, RecordReader, Records, BLOCK_SIZE};
+use rates::Rounding;
+use retention::{Retention, Spill};
+use statement::StatementFormat;
+use processor::{Batching, Settings};
+use transactions::{DisputePolicy, Policy};
 
 #[derive(FromArgs)]
 /// Toy Transaction Engine.
@@ -40,23 +70,514 @@ pub struct Args {
     #[argh(option, default = "10")]
     wrk_buff: usize,
 
-    /// path to transactions CSV file with columns (type,client,tx,amount)
+    /// maximal number of transactions sent to worker at once (default: 64)
+    #[argh(option, default = "64")]
+    batch_size: usize,
+
+    /// maximal time (in milliseconds) transaction waits in batch which is not full (default: 10)
+    #[argh(option, default = "10")]
+    batch_latency: u64,
+
+    /// number of threads parsing transactions file in blocks (default: 1 - file is parsed by processing loop),
+    /// records must not contain line breaks in quoted fields
+    #[argh(option, default = "1")]
+    parse_threads: usize,
+
+    /// assignment of clients to workers: round-robin (default), hash (by client ID, without client to worker map)
+    /// or load (to worker with the least queued transactions)
+    #[argh(option, default = "Assignment::RoundRobin")]
+    assignment: Assignment,
+
+    /// processing engine: multi (multi-threaded, default) or simple (single-threaded reference)
+    #[argh(option, default = "Engine::Multi")]
+    engine: Engine,
+
+    /// run both engines on the same input and fail if their results differ
+    #[argh(switch)]
+    verify: bool,
+
+    /// length of window (in --dispute-window-unit) after which open dispute is closed automatically
+    #[argh(option)]
+    dispute_window: Option<u64>,
+
+    /// unit of dispute window: transactions (subsequent transactions of client, default) or seconds
+    #[argh(option, default = "WindowUnit::Transactions")]
+    dispute_window_unit: WindowUnit,
+
+    /// handling of disputes which would drive available balance negative: allow-negative (default), reject, 
+    /// cap (hold at most available funds) or deposits-only (withdrawals may not be disputed)
+    #[argh(option, default = "DisputePolicy::AllowNegative")]
+    dispute_policy: DisputePolicy,
+
+    /// action on dispute after its window expired: resolve (default) or chargeback
+    #[argh(option, default = "ExpiryAction::Resolve")]
+    dispute_expiry: ExpiryAction,
+
+    /// handling of records which timestamp goes backwards for client: ignore (default), warn or reject
+    #[argh(option, default = "TsOrder::Ignore")]
+    ts_order: TsOrder,
+
+    /// input comes from privileged source (operations team) - accept administrative records (unlock, adjust)
+    #[argh(switch)]
+    privileged: bool,
+
+    /// path to CSV file with fees charged on withdrawals and chargebacks, with columns (type,flat,percent,min,max)
+    #[argh(option)]
+    fee_schedule: Option<PathBuf>,
+
+    /// house account where charged fees are accumulated (required with fee schedule)
+    #[argh(option)]
+    fee_account: Option<TClientId>,
+
+    /// currency of records treated as default currency of accounts (records without currency are always 
+    /// in default currency)
+    #[argh(option)]
+    pub base_currency: Option<Currency>,
+
+    /// path to CSV file with exchange rates used by exchange records, with columns (from,to,rate[,effective])
+    #[argh(option)]
+    exchange_rates: Option<PathBuf>,
+
+    /// spread in percent which lowers exchange rates given to clients (default: 0)
+    #[argh(option, default = "TMoney::ZERO")]
+    exchange_spread: TMoney,
+
+    /// rounding of exchanged amounts (to 4 decimal places): half-even (default), half-up or down
+    #[argh(option, default = "Rounding::HalfEven")]
+    exchange_rounding: Rounding,
+
+    /// path to CSV file where ledger postings of run will be saved, with columns (rec_no,tx,account,currency,amount)
+    #[argh(option)]
+    journal: Option<PathBuf>,
+
+    /// keep at most given number of the most recent transactions per client (older ones can not be disputed)
+    #[argh(option)]
+    retain_last: Option<usize>,
+
+    /// keep only transactions not older than given number of seconds, measured with timestamp of the latest
+    /// transaction of client (older ones can not be disputed)
+    #[argh(option)]
+    retain_seconds: Option<u64>,
+
+    /// path to CSV file where transactions evicted by retention policy will be saved, with columns
+    /// (client,tx,amount,currency,timestamp,charged_back,linked_amount,linked_currency)
+    #[argh(option)]
+    retention_spill: Option<PathBuf>,
+
+    /// record ordered history of operations in accounts (kept in snapshot, always recorded with statement command)
+    #[argh(switch)]
+    history: bool,
+
+    /// path to snapshot file with state of accounts to start processing from
+    #[argh(option)]
+    snapshot_in: Option<PathBuf>,
+
+    /// path to snapshot file where final state of accounts will be saved
+    #[argh(option)]
+    snapshot_out: Option<PathBuf>,
+
+    /// path to CSV file with columns (client,available,held,locked) to seed accounts with before processing
+    #[argh(option)]
+    opening_balances: Option<PathBuf>,
+
+    /// print processing statistics to stderr
+    #[argh(switch)]
+    pub stats: bool,
+
+    /// path to transactions CSV file with columns (type,client,tx,amount[,timestamp[,reason[,currency[,to_currency]]]])
     #[argh(positional)]
     trx_file: PathBuf,
+
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Processing engine.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Engine {
+    /// Multi-threaded engine - clients are partitioned between workers.
+    Multi,
+    /// Single-threaded reference engine.
+    Simple,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "multi" => Ok(Engine::Multi),
+            "simple" => Ok(Engine::Simple),
+            _ => Err(format!("unknown engine: {} (expected multi or simple)", s)),
+        }
+    }
+}
+
+/// Strategy of assignment of clients to workers of multi-threaded engine.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Assignment {
+    /// New clients are assigned to workers in turn.
+    #[default]
+    RoundRobin,
+    /// Worker is computed from client ID, so there is no need to remember assignment of clients.
+    Hash,
+    /// New client is assigned to worker with the least transactions waiting to be applied.
+    Load,
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Assignment::RoundRobin),
+            "hash" => Ok(Assignment::Hash),
+            "load" => Ok(Assignment::Load),
+            _ => Err(format!("unknown assignment: {} (expected round-robin, hash or load)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Assignment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Assignment::RoundRobin => "round-robin",
+            Assignment::Hash => "hash",
+            Assignment::Load => "load",
+        })
+    }
+}
+
+/// Handling of records which timestamp is earlier than timestamp of previous record of the same client.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TsOrder {
+    /// Timestamps order is not checked.
+    #[default]
+    Ignore,
+    /// Warning is printed, record is processed.
+    Warn,
+    /// Warning is printed, record is skipped.
+    Reject,
+}
+
+impl FromStr for TsOrder {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(TsOrder::Ignore),
+            "warn" => Ok(TsOrder::Warn),
+            "reject" => Ok(TsOrder::Reject),
+            _ => Err(format!("unknown timestamp order mode: {} (expected ignore, warn or reject)", s)),
+        }
+    }
+}
+
+/// Additional operations performed on processing result.
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum Command {
+    Reconcile(ReconcileArgs),
+    AccrueInterest(InterestArgs),
+    VerifyLedger(VerifyLedgerArgs),
+    Statement(StatementArgs),
+}
+
+#[derive(FromArgs)]
+/// Credit interest on available balances of not locked accounts after processing (before snapshot is saved),
+/// then print accounts.
+#[argh(subcommand, name = "accrue-interest")]
+pub struct InterestArgs {
+    /// interest rate in percent per period
+    #[argh(option)]
+    pub rate: TMoney,
+
+    /// rate in percent applied to part of balance above threshold, given as threshold:rate (may be repeated)
+    #[argh(option)]
+    pub tier: Vec<Tier>,
+
+    /// minimal available balance for which interest is accrued (default: 0)
+    #[argh(option, default = "TMoney::ZERO")]
+    pub min_balance: TMoney,
+
+    /// ID of interest transactions (default: next to the greatest transaction ID of all accounts)
+    #[argh(option)]
+    pub tx: Option<TTrxID>,
+}
+
+impl TryFrom<&InterestArgs> for InterestConfig {
+    type Error = anyhow::Error;
+    fn try_from(arg: &InterestArgs) -> Result<Self> {
+        let config = Self {rate: arg.rate, tiers: arg.tier.clone(), min_balance: arg.min_balance, tx: arg.tx};
+        config.validate().context("invalid interest settings")?;
+        Ok(config)
+    }
+}
+
+#[derive(FromArgs)]
+/// Compare final accounts with expected balances, print differences as CSV.
+/// Exit code is 0 if balances match, 2 if differences were found.
+#[argh(subcommand, name = "reconcile")]
+pub struct ReconcileArgs {
+    /// path to CSV file with expected balances with columns (client,available,held,total,locked)
+    #[argh(positional)]
+    pub expected: PathBuf,
+}
+
+#[derive(FromArgs)]
+/// Verify that ledger postings of run sum to zero and match final balances of accounts, print verification result.
+/// Exit code is 0 if ledger is balanced, 2 otherwise.
+#[argh(subcommand, name = "verify-ledger")]
+pub struct VerifyLedgerArgs {}
+
+#[derive(FromArgs)]
+/// Print statements of clients (operations recorded in account history with opening and closing balances).
+#[argh(subcommand, name = "statement")]
+pub struct StatementArgs {
+    /// client which statement is printed (may be repeated, default: all clients)
+    #[argh(option)]
+    pub client: Vec<TClientId>,
+
+    /// output format: csv (default) or json
+    #[argh(option, default = "StatementFormat::Csv")]
+    pub format: StatementFormat,
+}
+
+/// Processing statistics.
+#[derive(Default)]
+pub struct Stats {
+    /// Number of successfully processed transactions.
+    pub processed: u128,
+    /// Number of accounts seeded from opening balances file.
+    pub seeded: usize,
+    /// Number of disputes resolved automatically after their window expired.
+    pub auto_resolved: u128,
+    /// Number of disputes charged back automatically after their window expired.
+    pub auto_charged_back: u128,
+    /// Number of records which timestamp goes backwards for client (counted with --ts-order warn or reject).
+    pub out_of_order: u128,
+    /// Total of fees charged according to fee schedule.
+    pub fees: TMoney,
+    /// Number of accounts credited with interest.
+    pub interest_accounts: usize,
+    /// Total of credited interest.
+    pub interest: TMoney,
+    /// Number of transactions evicted from accounts by retention policy.
+    pub evicted: u128,
+    /// Ledger postings of run (recorded with --journal or verify-ledger command).
+    pub journal: Journal,
+    /// Assignment of clients to workers (`None` - single-threaded engine).
+    pub assignment: Option<Assignment>,
+    /// Number of successfully processed transactions of every worker.
+    pub worker_processed: Vec<u128>,
 }
 
-/// Performs transaction processing based on parameters passed in `Arg` argument, updates passed accounts object.
+impl Stats {
+    /// Adds counters of partial (e.g. worker) statistics.
+    /// Fees which would overflow the total are not added (they stay in fee income of journal),
+    /// diagnostic is printed unless `quiet`.
+    fn add(&mut self, other: &Stats, quiet: bool) {
+        self.processed = self.processed.wrapping_add(other.processed);
+        self.seeded += other.seeded;
+        self.auto_resolved += other.auto_resolved;
+        self.auto_charged_back += other.auto_charged_back;
+        self.out_of_order += other.out_of_order;
+        match self.fees.checked_add(other.fees) {
+            Some(total) => self.fees = total,
+            None => if !quiet {
+                eprintln!("fees {} not collected: total of fees overflow", other.fees);
+            },
+        }
+        self.interest_accounts += other.interest_accounts;
+        self.interest += other.interest;
+        self.evicted += other.evicted;
+        self.journal.append(&other.journal);
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "processed: {}", self.processed)?;
+        writeln!(f, "seeded: {}", self.seeded)?;
+        writeln!(f, "auto-resolved disputes: {}", self.auto_resolved)?;
+        writeln!(f, "auto-charged back disputes: {}", self.auto_charged_back)?;
+        writeln!(f, "out of order timestamps: {}", self.out_of_order)?;
+        writeln!(f, "fees: {}", self.fees)?;
+        writeln!(f, "evicted transactions: {}", self.evicted)?;
+        if let Some(assignment) = self.assignment {
+            let processed: Vec<_> = self.worker_processed.iter().map(u128::to_string).collect();
+            writeln!(f, "assignment: {} (processed by workers: {})", assignment, processed.join(", "))?;
+        }
+        write!(f, "interest: {} (accounts: {})", self.interest, self.interest_accounts)
+    }
+}
+
+/// Performs transaction processing based on parameters passed in `Arg` argument, returns number of successfully 
+/// processed transactions and final accounts state.
 /// Function separated from `main()` to feature integration tests.
 /// See Integration tests in `tests` folder for example usage.
 pub async fn process(arg:&Args) -> Result<(u128, HashMap::<TClientId,AccountState>)> {
-    let trx_file = File::open(&arg.trx_file).await
-        .with_context(|| format!("opening transactions file: {}", arg.trx_file.display()))?;
-    let rdr = AsyncReaderBuilder::new()
-        .has_headers(!arg.no_header)
-        .comment(if arg.comments {Some(b'#')} else {None})
+    let (stats, accounts) = process_with_stats(arg).await?;
+    Ok((stats.processed, accounts))
+}
+
+/// Performs transaction processing based on parameters passed in `Arg` argument, returns processing statistics
+/// and final accounts state.
+/// Processing starts from empty accounts or from state loaded from `--snapshot-in` file,
+/// then accounts are seeded with `--opening-balances` file (if given).
+/// Transactions are applied by engine selected with `--engine`, with `--verify` both engines are run 
+/// and function fails if their results differ.
+/// With `accrue-interest` command interest is credited before final state is saved to `--snapshot-out` file.
+/// Ledger postings (returned in statistics) are recorded with `--journal` option or `verify-ledger` command.
+pub async fn process_with_stats(arg:&Args) -> Result<(Stats, HashMap::<TClientId,AccountState>)> {
+    let interest = match &arg.command {
+        Some(Command::AccrueInterest(interest_arg)) => Some(InterestConfig::try_from(interest_arg)?),
+        _ => None,
+    };
+    let mut accounts = match &arg.snapshot_in {
+        Some(path) => snapshot::load(path).await?,
+        None => HashMap::new(),
+    };
+    let seeded = match &arg.opening_balances {
+        Some(path) => opening::load(path, &mut accounts).await?,
+        None => 0,
+    };
+    let (mut stats, mut accounts) = if arg.verify {
+        let settings = settings(arg).await?;
+        let result = processor::processing_loop(
+            open_records(arg).await?, accounts.clone(), &settings, arg.wrk_num, arg.wrk_buff, batching(arg), arg.assignment).await?;
+        flush_spill(&settings)?;
+        // evicted transactions are spilled only once, diagnostics are printed only once
+        let ref_settings = Settings {retention: settings.retention.as_ref().map(Retention::without_spill), quiet: true, ..settings.clone()};
+        let ref_result = processor::simple_loop(open_records(arg).await?, accounts, &ref_settings).await?;
+        processor::compare_results(&result, &ref_result)?;
+        result
+    } else {
+        process_records(arg, open_records(arg).await?, accounts).await?
+    };
+    stats.seeded = seeded;
+    if let Some(config) = &interest {
+        (stats.interest_accounts, stats.interest) = interest::accrue(&mut accounts, config, &mut stats.journal)?;
+    }
+    if let Some(path) = &arg.journal {
+        stats.journal.save(path).await?;
+    }
+    if let Some(path) = &arg.snapshot_out {
+        snapshot::save(path, &accounts).await?;
+    }
+    Ok((stats, accounts))
+}
+
+/// Applies transactions read from `data` on top of `accounts` with engine selected in `Arg` argument,
+/// returns processing statistics and final accounts state.
+/// Input file, snapshot and verification options of `Arg` are ignored here - this is part of `process` 
+/// separated to allow processing of data from other sources (e.g. fuzzing).
+pub async fn process_reader<R>(arg:&Args, data: R, accounts: HashMap::<TClientId,AccountState>) 
+    -> Result<(Stats, HashMap::<TClientId,AccountState>)>
+    where R: AsyncRead + Unpin + Send
+{
+    let records = Records::Sequential(RecordReader::new(reader_builder(arg).create_deserializer(data)).await
+        .context("reading transactions headers")?);
+    process_records(arg, records, accounts).await
+}
+
+/// Applies transactions of `records` on top of `accounts` with engine selected in `Arg` argument.
+async fn process_records<R>(arg:&Args, records: Records<R>, accounts: HashMap::<TClientId,AccountState>) 
+    -> Result<(Stats, HashMap::<TClientId,AccountState>)>
+    where R: AsyncRead + Unpin + Send
+{
+    let settings = settings(arg).await?;
+    let result = match arg.engine {
+        Engine::Multi => processor::processing_loop(
+            records, accounts, &settings, arg.wrk_num, arg.wrk_buff, batching(arg), arg.assignment).await?,
+        Engine::Simple => processor::simple_loop(records, accounts, &settings).await?,
+    };
+    flush_spill(&settings)?;
+    Ok(result)
+}
+
+/// Writes buffered evicted transactions to retention spill file (if given).
+fn flush_spill(settings: &Settings) -> Result<()> {
+    match settings.retention.as_ref().and_then(|retention| retention.spill.as_ref()) {
+        Some(spill) => spill.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Returns processing settings according to passed `Arg` argument, loads fee schedule and exchange rates (if given).
+async fn settings(arg:&Args) -> Result<Settings> {
+    let fees = match (&arg.fee_schedule, arg.fee_account) {
+        (Some(path), Some(account)) => Some(fees::load(path, account).await?),
+        (Some(_), None) => bail!("fee schedule given without house account (--fee-account)"),
+        (None, Some(_)) => bail!("house account (--fee-account) given without fee schedule"),
+        (None, None) => None,
+    };
+    if arg.exchange_spread.is_sign_negative() || arg.exchange_spread >= TMoney::ONE_HUNDRED {
+        bail!("exchange spread must be in range 0..100 percent: {}", arg.exchange_spread)
+    }
+    let retention = match (arg.retain_last, arg.retain_seconds, &arg.retention_spill) {
+        (None, None, Some(_)) => bail!("retention spill file given without retention policy (--retain-last or --retain-seconds)"),
+        (None, None, None) => None,
+        (last, seconds, spill) => Some(Retention {
+            last,
+            seconds,
+            spill: spill.as_deref().map(Spill::create).transpose()?,
+        }),
+    };
+    let exchange = match &arg.exchange_rates {
+        Some(path) => {
+            let mut rates = rates::load(path, arg.base_currency).await?;
+            rates.spread = arg.exchange_spread;
+            rates.rounding = arg.exchange_rounding;
+            Some(rates)
+        },
+        None => None,
+    };
+    Ok(Settings {
+        dispute_window: arg.dispute_window.map(|length| DisputeWindow {length, unit: arg.dispute_window_unit, action: arg.dispute_expiry}),
+        ts_order: arg.ts_order,
+        privileged: arg.privileged,
+        policy: Policy {dispute: arg.dispute_policy, exchange},
+        fees,
+        base_currency: arg.base_currency,
+        journal: arg.journal.is_some() || matches!(arg.command, Some(Command::VerifyLedger(_))),
+        history: arg.history || matches!(arg.command, Some(Command::Statement(_))),
+        retention,
+        quiet: false,
+    })
+}
+
+/// Returns batching of transactions sent to workers according to passed `Arg` argument.
+fn batching(arg:&Args) -> Batching {
+    Batching {size: arg.batch_size, latency: std::time::Duration::from_millis(arg.batch_latency)}
+}
+
+/// Returns CSV reader builder configured according to passed `Arg` argument.
+pub fn reader_builder(arg:&Args) -> AsyncReaderBuilder {
+    csv_builder(!arg.no_header, arg.comments)
+}
+
+fn csv_builder(has_headers: bool, comments: bool) -> AsyncReaderBuilder {
+    let mut builder = AsyncReaderBuilder::new();
+    builder
+        .has_headers(has_headers)
+        .comment(if comments {Some(b'#')} else {None})
         .trim(csv_async::Trim::All)
-        .flexible(true)
-        .create_deserializer(trx_file);
+        .flexible(true);
+    builder
+}
+
+/// Opens transactions file as source of records, parsed by `--parse-threads` threads if more than one.
+async fn open_records(arg:&Args) -> Result<Records<File>> {
+    if arg.parse_threads > 1 {
+        let (has_headers, comments) = (!arg.no_header, arg.comments);
+        let records = ParallelReader::open(&arg.trx_file, arg.parse_threads, BLOCK_SIZE, move || csv_builder(has_headers, comments)).await?;
+        Ok(Records::Parallel(records))
+    } else {
+        let records = RecordReader::new(reader_builder(arg).create_deserializer(open_transactions(arg).await?)).await
+            .with_context(|| format!("reading transactions file: {}", arg.trx_file.display()))?;
+        Ok(Records::Sequential(records))
+    }
+}
 
-    processor::processing_loop(rdr, arg.wrk_num, arg.wrk_buff).await
+async fn open_transactions(arg:&Args) -> Result<File> {
+    File::open(&arg.trx_file).await
+        .with_context(|| format!("opening transactions file: {}", arg.trx_file.display()))
 }
@@ -1,24 +1,82 @@
+use std::process::ExitCode;
+
 use anyhow::Result;
 
 use toy_trx_engine:: {
     Args,
-    process,
-    accounts::AccountState,
+    Command,
+    process_with_stats,
+    accounts::{self, AccountState},
+    reconcile,
+    statement::{self, StatementFormat},
 };
 
 #[async_std::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let arg: Args = argh::from_env();
 
-    let (_, accounts) = process(&arg).await?;
+    let (stats, accounts) = process_with_stats(&arg).await?;
+    if arg.stats {
+        eprintln!("{}", stats);
+    }
+    let negative = accounts::negative_accounts(&accounts);
+    if !negative.is_empty() {
+        eprintln!("Accounts with negative available balance: {}", negative.len());
+        for client in negative {
+            let negative = accounts[&client].balances().filter(|(_, balance)| balance.available.is_sign_negative() && !balance.available.is_zero());
+            for (currency, balance) in negative {
+                match currency {
+                    Some(currency) => eprintln!("client {} ({}): available {}, held {}, total {}", client, currency, balance.available, balance.held, balance.total()),
+                    None => eprintln!("client {}: available {}, held {}, total {}", client, balance.available, balance.held, balance.total()),
+                }
+            }
+        }
+    }
 
-    print!("client,");
-    AccountState::print_headers_to_stdout();
-    println!();
-    for (client, account) in accounts {
-        print!("{},", client);
-        account.print_as_csv_to_stdout();
-        println!();
+    match &arg.command {
+        None | Some(Command::AccrueInterest(_)) if accounts.values().any(|acct| !acct.currencies.is_empty()) => {
+            // one row per client and currency
+            println!("client,currency,available,held,total,locked");
+            for (client, account) in accounts {
+                for (currency, balance) in account.balances() {
+                    let currency = currency.or(arg.base_currency).map(|currency| currency.to_string()).unwrap_or_default();
+                    println!("{},{},{},{},{},{}", client, currency, balance.available, balance.held, balance.total(), account.locked);
+                }
+            }
+        },
+        None | Some(Command::AccrueInterest(_)) => {
+            print!("client,");
+            AccountState::print_headers_to_stdout();
+            println!();
+            for (client, account) in accounts {
+                print!("{},", client);
+                account.print_as_csv_to_stdout();
+                println!();
+            }
+        },
+        Some(Command::Reconcile(rec)) => {
+            let report = reconcile::reconcile_file(&rec.expected, &accounts).await?;
+            println!("{}", report);
+            let (missing, extra, mismatched) = report.counts();
+            eprintln!("Reconciliation: {} missing, {} extra clients, {} mismatched fields", missing, extra, mismatched);
+            if !report.is_ok() {
+                return Ok(ExitCode::from(2));
+            }
+        },
+        Some(Command::Statement(statement_arg)) => {
+            let statements = statement::statements(&accounts, &statement_arg.client);
+            match statement_arg.format {
+                StatementFormat::Csv => print!("{}", statement::to_csv(&statements)),
+                StatementFormat::Json => println!("{}", statement::to_json(&statements)?),
+            }
+        },
+        Some(Command::VerifyLedger(_)) => {
+            let verification = stats.journal.verify(&accounts);
+            println!("{}", verification);
+            if !verification.is_ok() {
+                return Ok(ExitCode::from(2));
+            }
+        },
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,118 @@
+//! Opening balances of accounts.
+//!
+//! Simple CSV file with columns `client,available,held,locked` used to seed accounts before transactions are applied.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Result, Context, bail};
+use async_std::{fs::File, stream::StreamExt};
+use csv_async::AsyncReaderBuilder;
+use futures::io::AsyncRead;
+use serde::Deserialize;
+
+use crate::{
+    TClientId, TMoney,
+    accounts::AccountState,
+};
+
+/// Represents opening balance record as read from input file.
+#[derive(Deserialize)]
+struct OpeningRec {
+    client: TClientId,
+    available: TMoney,
+    held: TMoney,
+    locked: bool,
+}
+
+/// Seeds `accounts` with opening balances read from file, returns number of seeded accounts.
+/// See `seed` for details.
+pub async fn load(path: &Path, accounts: &mut HashMap::<TClientId,AccountState>) -> Result<usize> {
+    let file = File::open(path).await
+        .with_context(|| format!("opening balances file: {}", path.display()))?;
+    seed(file, accounts).await
+        .with_context(|| format!("loading balances file: {}", path.display()))
+}
+
+/// Seeds `accounts` with opening balances read from `data`, returns number of seeded accounts.
+/// - if account is not registered - register it with given balances.
+/// - if account is registered (e.g. loaded from snapshot) - replace its balances but keep its transactions.
+/// - if `held` is not zero it must be equal to sum of amounts of disputed transactions of account - otherwise fail.
+/// - if client occurs more than once in data - fail.
+pub async fn seed<R>(data: R, accounts: &mut HashMap::<TClientId,AccountState>) -> Result<usize>
+    where R: AsyncRead + Unpin + Send
+{
+    let mut rdr = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(data);
+    let mut records = rdr.deserialize::<OpeningRec>();
+    let mut seeded = HashSet::new();
+    let mut rec_no = 0usize;
+    while let Some(record) = records.next().await {
+        rec_no += 1;
+        let rec = record.with_context(|| format!("Record# {} - parsing failed", rec_no))?;
+        if !seeded.insert(rec.client) {
+            bail!("Record# {} - client {} seeded more than once", rec_no, rec.client)
+        }
+        let acct = accounts.entry(rec.client).or_default();
+        if !rec.held.is_zero() {
+            let disputed = acct.disputed();
+            if disputed != rec.held {
+                bail!("Record# {} - held amount {} of client {} is not matched by disputed transactions ({})", rec_no, rec.held, rec.client, disputed)
+            }
+        }
+        acct.available = rec.available;
+        acct.held = rec.held;
+        acct.locked = rec.locked;
+    }
+    Ok(seeded.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use crate::accounts::TrxEntry;
+    use super::*;
+
+    #[async_std::test]
+    async fn new_accounts() {
+        let mut accounts = HashMap::new();
+        let data = "client,available,held,locked\n1, 10.5, 0, false\n2, 0.1234, 0.0, true\n";
+        assert_eq!(seed(data.as_bytes(), &mut accounts).await.expect("seeded"), 2);
+        let acct = accounts.get(&1).expect("client 1 seeded");
+        assert_eq!(acct.available, dec!(10.5));
+        assert!(!acct.locked);
+        let acct = accounts.get(&2).expect("client 2 seeded");
+        assert_eq!(acct.available, dec!(0.1234));
+        assert!(acct.locked);
+    }
+
+    #[async_std::test]
+    async fn held_without_dispute() {
+        let mut accounts = HashMap::new();
+        let data = "client,available,held,locked\n1, 10.5, 1.0, false\n";
+        assert!(seed(data.as_bytes(), &mut accounts).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn held_matched_by_dispute() {
+        let mut accounts = HashMap::new();
+        let mut acct = AccountState::with_balance(dec!(1.0));
+        acct.held = dec!(2.0);
+        acct.transactions.insert(1, TrxEntry::new(dec!(1.0), None));
+        acct.transactions.insert(2, TrxEntry {disputed: dec!(2.0), ..TrxEntry::new(dec!(2.0), None)});
+        accounts.insert(1, acct);
+        let data = "client,available,held,locked\n1, 5.0, 2.0, false\n";
+        assert_eq!(seed(data.as_bytes(), &mut accounts).await.expect("seeded"), 1);
+        let acct = accounts.get(&1).expect("client 1 seeded");
+        assert_eq!(acct.available, dec!(5.0));
+        assert_eq!(acct.transactions.len(), 2);
+    }
+
+    #[async_std::test]
+    async fn duplicated_client() {
+        let mut accounts = HashMap::new();
+        let data = "client,available,held,locked\n1, 1.0, 0, false\n1, 2.0, 0, false\n";
+        assert!(seed(data.as_bytes(), &mut accounts).await.is_err());
+    }
+}
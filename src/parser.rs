@@ -0,0 +1,557 @@
+//! Fast path of transaction records parsing.
+//!
+//! Records of transactions files have mostly basic `type,client,tx,amount` form. Transactions of such records are
+//! built directly from fields of CSV record read into reused buffer, without serde and without allocation. Other records
+//! (with timestamp, reason or currency, with unusual number formats or with unknown type) fall back to serde,
+//! so that the result (including errors) is the same as if all records were deserialized with serde.
+//! Fast path is used only if columns of file start with `type,client,tx,amount` (or file has no headers).
+//!
+//! Transactions file may be also parsed by several threads (see `ParallelReader`), records are returned
+//! in order of file anyway - so that records of every client come in order and record numbers are the same.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Result, Context};
+use async_std::{channel::{self, Receiver, Sender}, fs::File, io::{BufReader, prelude::*}, task};
+use csv_async::{AsyncDeserializer, AsyncReaderBuilder, ByteRecord, Position};
+use futures::io::AsyncRead;
+
+use crate::{
+    TMoney,
+    transactions::{Transaction, TransactionRec, Deposit, Withdrawal, Dispute, Resolve, Chargeback},
+};
+
+/// Columns parsed by fast path (in this order).
+const FAST_COLUMNS: [&[u8]; 4] = [b"type", b"client", b"tx", b"amount"];
+
+/// Record of transactions file.
+pub enum Record {
+    /// Transaction built by fast path.
+    Transaction(Transaction),
+    /// Record deserialized with serde (to be converted to transaction).
+    Rec(TransactionRec),
+}
+
+/// Reader of transaction records.
+pub struct RecordReader<R> {
+    data: AsyncDeserializer<R>,
+    /// Buffer reused for all records
+    record: ByteRecord,
+    /// Headers of file (`None` - file without headers)
+    headers: Option<ByteRecord>,
+    /// Fast path may be used for file columns
+    fast: bool,
+}
+
+impl<R> RecordReader<R>
+    where R: AsyncRead + Unpin + Send
+{
+    /// Creates reader of records of `data`, reads headers (if data has them).
+    pub async fn new(mut data: AsyncDeserializer<R>) -> Result<Self, csv_async::Error> {
+        // Headers are read also from file without headers - otherwise its first record would not be trimmed
+        // by `read_byte_record` (first record is then yielded from headers).
+        let headers = data.byte_headers().await?.clone();
+        let headers = Some(headers).filter(|_| data.has_headers());
+        Ok(Self::build(data, headers))
+    }
+
+    /// Creates reader of records of `data` without headers (part of file), records are deserialized according
+    /// to `headers` of whole file.
+    pub async fn with_headers(mut data: AsyncDeserializer<R>, headers: Option<ByteRecord>) -> Result<Self, csv_async::Error> {
+        // see `new`
+        data.byte_headers().await?;
+        Ok(Self::build(data, headers))
+    }
+
+    fn build(data: AsyncDeserializer<R>, headers: Option<ByteRecord>) -> Self {
+        let fast = headers.as_ref().is_none_or(|headers| headers.iter().take(FAST_COLUMNS.len()).eq(FAST_COLUMNS));
+        Self {data, record: ByteRecord::new(), headers, fast}
+    }
+
+    /// Reads next record, returns `None` at the end of data.
+    pub async fn next(&mut self) -> Option<Result<Record, csv_async::Error>> {
+        match self.read().await? {
+            Ok(Some(transaction)) => Some(Ok(Record::Transaction(transaction))),
+            Ok(None) => Some(self.record.deserialize(self.headers.as_ref()).map(Record::Rec)),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Reads next record into `record` buffer, returns its transaction if fast path could be used for it
+    /// (`None` - record has to be deserialized with serde).
+    async fn read(&mut self) -> Option<Result<Option<Transaction>, csv_async::Error>> {
+        match self.data.read_byte_record(&mut self.record).await {
+            Ok(true) => {},
+            Ok(false) => return None,
+            Err(err) => return Some(Err(err)),
+        }
+        Some(Ok(Some(&self.record).filter(|_| self.fast).and_then(parse_fast)))
+    }
+}
+
+/// Size of blocks of file parsed by threads of `ParallelReader`.
+pub const BLOCK_SIZE: u64 = 1 << 20;
+
+/// Number of blocks per thread which may be parsed ahead of block being returned by `ParallelReader`.
+const BLOCKS_AHEAD: usize = 2;
+
+/// Records of one block of file, positions of raw records are relative to block.
+struct Block {
+    /// Position of block in file (in bytes)
+    start: u64,
+    records: Vec<Result<Parsed, csv_async::Error>>,
+    /// Position after the last record of block
+    end: Position,
+}
+
+/// Transaction built by fast path or raw record to be deserialized with serde.
+enum Parsed {
+    Fast(Transaction),
+    Raw(ByteRecord),
+}
+
+/// Reader of transaction records of file parsed by several threads.
+///
+/// Part of file after headers is split into blocks of about `block_size` bytes, which start and end at line
+/// boundaries. Threads take blocks in order of file and parse their records with fast path, reader returns
+/// records in order of file and deserializes records which fast path could not parse with serde (after their
+/// positions are made relative to file), so records and errors are the same as `RecordReader` would return.
+/// Records may not contain line breaks in quoted fields.
+///
+/// Threads get only blocks at most `BLOCKS_AHEAD` blocks per thread ahead of block to be returned, so that
+/// blocks waiting for their turn do not pile up in memory if one thread is slow. If threads stop before all
+/// blocks are parsed (a thread panicked), reader returns error instead of the missing records.
+pub struct ParallelReader {
+    rx: Receiver<(usize, Block)>,
+    /// Indices of blocks to be parsed by threads
+    tasks: Sender<usize>,
+    threads: Vec<JoinHandle<()>>,
+    headers: Option<ByteRecord>,
+    /// Blocks received before their turn
+    pending: HashMap<usize, Block>,
+    /// Number of blocks of file
+    blocks: usize,
+    /// Number of blocks which may be given to threads before block after current one is returned
+    window: usize,
+    /// Index of block to be given to threads next
+    next_task: usize,
+    /// Index of block to be returned after current one
+    next_block: usize,
+    /// Records of current block
+    current: std::vec::IntoIter<Result<Parsed, csv_async::Error>>,
+    /// Position of current block in file (bytes, lines and records before it)
+    offset: (u64, u64, u64),
+    /// Numbers of lines and records before block after current one
+    next_offset: (u64, u64),
+}
+
+impl ParallelReader {
+    /// Opens file at `path`, reads its headers (if reader made with `builder` expects them) and starts `threads`
+    /// threads parsing its blocks.
+    pub async fn open<B>(path: &Path, threads: usize, block_size: u64, builder: B) -> Result<Self>
+        where B: Fn() -> AsyncReaderBuilder + Send + Sync + 'static
+    {
+        let file = File::open(path).await
+            .with_context(|| format!("opening transactions file: {}", path.display()))?;
+        let size = file.metadata().await
+            .with_context(|| format!("reading transactions file: {}", path.display()))?.len();
+        let mut rdr = builder().create_deserializer(file);
+        let (headers, start, next_offset) = if rdr.has_headers() {
+            let headers = rdr.byte_headers().await
+                .with_context(|| format!("reading transactions file: {}", path.display()))?.clone();
+            let pos = rdr.position();
+            (Some(headers), pos.byte(), (pos.line() - 1, pos.record()))
+        } else {
+            (None, 0, (0, 0))
+        };
+
+        let threads = threads.max(1);
+        let block_size = block_size.max(1);
+        let blocks = usize::try_from(size.saturating_sub(start).div_ceil(block_size))
+            .with_context(|| format!("too many blocks of transactions file: {}", path.display()))?;
+        // the number of blocks in channel of results is limited by window of given tasks
+        let (tx, rx) = channel::unbounded();
+        let (tasks, tasks_rx) = channel::unbounded::<usize>();
+        let (path, builder) = (Arc::new(path.to_path_buf()), Arc::new(builder));
+        let threads = (0..threads).map(|_| {
+            let (tx, tasks_rx, path, builder, headers) = (tx.clone(), tasks_rx.clone(), path.clone(), builder.clone(), headers.clone());
+            thread::spawn(move || task::block_on(async move {
+                let _guard = CloseOnPanic(&tasks_rx);
+                while let Ok(idx) = tasks_rx.recv().await {
+                    let from = start.saturating_add((idx as u64).saturating_mul(block_size));
+                    let block = read_block(&path, idx == 0, from, from.saturating_add(block_size), &headers, &*builder).await;
+                    if tx.send((idx, block)).await.is_err() {
+                        break;  // reader dropped
+                    }
+                }
+            }))
+        }).collect::<Vec<_>>();
+        let mut reader = Self {
+            rx,
+            tasks,
+            window: threads.len() * BLOCKS_AHEAD,
+            threads,
+            headers,
+            pending: HashMap::new(),
+            blocks,
+            next_task: 0,
+            next_block: 0,
+            current: Vec::new().into_iter(),
+            offset: (start, next_offset.0, next_offset.1),
+            next_offset,
+        };
+        reader.dispatch();
+        Ok(reader)
+    }
+
+    /// Gives threads blocks up to window ahead of block to be returned, closes tasks after the last block.
+    fn dispatch(&mut self) {
+        while self.next_task < self.blocks.min(self.next_block + self.window) {
+            let _ = self.tasks.try_send(self.next_task);  // fails only if threads stopped
+            self.next_task += 1;
+        }
+        if self.next_task >= self.blocks {
+            self.tasks.close();
+        }
+    }
+
+    /// Returns error of threads which stopped before all blocks were parsed.
+    async fn failure(&mut self) -> csv_async::Error {
+        let threads = std::mem::take(&mut self.threads);
+        // joined by separate thread not to block processing loop
+        let (tx, rx) = channel::bounded(1);
+        thread::spawn(move || {
+            let panics = threads.into_iter()
+                .filter_map(|thread| thread.join().err())
+                .map(|panic| panic_message(&*panic))
+                .collect::<Vec<_>>();
+            let _ = tx.try_send(panics);
+        });
+        let panics = rx.recv().await.unwrap_or_default();
+        let cause = if panics.is_empty() {"threads stopped".to_string()} else {panics.join("; ")};
+        let msg = format!("parsing of transactions file stopped at block {} of {}: {}", self.next_block + 1, self.blocks, cause);
+        std::io::Error::other(msg).into()
+    }
+
+    /// Returns next record in order of file (waits until block of next record is parsed),
+    /// `None` at the end of file.
+    pub async fn next(&mut self) -> Option<Result<Record, csv_async::Error>> {
+        loop {
+            match self.current.next() {
+                Some(Ok(Parsed::Fast(transaction))) => return Some(Ok(Record::Transaction(transaction))),
+                Some(Ok(Parsed::Raw(mut record))) => {
+                    if let Some(pos) = record.position() {
+                        let mut abs = Position::new();
+                        let (bytes, lines, records) = self.offset;
+                        abs.set_byte(bytes + pos.byte()).set_line(lines + pos.line()).set_record(records + pos.record());
+                        record.set_position(Some(abs));
+                    }
+                    return Some(record.deserialize(self.headers.as_ref()).map(Record::Rec))
+                },
+                Some(Err(err)) => return Some(Err(err)),
+                None => {},
+            }
+            if self.next_block >= self.blocks {
+                return None;
+            }
+            let block = match self.pending.remove(&self.next_block) {
+                Some(block) => block,
+                None => loop {
+                    match self.rx.recv().await {
+                        Ok((idx, block)) if idx == self.next_block => break block,
+                        Ok((idx, block)) => { self.pending.insert(idx, block); },
+                        Err(_) => {
+                            let err = self.failure().await;
+                            self.blocks = self.next_block;  // the rest of file is not returned
+                            return Some(Err(err));
+                        },
+                    }
+                },
+            };
+            self.next_block += 1;
+            self.dispatch();
+            let (line, record) = self.next_offset;
+            self.offset = (block.start, line, record);
+            self.next_offset = (line + block.end.line() - 1, record + block.end.record());
+            self.current = block.records.into_iter();
+        }
+    }
+}
+
+/// Closes tasks of threads parsing blocks if one of them panics, so that the others stop too.
+struct CloseOnPanic<'a>(&'a Receiver<usize>);
+
+impl Drop for CloseOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.close();
+        }
+    }
+}
+
+/// Returns message of panic payload.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    panic.downcast_ref::<&str>().map(|msg| msg.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "thread panicked".to_string())
+}
+
+/// Parses records of block of file between lines starting at `from` and `to` positions (exactly at `from`
+/// for the first block). Failure of reading file is returned as record error.
+async fn read_block(
+    path: &Path,
+    first: bool,
+    from: u64,
+    to: u64,
+    headers: &Option<ByteRecord>,
+    builder: &dyn Fn() -> AsyncReaderBuilder,
+)   -> Block
+{
+    let mut block = Block {start: from, records: Vec::new(), end: Position::new()};
+    let result = async {
+        let mut file = File::open(path).await?;
+        block.start = if first {from} else {line_start(&mut file, from).await?};
+        let to = line_start(&mut file, to).await?;
+        file.seek(SeekFrom::Start(block.start)).await?;
+        let rdr = builder().has_headers(false).create_deserializer(file.take(to.saturating_sub(block.start)));
+        let mut records = RecordReader::with_headers(rdr, headers.clone()).await?;
+        while let Some(record) = records.read().await {
+            block.records.push(record.map(|transaction| transaction.map_or_else(|| Parsed::Raw(records.record.clone()), Parsed::Fast)));
+        }
+        block.end = records.data.position().clone();
+        Ok::<_, csv_async::Error>(())
+    }.await;
+    if let Err(err) = result {
+        block.records.push(Err(err));
+    }
+    block
+}
+
+/// Returns position of the first line which starts at `pos` or later.
+async fn line_start(file: &mut File, pos: u64) -> std::io::Result<u64> {
+    file.seek(SeekFrom::Start(pos - 1)).await?;
+    let mut line = Vec::new();
+    let read = BufReader::new(&*file).read_until(b'\n', &mut line).await?;
+    Ok(pos - 1 + read as u64)
+}
+
+/// Source of transaction records of processing loops.
+pub enum Records<R> {
+    /// Records parsed by processing loop.
+    Sequential(RecordReader<R>),
+    /// Records parsed by separate threads.
+    Parallel(ParallelReader),
+}
+
+impl<R> Records<R>
+    where R: AsyncRead + Unpin + Send
+{
+    /// Reads next record, returns `None` at the end of data.
+    pub async fn next(&mut self) -> Option<Result<Record, csv_async::Error>> {
+        match self {
+            Records::Sequential(records) => records.next().await,
+            Records::Parallel(records) => records.next().await,
+        }
+    }
+}
+
+/// Builds transaction of record of `type,client,tx[,amount]` form, returns `None` if record needs to be deserialized
+/// with serde (also if it would be rejected when converted to transaction, so that error is the same).
+pub fn parse_fast(record: &ByteRecord) -> Option<Transaction> {
+    let (ttype, client, tx, amount) = match (record.get(0), record.get(1), record.get(2), record.get(3), record.len()) {
+        (Some(ttype), Some(client), Some(tx), amount, 3 | 4) => (ttype, client, tx, amount.unwrap_or_default()),
+        _ => return None,
+    };
+    let (client, tx) = (parse_digits(client)?, parse_digits(tx)?);
+    // amount is parsed as `f64` and converted the same way as amount of deserialized record
+    let amount = match amount {
+        b"" => None,
+        amount => Some(TMoney::try_from(std::str::from_utf8(amount).ok()?.parse::<f64>().ok()?).ok()?),
+    };
+    Some(match ttype {
+        b"deposit" => Deposit::new(client, tx, amount?).into(),
+        b"withdrawal" => Withdrawal::new(client, tx, amount?).into(),
+        b"dispute" => Dispute::new(client, tx, amount).into(),
+        b"resolve" => Resolve::new(client, tx, amount).into(),
+        b"chargeback" => Chargeback::new(client, tx, amount).into(),
+        _ => return None,
+    })
+}
+
+/// Parses unsigned decimal integer, returns `None` if field contains anything but digits or value overflows.
+fn parse_digits<T: TryFrom<u64>>(field: &[u8]) -> Option<T> {
+    if field.is_empty() || field.len() > 19 {
+        return None
+    }
+    let mut value = 0u64;
+    for byte in field {
+        if !byte.is_ascii_digit() {
+            return None
+        }
+        value = value * 10 + u64::from(byte - b'0');
+    }
+    T::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use csv_async::AsyncReaderBuilder;
+    use rust_decimal_macros::dec;
+    use crate::{TClientId, TTrxID, TTimestamp, transactions::TransactionInt};
+    use super::*;
+
+    type Summary = (&'static str, TClientId, TTrxID, Option<TMoney>, Option<TTimestamp>);
+
+    /// Returns summary of transaction built from record (by fast path or from deserialized record).
+    fn summary(record: Result<Record, csv_async::Error>) -> Result<Summary, String> {
+        let transaction = match record.map_err(|err| err.to_string())? {
+            Record::Transaction(transaction) => transaction,
+            Record::Rec(rec) => Transaction::try_from(rec).map_err(|err| err.to_string())?,
+        };
+        let ttype = match transaction {
+            Transaction::Deposit(_) => "deposit",
+            Transaction::Withdrawal(_) => "withdrawal",
+            Transaction::Dispute(_) => "dispute",
+            Transaction::Resolve(_) => "resolve",
+            Transaction::Chargeback(_) => "chargeback",
+            _ => "other",
+        };
+        Ok((ttype, transaction.client_id(), transaction.id(), transaction.amount(), transaction.timestamp()))
+    }
+
+    async fn read(data: &str, has_headers: bool) -> Vec<Result<Summary, String>> {
+        let rdr = AsyncReaderBuilder::new()
+            .has_headers(has_headers)
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .create_deserializer(data.as_bytes());
+        let mut records = RecordReader::new(rdr).await.expect("headers read");
+        let mut result = Vec::new();
+        while let Some(record) = records.next().await {
+            result.push(summary(record));
+        }
+        result
+    }
+
+    async fn read_serde(data: &str, has_headers: bool) -> Vec<Result<Summary, String>> {
+        let mut rdr = AsyncReaderBuilder::new()
+            .has_headers(has_headers)
+            .trim(csv_async::Trim::All)
+            .flexible(true)
+            .create_deserializer(data.as_bytes());
+        let mut records = rdr.deserialize::<TransactionRec>();
+        let mut result = Vec::new();
+        while let Some(record) = futures::StreamExt::next(&mut records).await {
+            result.push(summary(record.map(Record::Rec)));
+        }
+        result
+    }
+
+    #[test]
+    fn fast_records() {
+        let fast = |fields: Vec<&str>| parse_fast(&ByteRecord::from(fields)).map(|transaction| summary(Ok(Record::Transaction(transaction))));
+        assert_eq!(fast(vec!["withdrawal", "65535", "4294967295", "1.2345"]), 
+            Some(Ok(("withdrawal", 65535, 4294967295, Some(dec!(1.2345)), None))));
+        assert_eq!(fast(vec!["dispute", "1", "2"]), Some(Ok(("dispute", 1, 2, None, None))));
+        assert!(fast(vec!["deposit", "1", "2"]).is_none()); // amount is missing
+        assert!(parse_fast(&ByteRecord::from(vec!["deposit", "65536", "1", "1.0"])).is_none());
+        assert!(parse_fast(&ByteRecord::from(vec!["deposit", "0x1", "1", "1.0"])).is_none());
+        assert!(parse_fast(&ByteRecord::from(vec!["Deposit", "1", "1", "1.0"])).is_none());
+        assert!(parse_fast(&ByteRecord::from(vec!["adjust", "1", "1", "1.0"])).is_none());
+        assert!(parse_fast(&ByteRecord::from(vec!["deposit", "1", "1", "1.0", "1700000000"])).is_none());
+    }
+
+    #[async_std::test]
+    async fn same_as_serde() {
+        let data = "type, client, tx, amount\n\
+            deposit, 1, 1, 1.0\n\
+            deposit,0x2,2,1e2\n\
+            withdrawal, 1, 3, -0.5\n\
+            dispute, 1, 1,\n\
+            resolve, 1, 1\n\
+            chargeback, 1, 70000,\n\
+            deposit, 1, 4, abc\n\
+            transfer, 1, 5, 1.0\n\
+            deposit, 1, 6, 2.5, 1700000000\n";
+        assert_eq!(read(data, true).await, read_serde(data, true).await);
+        let data = data.split_once('\n').map(|(_, records)| records).unwrap_or_default();
+        assert_eq!(read(data, false).await, read_serde(data, false).await);
+        let data = "client,type,tx,amount\n1,deposit,1,1.0\n";
+        assert_eq!(read(data, true).await, read_serde(data, true).await);
+    }
+
+    #[async_std::test]
+    async fn parallel_same_as_sequential() {
+        let data = "# comment\n\
+            type, client, tx, amount, timestamp\n\
+            deposit, 1, 1, 1.0\n\
+            # comment, 2\n\
+            deposit,0x2,2,1e2\n\
+            \n\
+            withdrawal, 1, 3, -0.5\n\
+            dispute, 1, 1,\n\
+            deposit, 1, 4, abc\n\
+            deposit, 1, 6, 2.5, 1700000000\n\
+            resolve, 1, 1";
+        let path = std::env::temp_dir().join(format!("toy_trx_engine_parallel_{}.csv", std::process::id()));
+        for (data, has_headers) in [(data, true), (data.split_once("timestamp\n").map(|(_, records)| records).unwrap_or_default(), false)] {
+            std::fs::write(&path, data).expect("test file written");
+            let builder = move || {
+                let mut builder = AsyncReaderBuilder::new();
+                builder.has_headers(has_headers).comment(Some(b'#')).trim(csv_async::Trim::All).flexible(true);
+                builder
+            };
+            let mut sequential = Vec::new();
+            let mut records = RecordReader::new(builder().create_deserializer(data.as_bytes())).await.expect("headers read");
+            while let Some(record) = records.next().await {
+                sequential.push(summary(record));
+            }
+            assert_eq!(sequential.len(), 7);
+            for (threads, block_size) in [(1, 1), (2, 7), (3, 16), (4, 40), (2, BLOCK_SIZE)] {
+                let mut records = ParallelReader::open(&path, threads, block_size, builder).await.expect("test file opened");
+                let mut parallel = Vec::new();
+                while let Some(record) = records.next().await {
+                    assert!(records.pending.len() <= threads * BLOCKS_AHEAD);
+                    parallel.push(summary(record));
+                }
+                assert_eq!(parallel, sequential, "threads: {}, block size: {}", threads, block_size);
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[async_std::test]
+    async fn parallel_thread_panics() {
+        let data = (1..=100).map(|tx| format!("deposit,1,{},1.0\n", tx)).collect::<String>();
+        let path = std::env::temp_dir().join(format!("toy_trx_engine_panic_{}.csv", std::process::id()));
+        std::fs::write(&path, data).expect("test file written");
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let builder = move || {
+            // the first call is made by `open`, the rest by threads for their blocks
+            if calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed) == 4 {
+                panic!("test panic");
+            }
+            let mut builder = AsyncReaderBuilder::new();
+            builder.has_headers(false);
+            builder
+        };
+        let mut records = ParallelReader::open(&path, 2, 64, builder).await.expect("test file opened");
+        let mut parsed = 0;
+        let err = loop {
+            match records.next().await {
+                Some(Ok(_)) => parsed += 1,
+                Some(Err(err)) => break err,
+                None => panic!("records truncated without error"),
+            }
+        };
+        assert!(parsed < 100);
+        assert!(err.to_string().contains("test panic"), "{}", err);
+        assert!(records.next().await.is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,136 +1,810 @@
 use std::collections::HashMap;
-use std::sync::mpsc;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Result, bail};
-use async_std::stream::StreamExt;
 //use async_std::{stream::StreamExt, channel::Receiver};
 use std::collections::hash_map::Entry;
 
-use csv_async::AsyncDeserializer;
 use futures::io::AsyncRead;
 
 use crate::{
-    TClientId,
-    accounts::AccountState,
-    transactions::{TransactionValid, TransactionRec, Transaction, TransactionInt, TheEnd},
+    TClientId, TMoney, TTrxID, TTimestamp, Stats, TsOrder, Assignment,
+    accounts::{AccountState, AdminEntry, AdminKind, HistoryEntry, HistoryKind, TrxEntry},
+    currency::Currency,
+    expiry::{DisputeTracker, DisputeWindow, ExpiryAction},
+    fees::FeeSchedule,
+    ledger::{Journal, LedgerAccount},
+    parser::{Record, Records},
+    retention::Retention,
+    transactions::{Policy, TransactionValid, TransactionRec, TransactionRecType, Transaction, TransactionInt, TheEnd},
 };
 
+/// Settings of transactions processing, shared by all workers.
+#[derive(Clone, Default)]
+pub struct Settings {
+    /// Window after which open disputes are closed automatically.
+    pub dispute_window: Option<DisputeWindow>,
+    /// Handling of records which timestamp goes backwards for client.
+    pub ts_order: TsOrder,
+    /// Input comes from privileged source - administrative transactions (unlock, adjust) are accepted.
+    pub privileged: bool,
+    /// Rules applied by transactions on commit.
+    pub policy: Policy,
+    /// Fees charged on withdrawals and chargebacks.
+    pub fees: Option<FeeSchedule>,
+    /// Currency of records treated as default currency of accounts.
+    pub base_currency: Option<Currency>,
+    /// Changes of balances are recorded as ledger postings.
+    pub journal: bool,
+    /// Operations are recorded in history of accounts.
+    pub history: bool,
+    /// Old transactions are evicted from accounts.
+    pub retention: Option<Retention>,
+    /// Per-record diagnostics are not printed (reference run of `--verify` would repeat them).
+    pub quiet: bool,
+}
+
+/// Prints per-record diagnostic to stderr unless `settings` are quiet.
+macro_rules! diagnostic {
+    ($settings:expr, $($arg:tt)*) => {
+        if !$settings.quiet {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Batching of transactions sent by processing loop to workers.
+#[derive(Clone, Copy, Debug)]
+pub struct Batching {
+    /// Maximal number of transactions sent to worker at once.
+    pub size: usize,
+    /// Maximal time transaction waits in batch which is not full (also while processing loop waits for next record).
+    pub latency: Duration,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {size: 1, latency: Duration::ZERO}
+    }
+}
+
 /// Main transaction processing loop.
-/// Reads transactions from passed `data` and updates `accounts`.
+/// Reads transactions from passed `records` and applies them on top of initial `accounts` state.
+/// Every client is assigned to one worker (according to `assignment` strategy), transactions are sent to workers
+/// in batches, transactions of every client are applied in order of records.
 pub async fn processing_loop<'r, R>(
-    mut data: AsyncDeserializer<R>, 
+    mut records: Records<R>, 
+    mut accounts: HashMap::<TClientId,AccountState>,
+    settings: &Settings,
     num_workers: usize,
     wrk_buffer_size: usize,
-)   -> Result<(u128, HashMap::<TClientId,AccountState>)>
+    batching: Batching,
+    assignment: Assignment,
+)   -> Result<(Stats, HashMap::<TClientId,AccountState>)>
     where R: AsyncRead + Unpin + Send + 'r 
 {
     assert!(num_workers > 0);
+    let batch_size = batching.size.max(1);
+    let queue_size = (wrk_buffer_size * 1000 / batch_size).max(1);
     
-    let mut accounts = HashMap::new();      // this will store accumulated accounts data
-    let mut workers = Vec::new();           // this will store pairs (sender channel of worker to send transactions, worker handle)
-    let mut cli_to_worker = HashMap::new(); // maps clientID to index in workers vector
+    let mut journal = Journal::new(settings.journal);
+    journal.open(&accounts);
+    let mut workers: Vec<Worker> = Vec::new();
+    let mut cli_to_worker = HashMap::new(); // maps clientID to index in workers vector (not used by hash assignment)
+    let mut wrk_idx = 0usize;
+    let mut batches: Vec<Batch> = Vec::new(); // transactions waiting to be sent to workers (index as in workers vector)
+    let mut oldest: Option<Instant> = None;   // time when the oldest waiting transaction was read
+
+    // Initial accounts are distributed between workers the same way as new clients are in the main loop below
+    // (round-robin also with load assignment - there is no load yet).
+    let mut shards = Vec::new();
+    for (client, acct) in accounts.drain() {
+        let idx = if assignment == Assignment::Hash {
+            hash_worker(client, num_workers)
+        } else if shards.len() < num_workers {
+            shards.len()
+        } else {
+            let idx = wrk_idx;
+            wrk_idx = (wrk_idx + 1) % num_workers;
+            idx
+        };
+        while shards.len() <= idx {
+            shards.push(HashMap::new());
+        }
+        shards[idx].insert(client, acct);
+        if assignment != Assignment::Hash {
+            cli_to_worker.insert(client, idx);
+        }
+    }
+    for shard in shards {
+        workers.push(spawn_worker(shard, settings, queue_size));
+        batches.push(Vec::with_capacity(batch_size));
+    }
 
-    let mut records = data.deserialize::<TransactionRec>();
     let mut rec_no = 0u128;
-    let mut wrk_idx = 0usize;
-    while let Some(record) = records.next().await {
+    let mut clock = None;   // time of input - the latest timestamp of records read so far
+    loop {
+        let record = wait_flushing(records.next(), &mut oldest, batching.latency, 
+            || send_batches(&workers, &mut batches, batch_size)).await;
+        let Some(record) = record else {
+            break
+        };
         rec_no = rec_no.wrapping_add(1);
         if rec_no == 0 {rec_no = 1;} // rec_no 0 means end-of stream by convention.
-        if let Err(err) = record {
-            if rec_no > 1 {
-                eprintln!("Record# {} - parsing failed: {}", rec_no, err);
-                continue;
-            } else {
-                bail!(err);
-            }
+        let transaction = match decode(rec_no, record, settings)? {
+            Some(transaction) => transaction,
+            None => continue,
+        };
+        clock = clock.max(transaction.timestamp());
+        
+        let idx = match (assignment, cli_to_worker.entry(transaction.client_id())) {
+            (Assignment::Hash, _) => hash_worker(transaction.client_id(), num_workers),
+            (_, Entry::Occupied(ent)) => *ent.get(),
+            (_, Entry::Vacant(ent)) if workers.len() < num_workers => *ent.insert(workers.len()),
+            (Assignment::RoundRobin, Entry::Vacant(ent)) => {
+                let idx = wrk_idx;
+                wrk_idx = (wrk_idx + 1) % num_workers;
+                *ent.insert(idx)
+            },
+            (Assignment::Load, Entry::Vacant(ent)) => *ent.insert(least_loaded(&workers, &batches)),
+        };
+        while workers.len() <= idx {
+            workers.push(spawn_worker(HashMap::new(), settings, queue_size));
+            batches.push(Vec::with_capacity(batch_size));
         }
-            
-        let transaction_rec:TransactionRec = record?;
-        let transaction = transaction_rec.try_into();
-        if let Err(err) = transaction {
-            eprintln!("Record# {} - invalid (will be skipped): {}", rec_no, err);
-            continue;
+        batches[idx].push((rec_no, clock, transaction));
+        if batches[idx].len() >= batch_size {
+            send_batch(&workers[idx], &mut batches[idx], batch_size);
+        } else if oldest.is_none() {
+            oldest = Some(Instant::now());
         }
+        if oldest.is_some_and(|oldest| oldest.elapsed() >= batching.latency) {
+            send_batches(&workers, &mut batches, batch_size);
+            oldest = None;
+        }
+    }
 
-        let transaction:Transaction = transaction?;
-        match transaction.validate() {
-            TransactionValid::Ok => {},
-            TransactionValid::Warn(msg) => {
-                eprintln!("Record# {}, Transaction ID = {} - validation warning: {}", rec_no, transaction.id(), msg);
+    send_batches(&workers, &mut batches, batch_size);
+    workers.retain(|worker| worker.tx.send(vec![(0, clock, Transaction::TheEnd(TheEnd{}))]).is_ok());
+
+    let mut stats = Stats {journal, assignment: Some(assignment), ..Default::default()};
+    let mut disputes = DisputeTracker::default();
+    for worker in workers {
+        match worker.handle.join() {
+            Ok(shard) => {
+                accounts.extend(shard.accounts);
+                disputes.merge(shard.disputes);
+                stats.add(&shard.stats, settings.quiet);
+                stats.worker_processed.push(shard.stats.processed);
             },
-            TransactionValid::Invalid(msg) => {
-                eprintln!("Record# {}, Transaction ID = {} - invalid (will be skipped): {}", rec_no, transaction.id(), msg);
-                continue;
+            Err(_) => {
+                eprintln!("Worker crashed");
             }
         }
-        
-        match cli_to_worker.entry(transaction.client_id()) {
-            Entry::Vacant(ent) => {
-                let wrk_len = workers.len();
-                if wrk_len < num_workers {
-                    let (tx, rx) = mpsc::sync_channel(wrk_buffer_size * 1000);
-                    let jh = thread::spawn(|| process_transactions(rx));
-                    workers.push((tx, jh));
-                    ent.insert(wrk_len);
-                    if let Err(e) = workers[wrk_len].0.send((rec_no, transaction)) {
-                        eprintln!("Record# {}, Transaction ID = {} - skipped - internal error in send(): {}", rec_no, e.0.1.id(), e);
-                    }
-                } else {
-                    ent.insert(wrk_idx);
-                    if let Err(e) = workers[wrk_idx].0.send((rec_no, transaction)) {
-                        eprintln!("Record# {}, Transaction ID = {} - skipped - internal error in send(): {}", rec_no, e.0.1.id(), e);
-                    }
-                    wrk_idx = (wrk_idx + 1) % num_workers;
-                }
+    }
+    stats.journal.sort();
+    // disputes of all workers are closed together, in the same order as by `simple_loop`
+    let mut shard = Shard {disputes, ..Shard::new(accounts, settings.clone())};
+    shard.sweep(end_rec_no(rec_no), clock);
+    stats.add(&shard.stats, settings.quiet);
+    let mut accounts = shard.accounts;
+    post_fees(&mut stats, &mut accounts, settings);
+
+    Ok((stats, accounts))
+}
+
+/// Transactions (with their record numbers and time of input when they were read) sent to worker at once.
+type Batch = Vec<(u128, Option<TTimestamp>, Transaction)>;
+
+struct Worker {
+    /// Channel to send transactions to worker
+    tx: mpsc::SyncSender<Batch>,
+    handle: thread::JoinHandle<Shard>,
+    /// Number of transactions sent to worker and not applied yet
+    queued: Arc<AtomicUsize>,
+}
+
+/// Starts worker thread that will apply transactions on passed (initial) `accounts`.
+fn spawn_worker(accounts: HashMap::<TClientId,AccountState>, settings: &Settings, queue_size: usize) -> Worker {
+    let (tx, rx) = mpsc::sync_channel(queue_size);
+    let settings = settings.clone();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let wrk_queued = queued.clone();
+    let handle = thread::spawn(|| process_transactions(rx, accounts, settings, wrk_queued));
+    Worker {tx, handle, queued}
+}
+
+/// Returns worker assigned to client by hash assignment.
+fn hash_worker(client: TClientId, num_workers: usize) -> usize {
+    // Fibonacci hashing - consecutive client IDs are spread between workers
+    ((client as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) as usize % num_workers
+}
+
+/// Returns worker with the least transactions queued (sent and waiting in batch).
+fn least_loaded(workers: &[Worker], batches: &[Batch]) -> usize {
+    workers.iter().zip(batches)
+        .map(|(worker, batch)| worker.queued.load(Ordering::Relaxed) + batch.len())
+        .enumerate()
+        .min_by_key(|(_, queued)| *queued)
+        .map_or(0, |(idx, _)| idx)
+}
+
+/// Sends waiting transactions to worker (if there are any).
+fn send_batch(worker: &Worker, batch: &mut Batch, batch_size: usize) {
+    if batch.is_empty() {
+        return;
+    }
+    let transactions = std::mem::replace(batch, Vec::with_capacity(batch_size));
+    worker.queued.fetch_add(transactions.len(), Ordering::Relaxed);
+    if let Err(e) = worker.tx.send(transactions) {
+        for (rec_no, _, transaction) in &e.0 {
+            eprintln!("Record# {}, Transaction ID = {} - skipped - internal error in send(): {}", rec_no, transaction.id(), e);
+        }
+    }
+}
+
+/// Waits for `next` record, calls `flush` (and clears `oldest`) if the `oldest` waiting transaction waits longer than
+/// `latency` in the meantime. Reading of record is not interrupted by timeout.
+async fn wait_flushing<F: Future>(next: F, oldest: &mut Option<Instant>, latency: Duration, mut flush: impl FnMut()) -> F::Output {
+    let mut next = std::pin::pin!(next);
+    loop {
+        let Some(since) = *oldest else {
+            return next.await
+        };
+        match async_std::future::timeout(latency.saturating_sub(since.elapsed()), &mut next).await {
+            Ok(output) => return output,
+            Err(_) => {
+                flush();
+                *oldest = None;
             },
-            Entry::Occupied(ent) => {
-                if let Err(e) = workers[*ent.get()].0.send((rec_no, transaction)) {
-                    eprintln!("Record# {}, Transaction ID = {} - skipped - internal error in send(): {}", rec_no, e.0.1.id(), e);
+        }
+    }
+}
+
+/// Sends waiting transactions to all workers.
+fn send_batches(workers: &[Worker], batches: &mut [Batch], batch_size: usize) {
+    for (worker, batch) in workers.iter().zip(batches.iter_mut()) {
+        send_batch(worker, batch, batch_size);
+    }
+}
+
+fn process_transactions(
+    rx: mpsc::Receiver<Batch>, 
+    accounts: HashMap::<TClientId,AccountState>, 
+    settings: Settings,
+    queued: Arc<AtomicUsize>,
+)   -> Shard
+{
+    let mut shard = Shard::new(accounts, settings);
+    'batches: loop {
+        if let Ok(batch) = rx.recv() {
+            let len = batch.len();
+            for (rec_no, clock, transaction) in batch {
+                if rec_no == 0 {
+                    assert!(matches!(transaction, Transaction::TheEnd(_))); 
+                    break 'batches;
                 }
+                shard.apply(rec_no, transaction, clock);
             }
+            queued.fetch_sub(len, Ordering::Relaxed);
+        } else {
+            eprintln!("Internal error in process_transactions() - channel broken");
+            break;
         }
     }
+    shard
+}
 
-    for i in 0..workers.len() {
-        if let Err(_) = workers[i].0.send((0, Transaction::TheEnd(TheEnd{}))) {
-            workers.remove(i);
+/// Reference single-threaded transaction processing loop.
+/// Does the same as `processing_loop` but applies all transactions in calling task, 
+/// so it may be used to cross-check results of multi-threaded processing.
+pub async fn simple_loop<'r, R>(
+    mut records: Records<R>, 
+    accounts: HashMap::<TClientId,AccountState>,
+    settings: &Settings,
+)   -> Result<(Stats, HashMap::<TClientId,AccountState>)>
+    where R: AsyncRead + Unpin + Send + 'r 
+{
+    let mut shard = Shard::new(accounts, settings.clone());
+    shard.stats.journal.open(&shard.accounts);
+    let mut rec_no = 0u128;
+    let mut clock = None;
+    while let Some(record) = records.next().await {
+        rec_no = rec_no.wrapping_add(1);
+        if rec_no == 0 {rec_no = 1;}
+        if let Some(transaction) = decode(rec_no, record, settings)? {
+            clock = clock.max(transaction.timestamp());
+            shard.apply(rec_no, transaction, clock);
         }
     }
+    shard.sweep(end_rec_no(rec_no), clock);
+    post_fees(&mut shard.stats, &mut shard.accounts, settings);
+    Ok((shard.stats, shard.accounts))
+}
 
-    let mut processed_sucessfully = 0u128;
-    for (_, jh) in workers {
-        match jh.join() {
-            Ok((ps, acct)) => {
-                accounts.extend(acct);
-                processed_sucessfully = processed_sucessfully.wrapping_add(ps);
-            },
-            Err(_) => {
-                eprintln!("Worker crashed");
+/// Returns record number of operations performed after the last record `rec_no`.
+fn end_rec_no(rec_no: u128) -> u128 {
+    rec_no.wrapping_add(1).max(1)
+}
+
+/// Credits total of fees charged by all workers to house account (if fee schedule is given).
+/// Fees are not credited (they stay in fee income of journal) if house account is locked,
+/// is a client of input or its balance would overflow.
+fn post_fees(stats: &mut Stats, accounts: &mut HashMap::<TClientId,AccountState>, settings: &Settings) {
+    if let Some(fees) = &settings.fees {
+        if !stats.fees.is_zero() {
+            let acct = accounts.entry(fees.account).or_default();
+            if acct.locked {
+                diagnostic!(settings, "Client {} - fees {} not collected: house account is locked", fees.account, stats.fees);
+                return;
+            }
+            if !acct.transactions.is_empty() || !acct.evicted.is_empty() {
+                diagnostic!(settings, "Client {} - fees {} not collected: house account is a client of input", fees.account, stats.fees);
+                return;
+            }
+            let Some(available) = acct.available.checked_add(stats.fees) else {
+                diagnostic!(settings, "Client {} - fees {} not collected: amount overflow", fees.account, stats.fees);
+                return;
+            };
+            if settings.history {
+                acct.start_history();
             }
+            acct.available = available;
+            acct.record(HistoryEntry {
+                reason: Some("collected fees".to_string()),
+                ..HistoryEntry::new(0, 0, HistoryKind::Fee, None, Some(stats.fees), acct.balance(None))
+            });
+            stats.journal.transfer(0, 0, None, LedgerAccount::FeeIncome, LedgerAccount::Available(fees.account), stats.fees);
+        }
+    }
+}
+
+/// Converts parsed record to transaction (unless fast path built it already) and validates it.
+/// Returns `None` if record should be skipped, fails only if first record could not be parsed 
+/// (most likely input is not a transactions file at all).
+/// Administrative records are skipped unless input is privileged.
+fn decode(rec_no: u128, record: Result<Record, csv_async::Error>, settings: &Settings) -> Result<Option<Transaction>> {
+    let mut transaction_rec = match record {
+        Ok(Record::Transaction(transaction)) => return Ok(validated(rec_no, transaction, settings)),
+        Ok(Record::Rec(rec)) => rec,
+        Err(err) if rec_no > 1 => {
+            diagnostic!(settings, "Record# {} - parsing failed: {}", rec_no, err);
+            return Ok(None);
+        },
+        Err(err) => bail!(err),
+    };
+    if transaction_rec.currency.is_some() && transaction_rec.currency == settings.base_currency {
+        transaction_rec.currency = None;
+    }
+    if transaction_rec.to_currency.is_some() && transaction_rec.to_currency == settings.base_currency {
+        transaction_rec.to_currency = None;
+    }
+    if transaction_rec.ttype.is_privileged() && !settings.privileged {
+        diagnostic!(settings, "Record# {}, Transaction ID = {} - administrative transaction in not privileged input (will be skipped)", 
+            rec_no, transaction_rec.tx);
+        return Ok(None);
+    }
+
+    match transaction_rec.try_into() {
+        Ok(transaction) => Ok(validated(rec_no, transaction, settings)),
+        Err(err) => {
+            diagnostic!(settings, "Record# {} - invalid (will be skipped): {}", rec_no, err);
+            Ok(None)
+        }
+    }
+}
+
+/// Validates transaction, returns `None` if it should be skipped.
+fn validated(rec_no: u128, transaction: Transaction, settings: &Settings) -> Option<Transaction> {
+    match transaction.validate() {
+        TransactionValid::Ok => {},
+        TransactionValid::Warn(msg) => {
+            diagnostic!(settings, "Record# {}, Transaction ID = {} - validation warning: {}", rec_no, transaction.id(), msg);
+        },
+        TransactionValid::Invalid(msg) => {
+            diagnostic!(settings, "Record# {}, Transaction ID = {} - invalid (will be skipped): {}", rec_no, transaction.id(), msg);
+            return None;
         }
     }
+    Some(transaction)
+}
 
-    Ok((processed_sucessfully, accounts))
+/// Set of accounts processed by one worker together with processing counters.
+struct Shard {
+    accounts: HashMap::<TClientId,AccountState>,
+    settings: Settings,
+    stats: Stats,
+    disputes: DisputeTracker,
+    /// Timestamp of last transaction of client.
+    last_ts: HashMap::<TClientId,TTimestamp>,
 }
 
-fn process_transactions(rx: mpsc::Receiver<(u128, Transaction)>) -> (u128, HashMap::<TClientId,AccountState>) {
-    let mut processed_sucessfully = 0u128;
-    let mut accounts = HashMap::new();
-    loop {
-        if let Ok((rec_no, transaction)) = rx.recv() {
-            if rec_no == 0 {
-                assert!(matches!(transaction, Transaction::TheEnd(_))); 
-                break;
+impl Shard {
+    fn new(mut accounts: HashMap::<TClientId,AccountState>, settings: Settings) -> Self {
+        if settings.history {
+            accounts.values_mut().for_each(AccountState::start_history);
+        }
+        let stats = Stats {journal: Journal::new(settings.journal), ..Default::default()};
+        Self {accounts, settings, stats, disputes: DisputeTracker::default(), last_ts: HashMap::new()}
+    }
+
+    /// Commits transaction, logs failure.
+    /// Before commit timestamp order is checked and disputes of the same client which window expired by time of input
+    /// `clock` are closed.
+    fn apply(&mut self, rec_no: u128, transaction: Transaction, clock: Option<TTimestamp>) {
+        let client = transaction.client_id();
+        let timestamp = transaction.timestamp();
+        if let Some(ts) = timestamp {
+            if self.settings.ts_order != TsOrder::Ignore {
+                match self.last_ts.get(&client) {
+                    Some(&last) if ts < last => {
+                        self.stats.out_of_order += 1;
+                        if self.settings.ts_order == TsOrder::Reject {
+                            diagnostic!(self.settings, "Record# {}, Transaction ID = {} - timestamp goes backwards (will be skipped)", rec_no, transaction.id());
+                            return;
+                        }
+                        diagnostic!(self.settings, "Record# {}, Transaction ID = {} - timestamp goes backwards", rec_no, transaction.id());
+                    },
+                    _ => { self.last_ts.insert(client, ts); },
+                }
             }
-            if let Err(e) = transaction.commit(&mut accounts) {
-                eprintln!("Record# {}, Transaction ID = {} - failed: {}", rec_no, transaction.id(), e);
-                continue;
+        }
+        if let Some(window) = self.settings.dispute_window {
+            for tx in self.disputes.next(client, clock, &window) {
+                self.expire(rec_no, client, tx, clock, window.action);
             }
+        }
+        let before = self.trx_entry(client, transaction.id());
+        let result = if self.settings.journal {
+            transaction.commit_journaled(&mut self.accounts, &self.settings.policy, rec_no, &mut self.stats.journal)
         } else {
-            eprint!("Internal error in process_transactions() - channel broken");
+            transaction.commit_with(&mut self.accounts, &self.settings.policy)
+        };
+        if let Err(e) = result {
+            diagnostic!(self.settings, "Record# {}, Transaction ID = {} - failed: {}", rec_no, transaction.id(), e);
+            return;
+        }
+        if let (Transaction::Dispute(dispute), Some(trx)) = (&transaction, &before) {
+            if dispute.repeats(trx) {
+                diagnostic!(self.settings, "Record# {}, Transaction ID = {} - warning - repeated Dispute", rec_no, transaction.id());
+            }
+        }
+        if self.settings.history {
+            self.record_history(rec_no, &transaction, before.as_ref(), None);
+        }
+        if self.settings.fees.is_some() {
+            self.charge_fee(rec_no, &transaction, before.map(|trx| trx.charged_back).unwrap_or_default());
+        }
+        if let Some(window) = &self.settings.dispute_window {
+            match transaction {
+                Transaction::Dispute(_) => self.disputes.opened(client, transaction.id(), timestamp, window),
+                Transaction::Resolve(_) | Transaction::Chargeback(_) => {
+                    // partial resolve or chargeback leaves rest of transaction under dispute
+                    let disputed = self.accounts.get(&client)
+                        .and_then(|acct| acct.transactions.get(transaction.id()))
+                        .is_some_and(|trx| trx.is_disputed());
+                    if !disputed {
+                        self.disputes.closed(client, transaction.id());
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Some(retention) = &self.settings.retention {
+            if let Some(acct) = self.accounts.get_mut(&client) {
+                match retention.apply(client, acct, timestamp) {
+                    Ok(evicted) => self.stats.evicted += evicted as u128,
+                    Err(e) => diagnostic!(self.settings, "Record# {}, Transaction ID = {} - retention failed: {:#}", rec_no, transaction.id(), e),
+                }
+            }
+        }
+        self.stats.processed = self.stats.processed.wrapping_add(1);
+    }
+
+    /// Returns (copy of) transaction stored in account of client.
+    fn trx_entry(&self, client: TClientId, tx: TTrxID) -> Option<TrxEntry> {
+        self.accounts.get(&client).and_then(|acct| acct.transactions.get(tx))
+    }
+
+    /// Records committed transaction in history of client's account (`before` - state of stored or referenced 
+    /// transaction before commit). Exchange and operations on exchange are recorded also in currency of its linked leg.
+    fn record_history(&mut self, rec_no: u128, transaction: &Transaction, before: Option<&TrxEntry>, reason: Option<&str>) {
+        let kind = match transaction {
+            Transaction::Deposit(_) => HistoryKind::Deposit,
+            Transaction::Withdrawal(_) => HistoryKind::Withdrawal,
+            Transaction::Dispute(_) => HistoryKind::Dispute,
+            Transaction::Resolve(_) => HistoryKind::Resolve,
+            Transaction::Chargeback(_) => HistoryKind::Chargeback,
+            Transaction::Unlock(_) => HistoryKind::Unlock,
+            Transaction::Adjust(_) => HistoryKind::Adjust,
+            Transaction::Exchange(_) => HistoryKind::Exchange,
+            Transaction::TheEnd(_) => return,
+        };
+        let (client, tx) = (transaction.client_id(), transaction.id());
+        let Some(acct) = self.accounts.get_mut(&client) else {return};
+        if acct.history.is_none() {
+            // account created by transaction (accounts existing before were started in `new`)
+            acct.history = Some(Vec::new());
+        }
+        let mut entry = HistoryEntry {
+            timestamp: transaction.timestamp(),
+            reason: reason.map(str::to_string),
+            ..HistoryEntry::new(rec_no, tx, kind, None, transaction.amount(), Default::default())
+        };
+        let mut linked = None;
+        match kind {
+            HistoryKind::Unlock | HistoryKind::Adjust => if let Some(admin) = acct.admin.last() {
+                entry.currency = admin.currency;
+                entry.reason.clone_from(&admin.reason);
+            },
+            _ => if let Some(trx) = acct.transactions.get(tx) {
+                entry.currency = trx.currency;
+                linked = trx.linked;
+                if matches!(kind, HistoryKind::Dispute | HistoryKind::Resolve | HistoryKind::Chargeback) {
+                    let before = before.unwrap_or(&trx);
+                    let part = match kind {
+                        HistoryKind::Dispute => trx.disputed - before.disputed,
+                        HistoryKind::Resolve => before.disputed - trx.disputed,
+                        _ => trx.charged_back - before.charged_back,
+                    };
+                    entry.amount = Some(part.abs());
+                    entry.disputed = Some(trx.disputed.abs());
+                    entry.charged_back = Some(trx.charged_back.abs());
+                }
+            },
+        }
+        let balance = acct.balance(entry.currency);
+        (entry.available, entry.held) = (balance.available, balance.held);
+        let leg = linked.filter(|_| entry.amount.is_some_and(|amount| !amount.is_zero())).map(|leg| {
+            let balance = acct.balance(leg.currency);
+            HistoryEntry {currency: leg.currency, amount: Some(leg.amount), available: balance.available, held: balance.held, ..entry.clone()}
+        });
+        acct.record(entry);
+        if let Some(leg) = leg {
+            acct.record(leg);
+        }
+    }
+
+    /// Charges fee of committed withdrawal or chargeback according to fee schedule 
+    /// (`charged_back` is part of transaction charged back before chargeback was committed).
+    /// Fee is limited to available funds of client, it is recorded in account administrative history.
+    /// Fee schedule amounts are in default currency, so transactions in other currencies are not charged.
+    fn charge_fee(&mut self, rec_no: u128, transaction: &Transaction, charged_back: TMoney) {
+        let Some(fees) = &self.settings.fees else {return};
+        let (client, tx) = (transaction.client_id(), transaction.id());
+        let Some(trx) = self.trx_entry(client, tx).filter(|trx| trx.currency.is_none()) else {return};
+        let (rule, amount, reason) = match transaction {
+            Transaction::Withdrawal(_) => (&fees.withdrawal, trx.amount, "withdrawal fee"),
+            Transaction::Chargeback(_) => (&fees.chargeback, trx.charged_back - charged_back, "chargeback fee"),
+            _ => return,
+        };
+        let Some(rule) = rule else {return};
+        let Some(fee) = rule.fee(amount.abs()) else {
+            diagnostic!(self.settings, "Record# {}, Transaction ID = {} - fee rejected: amount overflow", rec_no, tx);
+            return;
+        };
+        let Some(acct) = self.accounts.get_mut(&client) else {return};
+        let charged = fee.min(acct.available.max(TMoney::ZERO));
+        if charged < fee {
+            diagnostic!(self.settings, "Record# {}, Transaction ID = {} - fee {} limited to available funds {}", rec_no, tx, fee, charged);
+        }
+        if charged.is_zero() {
+            return;
+        }
+        let Some(total) = self.stats.fees.checked_add(charged) else {
+            diagnostic!(self.settings, "Record# {}, Transaction ID = {} - fee rejected: total of fees overflow", rec_no, tx);
+            return;
+        };
+        self.stats.fees = total;
+        acct.available -= charged;
+        self.stats.journal.transfer(rec_no, tx, None, LedgerAccount::Available(client), LedgerAccount::FeeIncome, charged);
+        acct.admin.push(AdminEntry {
+            tx,
+            kind: AdminKind::Fee,
+            amount: -charged,
+            reason: Some(reason.to_string()),
+            timestamp: transaction.timestamp(),
+            currency: None,
+        });
+        acct.record(HistoryEntry {
+            timestamp: transaction.timestamp(),
+            reason: Some(reason.to_string()),
+            ..HistoryEntry::new(rec_no, tx, HistoryKind::Fee, None, Some(charged), acct.balance(None))
+        });
+    }
+
+    /// Closes disputes which expired by the end of input (`clock` - time of input at the end),
+    /// generated transactions get record number `rec_no` after the last record.
+    fn sweep(&mut self, rec_no: u128, clock: Option<TTimestamp>) {
+        if let Some(window) = self.settings.dispute_window {
+            for (client, tx) in self.disputes.expired(clock, &window) {
+                self.expire(rec_no, client, tx, clock, window.action);
+            }
+        }
+    }
+
+    /// Closes expired dispute with automatically generated resolve or chargeback.
+    /// Generated transaction gets time of input `timestamp` when dispute is closed.
+    fn expire(&mut self, rec_no: u128, client: TClientId, tx: TTrxID, timestamp: Option<TTimestamp>, action: ExpiryAction) {
+        let ttype = match action {
+            ExpiryAction::Resolve => TransactionRecType::Resolve,
+            ExpiryAction::Chargeback => TransactionRecType::Chargeback,
+        };
+        let before = self.trx_entry(client, tx);
+        let result = Transaction::try_from(TransactionRec {ttype, client, tx, amount: None, timestamp, reason: None, currency: None, to_currency: None})
+            .and_then(|transaction| transaction.commit_journaled(&mut self.accounts, &Policy::default(), rec_no, &mut self.stats.journal)
+                .map(|_| transaction));
+        if let (Ok(transaction), true) = (&result, self.settings.history) {
+            self.record_history(rec_no, transaction, before.as_ref(), Some("dispute expired"));
+        }
+        match (result, action) {
+            (Ok(_), ExpiryAction::Resolve) => {
+                diagnostic!(self.settings, "Record# {}, Transaction ID = {} - dispute expired: auto-resolved", rec_no, tx);
+                self.stats.auto_resolved += 1;
+            },
+            (Ok(transaction), ExpiryAction::Chargeback) => {
+                diagnostic!(self.settings, "Record# {}, Transaction ID = {} - dispute expired: auto-charged back", rec_no, tx);
+                self.stats.auto_charged_back += 1;
+                if self.settings.fees.is_some() {
+                    self.charge_fee(rec_no, &transaction, before.map(|trx| trx.charged_back).unwrap_or_default());
+                }
+            },
+            (Err(e), _) => diagnostic!(self.settings, "Record# {}, Transaction ID = {} - dispute expired, closing failed: {}", rec_no, tx, e),
+        }
+    }
+}
+
+/// Compares results of two processing runs, fails with description of differences if they are not equal.
+pub fn compare_results(
+    (stats, accounts): &(Stats, HashMap::<TClientId,AccountState>),
+    (ref_stats, ref_accounts): &(Stats, HashMap::<TClientId,AccountState>),
+)   -> Result<()>
+{
+    let mut differences = Vec::new();
+    if stats.processed != ref_stats.processed {
+        differences.push(format!("successfully processed {} vs {}", stats.processed, ref_stats.processed));
+    }
+    if (stats.auto_resolved, stats.auto_charged_back) != (ref_stats.auto_resolved, ref_stats.auto_charged_back) {
+        differences.push(format!("auto-closed disputes (resolved, charged back) {:?} vs {:?}", 
+            (stats.auto_resolved, stats.auto_charged_back), (ref_stats.auto_resolved, ref_stats.auto_charged_back)));
+    }
+    if stats.fees != ref_stats.fees {
+        differences.push(format!("fees {} vs {}", stats.fees, ref_stats.fees));
+    }
+    if stats.journal != ref_stats.journal {
+        differences.push(format!("journal postings {} vs {}", stats.journal.postings.len(), ref_stats.journal.postings.len()));
+    }
+    if stats.evicted != ref_stats.evicted {
+        differences.push(format!("evicted transactions {} vs {}", stats.evicted, ref_stats.evicted));
+    }
+    if stats.out_of_order != ref_stats.out_of_order {
+        differences.push(format!("out of order timestamps {} vs {}", stats.out_of_order, ref_stats.out_of_order));
+    }
+    let mut clients: Vec<_> = accounts.keys().chain(ref_accounts.keys()).collect();
+    clients.sort_unstable();
+    clients.dedup();
+    for client in clients {
+        match (accounts.get(client), ref_accounts.get(client)) {
+            (Some(acct), Some(ref_acct)) if acct == ref_acct => {},
+            (Some(acct), Some(ref_acct)) => differences.push(format!(
+                "client {}: available {} vs {}, held {} vs {}, locked {} vs {}, transactions {} vs {}",
+                client, acct.available, ref_acct.available, acct.held, ref_acct.held, 
+                acct.locked, ref_acct.locked, acct.transactions.len(), ref_acct.transactions.len())),
+            (Some(_), None) => differences.push(format!("client {}: missing in reference result", client)),
+            (None, _) => differences.push(format!("client {}: missing in result", client)),
+        }
+    }
+    if !differences.is_empty() {
+        bail!("Engines results differ:\n{}", differences.join("\n"))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use csv_async::AsyncReaderBuilder;
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+    use crate::{parser::RecordReader, transactions::proptests};
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn multi_worker_equals_sequential_fold(
+            ops in proptests::ops(300),
+            num_workers in 1..5usize,
+            batch_size in 1..20usize,
+            assignment in prop_oneof![Just(Assignment::RoundRobin), Just(Assignment::Hash), Just(Assignment::Load)],
+        ) {
+            let mut sequential = HashMap::new();
+            let processed = ops.iter()
+                .fold(0u128, |processed, op| processed + proptests::apply(op, &mut sequential) as u128);
+            let stats = Stats {processed, ..Default::default()};
+
+            let input: String = std::iter::once("type,client,tx,amount\n".to_string())
+                .chain(ops.iter().map(proptests::to_csv))
+                .collect();
+            let rdr = AsyncReaderBuilder::new()
+                .flexible(true)
+                .create_deserializer(input.as_bytes());
+            let result = async_std::task::block_on(async {
+                let batching = Batching {size: batch_size, latency: Duration::from_secs(1)};
+                processing_loop(Records::Sequential(RecordReader::new(rdr).await?), HashMap::new(), &Settings::default(), num_workers, 1, batching, assignment).await
+            }).expect("processing succeeded");
+            prop_assert_eq!(result.0.worker_processed.iter().sum::<u128>(), result.0.processed);
+            compare_results(&result, &(stats, sequential)).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        }
+    }
+
+    #[test]
+    fn hash_assignment() {
+        let mut counts = [0usize; 4];
+        for client in 0..1000 {
+            counts[hash_worker(client, 4)] += 1;
         }
-        processed_sucessfully = processed_sucessfully.wrapping_add(1);
+        assert!(counts.iter().all(|count| (200..300).contains(count)), "{:?}", counts);
+        assert_eq!(hash_worker(7, 3), hash_worker(7, 3));
+    }
+
+    #[async_std::test]
+    async fn flush_while_reading() {
+        let mut oldest = Some(Instant::now());
+        let mut flushed = 0;
+        let record = wait_flushing(async_std::task::sleep(Duration::from_millis(50)), &mut oldest, Duration::from_millis(10), || flushed += 1).await;
+        assert_eq!((record, flushed, oldest), ((), 1, None)); // batches sent before record was read
+        let record = wait_flushing(async {7}, &mut oldest, Duration::ZERO, || flushed += 1).await;
+        assert_eq!((record, flushed), (7, 1)); // nothing waits
+    }
+
+    #[test]
+    fn fees_posted_to_house_account() {
+        let schedule = FeeSchedule {withdrawal: None, chargeback: None, account: 9};
+        let settings = Settings {fees: Some(schedule), quiet: true, ..Default::default()};
+        let post = |house: AccountState, fees: TMoney| {
+            let mut stats = Stats {fees, ..Default::default()};
+            let mut accounts = HashMap::from([(9, house)]);
+            post_fees(&mut stats, &mut accounts, &settings);
+            accounts[&9].available
+        };
+        assert_eq!(post(AccountState::with_balance(dec!(1.0)), dec!(2.5)), dec!(3.5));
+        let locked = AccountState {locked: true, ..AccountState::with_balance(dec!(1.0))};
+        assert_eq!(post(locked, dec!(2.5)), dec!(1.0));
+        let mut client = AccountState::with_balance(dec!(1.0));
+        client.evicted.insert(5);
+        assert_eq!(post(client, dec!(2.5)), dec!(1.0));
+        assert_eq!(post(AccountState::with_balance(TMoney::MAX), dec!(2.5)), TMoney::MAX);
     }
-    (processed_sucessfully, accounts)
-}
\ No newline at end of file
+
+    #[test]
+    fn fees_total_overflow() {
+        let mut stats = Stats {fees: TMoney::MAX, ..Default::default()};
+        stats.add(&Stats {fees: dec!(1), ..Default::default()}, true);
+        assert_eq!(stats.fees, TMoney::MAX);
+        stats.add(&Stats {fees: dec!(-1), ..Default::default()}, true);
+        assert_eq!(stats.fees, TMoney::MAX - dec!(1));
+    }
+
+    #[test]
+    fn compare() {
+        let mut accounts = HashMap::new();
+        accounts.insert(1, AccountState::with_balance(dec!(1.0)));
+        accounts.insert(2, AccountState::with_balance(dec!(2.0)));
+        let mut ref_accounts = HashMap::new();
+        ref_accounts.insert(1, AccountState::with_balance(dec!(1.0)));
+        ref_accounts.insert(2, AccountState::with_balance(dec!(2.0)));
+        let mut result = (Stats {processed: 2, ..Default::default()}, accounts);
+        let ref_result = (Stats {processed: 2, ..Default::default()}, ref_accounts);
+        assert!(compare_results(&result, &ref_result).is_ok());
+        result.0.processed = 3;
+        assert!(compare_results(&result, &ref_result).is_err());
+        result.0.processed = 2;
+        result.1.get_mut(&2).expect("client 2 in test accounts").locked = true;
+        assert!(compare_results(&result, &ref_result).is_err());
+        result.1.remove(&2);
+        assert!(compare_results(&result, &ref_result).is_err());
+    }
+}
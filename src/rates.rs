@@ -0,0 +1,195 @@
+//! Exchange rates - conversion rates used by `exchange` transactions.
+//!
+//! Simple CSV file with columns `from,to,rate,effective` (`effective` is optional), one row per currency pair and
+//! time rate is effective from (RFC3339 date-time or seconds since Unix epoch). Rate stays effective until next
+//! rate of the same pair, rate without `effective` time applies from the very beginning. Currency equal to base
+//! currency denotes default currency of accounts.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Result, Context, bail};
+use async_std::{fs::File, stream::StreamExt};
+use csv_async::AsyncReaderBuilder;
+use futures::io::AsyncRead;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+
+use crate::{
+    TMoney, TTimestamp,
+    currency::Currency,
+    transactions::deserialize_timestamp,
+};
+
+/// Represents exchange rate record as read from input file.
+#[derive(Deserialize)]
+struct RateRec {
+    from: Currency,
+    to: Currency,
+    rate: TMoney,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    effective: Option<TTimestamp>,
+}
+
+/// Rounding of converted amounts (to 4 decimal places).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rounding {
+    /// Half to even (banker's rounding).
+    #[default]
+    HalfEven,
+    /// Half away from zero.
+    HalfUp,
+    /// Towards zero.
+    Down,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "half-even" => Ok(Rounding::HalfEven),
+            "half-up" => Ok(Rounding::HalfUp),
+            "down" => Ok(Rounding::Down),
+            _ => Err(format!("unknown rounding: {} (expected half-even, half-up or down)", s)),
+        }
+    }
+}
+
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+/// Currency pair (`None` - default currency).
+type Pair = (Option<Currency>, Option<Currency>);
+
+/// Exchange rates of currency pairs together with spread and rounding applied on conversion.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExchangeRates {
+    /// Rates of currency pairs ordered by time they are effective from.
+    rates: HashMap<Pair, Vec<(Option<TTimestamp>, TMoney)>>,
+    /// Spread in percent - client gets rate lowered by this part.
+    pub spread: TMoney,
+    /// Rounding of converted amounts.
+    pub rounding: Rounding,
+}
+
+impl ExchangeRates {
+    /// Returns rate of currency pair effective at given time (latest rate if time is not known).
+    pub fn rate(&self, from: Option<Currency>, to: Option<Currency>, timestamp: Option<TTimestamp>) -> Option<TMoney> {
+        let rates = self.rates.get(&(from, to))?;
+        let rate = match timestamp {
+            None => rates.last(),
+            Some(timestamp) => rates.iter().rev().find(|(effective, _)| effective.is_none_or(|effective| effective <= timestamp)),
+        };
+        rate.map(|(_, rate)| *rate)
+    }
+
+    /// Converts `amount` with rate effective at given time lowered by spread, converted amount is rounded.
+    /// Returns `None` if there is no effective rate or conversion overflows.
+    pub fn convert(&self, from: Option<Currency>, to: Option<Currency>, amount: TMoney, timestamp: Option<TTimestamp>) -> Option<TMoney> {
+        let rate = self.rate(from, to, timestamp)?;
+        let rate = rate - rate * self.spread / Decimal::ONE_HUNDRED;
+        Some(amount.checked_mul(rate)?.round_dp_with_strategy(4, self.rounding.strategy()).normalize())
+    }
+}
+
+#[cfg(test)]
+impl ExchangeRates {
+    pub fn insert(&mut self, from: Option<Currency>, to: Option<Currency>, effective: Option<TTimestamp>, rate: TMoney) {
+        let pair = self.rates.entry((from, to)).or_default();
+        pair.push((effective, rate));
+        pair.sort_by_key(|(effective, _)| *effective);
+    }
+}
+
+/// Loads exchange rates from file, see `read` for details.
+pub async fn load(path: &Path, base_currency: Option<Currency>) -> Result<ExchangeRates> {
+    let file = File::open(path).await
+        .with_context(|| format!("opening exchange rates file: {}", path.display()))?;
+    read(file, base_currency).await
+        .with_context(|| format!("loading exchange rates file: {}", path.display()))
+}
+
+/// Reads exchange rates from `data` (without spread), `base_currency` is mapped to default currency.
+/// - if rate is not positive - fail.
+/// - if currencies of pair are the same - fail.
+/// - if rate of pair with the same effective time occurs more than once in data - fail.
+pub async fn read<R>(data: R, base_currency: Option<Currency>) -> Result<ExchangeRates>
+    where R: AsyncRead + Unpin + Send
+{
+    let mut rdr = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(data);
+    let mut records = rdr.deserialize::<RateRec>();
+    let mut rates = ExchangeRates::default();
+    let mut rec_no = 0usize;
+    while let Some(record) = records.next().await {
+        rec_no += 1;
+        let rec = record.with_context(|| format!("Record# {} - parsing failed", rec_no))?;
+        if rec.rate.is_sign_negative() || rec.rate.is_zero() {
+            bail!("Record# {} - rate must be positive", rec_no)
+        }
+        if rec.from == rec.to {
+            bail!("Record# {} - rate of {} to itself", rec_no, rec.from)
+        }
+        let currency = |currency| Some(currency).filter(|currency| Some(*currency) != base_currency);
+        let pair = rates.rates.entry((currency(rec.from), currency(rec.to))).or_default();
+        match pair.binary_search_by_key(&rec.effective, |(effective, _)| *effective) {
+            Ok(_) => bail!("Record# {} - rate of {} to {} defined more than once for the same time", rec_no, rec.from, rec.to),
+            Err(pos) => pair.insert(pos, (rec.effective, rec.rate)),
+        }
+    }
+    Ok(rates)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[async_std::test]
+    async fn effective_rates() {
+        let data = "from,to,rate,effective\nEUR,USD,1.1,\nEUR,USD,1.2,2024-01-02T00:00:00Z\nEUR,USD,1.15,1704067200\n";
+        let usd = "USD".parse().ok();
+        let eur = "EUR".parse().ok();
+        let rates = read(data.as_bytes(), usd).await.expect("valid rates");
+        assert_eq!(rates.rate(eur, None, Some(0)), Some(dec!(1.1)));
+        assert_eq!(rates.rate(eur, None, Some(1_704_067_200_000)), Some(dec!(1.15)));
+        assert_eq!(rates.rate(eur, None, Some(1_800_000_000_000)), Some(dec!(1.2)));
+        assert_eq!(rates.rate(eur, None, None), Some(dec!(1.2)));
+        assert_eq!(rates.rate(None, eur, None), None);
+    }
+
+    #[async_std::test]
+    async fn spread_and_rounding() {
+        let data = "from,to,rate\nEUR,USD,1.23456\n";
+        let usd = "USD".parse().ok();
+        let eur = "EUR".parse().ok();
+        let mut rates = read(data.as_bytes(), None).await.expect("valid rates");
+        assert_eq!(rates.convert(eur, usd, dec!(10), None), Some(dec!(12.3456)));
+        assert_eq!(rates.convert(eur, usd, dec!(0.5), None), Some(dec!(0.6173))); // 0.61728
+        rates.spread = dec!(1);
+        assert_eq!(rates.convert(eur, usd, dec!(10), None), Some(dec!(12.2221))); // 12.222144
+        rates.spread = dec!(0);
+        rates.rounding = Rounding::Down;
+        assert_eq!(rates.convert(eur, usd, dec!(0.5), None), Some(dec!(0.6172)));
+        assert_eq!(rates.convert(usd, eur, dec!(10), None), None);
+    }
+
+    #[async_std::test]
+    async fn invalid_rates() {
+        let data = "from,to,rate,effective\nEUR,USD,0,\n";
+        assert!(read(data.as_bytes(), None).await.is_err());
+        let data = "from,to,rate,effective\nEUR,EUR,1,\n";
+        assert!(read(data.as_bytes(), None).await.is_err());
+        let data = "from,to,rate,effective\nEUR,USD,1.1,1704067200\nEUR,USD,1.2,2024-01-01T00:00:00Z\n";
+        assert!(read(data.as_bytes(), None).await.is_err());
+    }
+}
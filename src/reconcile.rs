@@ -0,0 +1,181 @@
+//! Reconciliation of engine result with expected balances.
+//!
+//! Expected balances are read from CSV file with the same columns as program output: `client,available,held,total,locked`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Result, Context, bail};
+use async_std::{fs::File, stream::StreamExt};
+use csv_async::AsyncReaderBuilder;
+use futures::io::AsyncRead;
+use serde::Deserialize;
+
+use crate::{
+    TClientId, TMoney,
+    accounts::AccountState,
+};
+
+/// Represents expected balance of account as read from input file.
+#[derive(Deserialize)]
+pub struct ExpectedRec {
+    pub client: TClientId,
+    pub available: TMoney,
+    pub held: TMoney,
+    pub total: TMoney,
+    pub locked: bool,
+}
+
+/// Single difference between expected and actual accounts.
+pub enum Difference {
+    /// Client expected but not present in engine result.
+    Missing(TClientId),
+    /// Client present in engine result but not expected.
+    Extra(TClientId),
+    /// Value of given field differs.
+    Mismatch {
+        client: TClientId,
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Result of reconciliation - list of differences ordered by client.
+#[derive(Default)]
+pub struct Report {
+    pub differences: Vec<Difference>,
+}
+
+impl Report {
+    /// Returns `true` if no differences were found.
+    pub fn is_ok(&self) -> bool {
+        self.differences.is_empty()
+    }
+
+    /// Returns number of (missing, extra, mismatched fields) differences.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        self.differences.iter().fold((0, 0, 0), |(m, e, f), d| match d {
+            Difference::Missing(_) => (m + 1, e, f),
+            Difference::Extra(_) => (m, e + 1, f),
+            Difference::Mismatch{..} => (m, e, f + 1),
+        })
+    }
+
+    fn check<T: PartialEq + ToString>(&mut self, client: TClientId, field: &'static str, expected: T, actual: T) {
+        if expected != actual {
+            self.differences.push(Difference::Mismatch {
+                client, field, expected: expected.to_string(), actual: actual.to_string()
+            });
+        }
+    }
+}
+
+/// Formats report as CSV with columns `client,issue,field,expected,actual`.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client,issue,field,expected,actual")?;
+        for diff in &self.differences {
+            match diff {
+                Difference::Missing(client) => write!(f, "\n{},missing,,,", client)?,
+                Difference::Extra(client) => write!(f, "\n{},extra,,,", client)?,
+                Difference::Mismatch{client, field, expected, actual} => 
+                    write!(f, "\n{},mismatch,{},{},{}", client, field, expected, actual)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads expected balances from file.
+pub async fn load_expected(path: &Path) -> Result<HashMap::<TClientId,ExpectedRec>> {
+    let file = File::open(path).await
+        .with_context(|| format!("opening expected balances file: {}", path.display()))?;
+    read_expected(file).await
+        .with_context(|| format!("loading expected balances file: {}", path.display()))
+}
+
+/// Reads expected balances from `data`, fails if client occurs more than once.
+pub async fn read_expected<R>(data: R) -> Result<HashMap::<TClientId,ExpectedRec>>
+    where R: AsyncRead + Unpin + Send
+{
+    let mut rdr = AsyncReaderBuilder::new()
+        .trim(csv_async::Trim::All)
+        .create_deserializer(data);
+    let mut records = rdr.deserialize::<ExpectedRec>();
+    let mut expected = HashMap::new();
+    let mut rec_no = 0usize;
+    while let Some(record) = records.next().await {
+        rec_no += 1;
+        let rec = record.with_context(|| format!("Record# {} - parsing failed", rec_no))?;
+        if let Some(rec) = expected.insert(rec.client, rec) {
+            bail!("Record# {} - client {} occurs more than once", rec_no, rec.client)
+        }
+    }
+    Ok(expected)
+}
+
+/// Compares `actual` accounts with `expected` balances.
+pub fn reconcile(expected: &HashMap::<TClientId,ExpectedRec>, actual: &HashMap::<TClientId,AccountState>) -> Report {
+    let mut clients = BTreeMap::new();
+    for client in expected.keys() {
+        clients.insert(*client, (expected.get(client), actual.get(client)));
+    }
+    for client in actual.keys() {
+        clients.insert(*client, (expected.get(client), actual.get(client)));
+    }
+
+    let mut report = Report::default();
+    for (client, pair) in clients {
+        match pair {
+            (Some(exp), Some(act)) => {
+                report.check(client, "available", exp.available, act.available);
+                report.check(client, "held", exp.held, act.held);
+                report.check(client, "total", exp.total, act.total());
+                report.check(client, "locked", exp.locked, act.locked);
+            },
+            (Some(_), None) => report.differences.push(Difference::Missing(client)),
+            (None, Some(_)) => report.differences.push(Difference::Extra(client)),
+            (None, None) => unreachable!(),
+        }
+    }
+    report
+}
+
+/// Compares `actual` accounts with expected balances read from file.
+pub async fn reconcile_file(path: &Path, actual: &HashMap::<TClientId,AccountState>) -> Result<Report> {
+    let expected = load_expected(path).await?;
+    Ok(reconcile(&expected, actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[async_std::test]
+    async fn differences() {
+        let mut actual = HashMap::new();
+        actual.insert(1, AccountState::with_balance(dec!(1.5)));
+        actual.insert(2, AccountState::with_balance(dec!(2.0)));
+        actual.insert(4, AccountState::with_balance(dec!(4.0)));
+        let data = "client,available,held,total,locked\n1, 1.50, 0, 1.5, false\n2, 2.0, 0, 2.0, true\n3, 1, 0, 1, false\n";
+        let expected = read_expected(data.as_bytes()).await.expect("expected balances");
+        let report = reconcile(&expected, &actual);
+        assert!(!report.is_ok());
+        assert_eq!(report.counts(), (1, 1, 1));
+        assert_eq!(report.to_string(), "client,issue,field,expected,actual\n2,mismatch,locked,true,false\n3,missing,,,\n4,extra,,,");
+    }
+
+    #[async_std::test]
+    async fn matching() {
+        let mut actual = HashMap::new();
+        let mut acct = AccountState::with_balance(dec!(1.5));
+        acct.held = dec!(0.5);
+        actual.insert(1, acct);
+        let data = "client,available,held,total,locked\n1, 1.5, 0.5, 2.0, false\n";
+        let expected = read_expected(data.as_bytes()).await.expect("expected balances");
+        assert!(reconcile(&expected, &actual).is_ok());
+    }
+}
@@ -0,0 +1,166 @@
+//! Retention of stored transactions.
+//!
+//! Transactions are kept in accounts only to allow their later disputes, so without limits memory grows with every
+//! deposit and withdrawal ever seen. Retention policy evicts the oldest transactions of client which are beyond
+//! given number of the most recent ones and/or older than given number of seconds (measured with timestamp
+//! of the latest transaction of client). Transactions under dispute are never evicted.
+//! Dispute, resolve or chargeback referencing evicted transaction is rejected with "reference expired" reason.
+//! IDs of evicted transactions are kept as ranges of consecutive IDs, deposit, withdrawal or exchange with ID
+//! not greater than the greatest evicted one is rejected (it could not be told apart from evicted one later).
+//! Evicted transactions may be spilled to CSV file with columns
+//! `client,tx,amount,currency,timestamp,charged_back,linked_amount,linked_currency`.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{Result, Context};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    TClientId, TTrxID, TTimestamp,
+    accounts::{AccountState, TrxEntry},
+    currency::Currency,
+};
+
+/// Retention policy of stored transactions.
+#[derive(Clone, Debug, Default)]
+pub struct Retention {
+    /// Number of the most recent transactions kept per client.
+    pub last: Option<usize>,
+    /// Age (in seconds) of the oldest transaction kept.
+    pub seconds: Option<u64>,
+    /// File where evicted transactions are written.
+    pub spill: Option<Spill>,
+}
+
+impl Retention {
+    /// Evicts transactions of `client` account according to policy (`timestamp` - time of the latest transaction
+    /// of client), evicted transactions are written to spill file. Returns number of evicted transactions.
+    pub fn apply(&self, client: TClientId, acct: &mut AccountState, timestamp: Option<TTimestamp>) -> Result<usize> {
+        let cutoff = self.seconds
+            .zip(timestamp)
+            .map(|(seconds, timestamp)| timestamp.saturating_sub(seconds.min(i64::MAX as u64 / 1000) as i64 * 1000));
+        let evicted = acct.transactions.evict(self.last.unwrap_or(usize::MAX), cutoff);
+        for (tx, _) in &evicted {
+            acct.evicted.insert(*tx);
+        }
+        if let Some(spill) = &self.spill {
+            spill.write(client, &evicted)?;
+        }
+        Ok(evicted.len())
+    }
+
+    /// Returns the same policy without spill file (e.g. for reference run of `--verify`).
+    pub fn without_spill(&self) -> Self {
+        Self {spill: None, ..self.clone()}
+    }
+}
+
+/// IDs of evicted transactions, as ordered ranges (`(first, last)`) of consecutive IDs.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EvictedIds(Vec<(TTrxID, TTrxID)>);
+
+impl EvictedIds {
+    /// Adds ID of evicted transaction.
+    pub fn insert(&mut self, tx: TTrxID) {
+        // the first range which does not end before `tx`
+        let pos = self.0.partition_point(|(_, last)| *last < tx);
+        if self.0.get(pos).is_some_and(|(first, _)| *first <= tx) {
+            return
+        }
+        let joins_prev = pos > 0 && self.0[pos - 1].1 + 1 == tx;
+        let joins_next = self.0.get(pos).is_some_and(|(first, _)| *first == tx + 1);
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.0[pos - 1].1 = self.0[pos].1;
+                self.0.remove(pos);
+            },
+            (true, false) => self.0[pos - 1].1 = tx,
+            (false, true) => self.0[pos].0 = tx,
+            (false, false) => self.0.insert(pos, (tx, tx)),
+        }
+    }
+
+    /// Returns `true` if no transaction was evicted.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns `true` if transaction `tx` was evicted.
+    pub fn contains(&self, tx: TTrxID) -> bool {
+        let pos = self.0.partition_point(|(_, last)| *last < tx);
+        self.0.get(pos).is_some_and(|(first, _)| *first <= tx)
+    }
+
+    /// Returns `true` if ID `tx` is not greater than ID of any evicted transaction.
+    pub fn covers(&self, tx: TTrxID) -> bool {
+        self.0.last().is_some_and(|(_, last)| tx <= *last)
+    }
+}
+
+/// CSV file with evicted transactions, shared by all workers.
+#[derive(Clone, Debug)]
+pub struct Spill(Arc<Mutex<BufWriter<File>>>);
+
+impl Spill {
+    /// Creates spill file (with headers).
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)
+            .with_context(|| format!("creating retention spill file: {}", path.display()))?);
+        writeln!(file, "client,tx,amount,currency,timestamp,charged_back,linked_amount,linked_currency")
+            .with_context(|| format!("writing retention spill file: {}", path.display()))?;
+        Ok(Self(Arc::new(Mutex::new(file))))
+    }
+
+    /// Writes evicted transactions of client, default currency is written as empty string.
+    fn write(&self, client: TClientId, evicted: &[(TTrxID, TrxEntry)]) -> Result<()> {
+        let currency = |currency: Option<Currency>| currency.map(|currency| currency.to_string()).unwrap_or_default();
+        let mut file = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        for (tx, trx) in evicted {
+            writeln!(file, "{},{},{},{},{},{},{},{}",
+                client, tx, trx.amount, currency(trx.currency),
+                trx.timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default(), trx.charged_back,
+                trx.linked.map(|leg| leg.amount.to_string()).unwrap_or_default(), currency(trx.linked.and_then(|leg| leg.currency)))
+                .context("writing retention spill file")?;
+        }
+        Ok(())
+    }
+
+    /// Writes buffered transactions to file.
+    pub fn flush(&self) -> Result<()> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).flush().context("writing retention spill file")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[test]
+    fn last_and_seconds() {
+        let mut acct = AccountState::default();
+        for tx in 1..=4 {
+            acct.transactions.insert(tx, TrxEntry::new(dec!(1), Some(tx as TTimestamp * 10_000)));
+        }
+        let retention = Retention {last: Some(3), seconds: Some(15), spill: None};
+        assert_eq!(retention.apply(1, &mut acct, None).expect("no spill"), 1);
+        assert!(acct.evicted.contains(1));
+        assert_eq!(retention.apply(1, &mut acct, Some(40_000)).expect("no spill"), 1);  // older than 25s
+        assert!(acct.evicted.contains(2) && !acct.evicted.contains(3));
+        assert_eq!(acct.transactions.len(), 2);
+    }
+
+    #[test]
+    fn evicted_ranges() {
+        let mut evicted = EvictedIds::default();
+        for tx in [5, 1, 2, 7, 6, 2, 10] {
+            evicted.insert(tx);
+        }
+        assert_eq!(evicted.0, [(1, 2), (5, 7), (10, 10)]);
+        assert!(evicted.contains(6) && !evicted.contains(3) && !evicted.contains(11));
+        assert!(evicted.covers(3) && evicted.covers(10) && !evicted.covers(11));
+    }
+}
@@ -0,0 +1,94 @@
+//! Snapshot of full engine state.
+//!
+//! Snapshot contains every `AccountState` together with its transactions (including dispute flags and amounts),
+//! so it may be used as a starting state of next processing run (e.g. to process day N on top of day N-1 closing state).
+//! Snapshot is stored as JSON document with format version number.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::{Result, Context, bail};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    TClientId,
+    accounts::AccountState,
+};
+
+/// Version of snapshot file format. Must be increased on every incompatible change of `AccountState` layout.
+pub const SNAPSHOT_VERSION: u32 = 9;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<A> {
+    version: u32,
+    accounts: A,
+}
+
+/// Serializes accounts to snapshot document.
+pub fn to_json(accounts: &HashMap::<TClientId,AccountState>) -> Result<Vec<u8>> {
+    // Sorted by client to make snapshots easier to compare
+    let accounts: BTreeMap<_,_> = accounts.iter().collect();
+    Ok(serde_json::to_vec(&Snapshot {version: SNAPSHOT_VERSION, accounts})?)
+}
+
+/// Deserializes accounts from snapshot document, rejects snapshots with different format version.
+pub fn from_json(data: &[u8]) -> Result<HashMap::<TClientId,AccountState>> {
+    #[derive(Deserialize)]
+    struct Header {
+        version: u32,
+    }
+    let header: Header = serde_json::from_slice(data).context("reading snapshot version")?;
+    if header.version != SNAPSHOT_VERSION {
+        bail!("unsupported snapshot version {} (expected {})", header.version, SNAPSHOT_VERSION)
+    }
+    let snapshot: Snapshot<HashMap::<TClientId,AccountState>> = serde_json::from_slice(data).context("reading snapshot accounts")?;
+    Ok(snapshot.accounts)
+}
+
+/// Loads accounts from snapshot file.
+pub async fn load(path: &Path) -> Result<HashMap::<TClientId,AccountState>> {
+    let data = async_std::fs::read(path).await
+        .with_context(|| format!("opening snapshot file: {}", path.display()))?;
+    from_json(&data).with_context(|| format!("loading snapshot file: {}", path.display()))
+}
+
+/// Saves accounts to snapshot file.
+pub async fn save(path: &Path, accounts: &HashMap::<TClientId,AccountState>) -> Result<()> {
+    let data = to_json(accounts)?;
+    async_std::fs::write(path, data).await
+        .with_context(|| format!("writing snapshot file: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use crate::accounts::TrxEntry;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut accounts = HashMap::new();
+        let mut acct = AccountState::with_balance(dec!(1.5));
+        acct.held = dec!(1.25);
+        acct.transactions.insert(1, TrxEntry::new(dec!(1.5), None));
+        acct.transactions.insert(2, TrxEntry {disputed: dec!(1.25), ..TrxEntry::new(dec!(2.25), Some(1_600_000_000_000))});
+        accounts.insert(1, acct);
+        accounts.insert(2, AccountState {locked: true, ..Default::default()});
+
+        let data = to_json(&accounts).expect("serialized snapshot");
+        let loaded = from_json(&data).expect("deserialized snapshot");
+        let acct = loaded.get(&1).expect("client 1 in snapshot");
+        assert_eq!(acct.available, dec!(1.5));
+        assert_eq!(acct.held, dec!(1.25));
+        assert_eq!(acct.transactions.get(2), Some(TrxEntry {disputed: dec!(1.25), ..TrxEntry::new(dec!(2.25), Some(1_600_000_000_000))}));
+        assert!(loaded.get(&2).expect("client 2 in snapshot").locked);
+    }
+
+    #[test]
+    fn wrong_version() {
+        let data = br#"{"version": 1, "accounts": {}}"#;
+        assert!(from_json(data).is_err());
+        let data = br#"{"accounts": {}}"#;
+        assert!(from_json(data).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Account statements - operations recorded in account history together with opening and closing balances.
+//!
+//! Statement covers whole recorded history of account, so opening balances are balances of account when history
+//! recording started (zero for accounts created later). Statements are printed as CSV with columns
+//! `client,rec_no,tx,type,currency,amount,available,held,disputed,charged_back,timestamp,reason`
+//! (opening and closing balances are rows of type `opening` and `closing`) or as JSON.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    TClientId, TMoney,
+    accounts::{AccountState, Balance, HistoryEntry, HistoryKind},
+    currency::Currency,
+};
+
+/// Output format of statements.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StatementFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for StatementFormat {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(StatementFormat::Csv),
+            "json" => Ok(StatementFormat::Json),
+            _ => Err(format!("unknown statement format: {} (expected csv or json)", s)),
+        }
+    }
+}
+
+/// Balance of account in one currency.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct StatementBalance {
+    /// Currency (`None` - default currency)
+    pub currency: Option<Currency>,
+    pub available: TMoney,
+    pub held: TMoney,
+    pub total: TMoney,
+}
+
+impl StatementBalance {
+    fn new(currency: Option<Currency>, balance: Balance) -> Self {
+        Self {currency, available: balance.available, held: balance.held, total: balance.total()}
+    }
+}
+
+/// Statement of one client.
+#[derive(Debug, Serialize)]
+pub struct Statement {
+    pub client: TClientId,
+    /// Balances when history recording started
+    pub opening: Vec<StatementBalance>,
+    /// Recorded operations in order of processing
+    pub entries: Vec<HistoryEntry>,
+    /// Final balances
+    pub closing: Vec<StatementBalance>,
+    pub locked: bool,
+}
+
+/// Returns statement of `client` account, `None` if history of account is not recorded.
+pub fn statement(client: TClientId, acct: &AccountState) -> Option<Statement> {
+    let history = acct.history.as_ref()?;
+    let (opening, entries): (Vec<_>, Vec<_>) = history.iter().cloned()
+        .partition(|entry| entry.kind == HistoryKind::Opening);
+    let closing: Vec<_> = acct.balances().map(|(currency, balance)| StatementBalance::new(currency, balance)).collect();
+    let opening = if opening.is_empty() {
+        // account was created while history was recorded
+        closing.iter().map(|balance| StatementBalance::new(balance.currency, Balance::default())).collect()
+    } else {
+        opening.into_iter()
+            .map(|entry| StatementBalance::new(entry.currency, Balance {available: entry.available, held: entry.held}))
+            .collect()
+    };
+    Some(Statement {client, opening, entries, closing, locked: acct.locked})
+}
+
+/// Returns statements of given `clients` (all clients if empty) ordered by client.
+/// Clients which are unknown or which history is not recorded are skipped.
+pub fn statements(accounts: &HashMap::<TClientId,AccountState>, clients: &[TClientId]) -> Vec<Statement> {
+    let mut statements: Vec<_> = accounts.iter()
+        .filter(|(client, _)| clients.is_empty() || clients.contains(client))
+        .filter_map(|(client, acct)| statement(*client, acct))
+        .collect();
+    statements.sort_by_key(|statement| statement.client);
+    statements
+}
+
+/// Formats statements as CSV (default currency is written as empty string).
+pub fn to_csv(statements: &[Statement]) -> String {
+    let mut csv = String::from("client,rec_no,tx,type,currency,amount,available,held,disputed,charged_back,timestamp,reason\n");
+    for statement in statements {
+        let client = statement.client;
+        for balance in &statement.opening {
+            let _ = writeln!(csv, "{},,,opening,{},,{},{},,,,", client, currency(balance.currency), balance.available, balance.held);
+        }
+        for entry in &statement.entries {
+            let timestamp = entry.timestamp
+                .and_then(chrono::DateTime::from_timestamp_millis)
+                .map(|timestamp| timestamp.to_rfc3339())
+                .unwrap_or_default();
+            let _ = writeln!(csv, "{},{},{},{},{},{},{},{},{},{},{},{}",
+                client, entry.rec_no, entry.tx, entry.kind, currency(entry.currency), optional(entry.amount),
+                entry.available, entry.held, optional(entry.disputed), optional(entry.charged_back),
+                timestamp, quoted(entry.reason.as_deref().unwrap_or_default()));
+        }
+        for balance in &statement.closing {
+            let _ = writeln!(csv, "{},,,closing,{},,{},{},,,,", client, currency(balance.currency), balance.available, balance.held);
+        }
+    }
+    csv
+}
+
+/// Formats statements as JSON array.
+pub fn to_json(statements: &[Statement]) -> Result<String> {
+    Ok(serde_json::to_string_pretty(statements)?)
+}
+
+fn currency(currency: Option<Currency>) -> String {
+    currency.map(|currency| currency.to_string()).unwrap_or_default()
+}
+
+fn optional(amount: Option<TMoney>) -> String {
+    amount.map(|amount| amount.to_string()).unwrap_or_default()
+}
+
+/// Quotes CSV field if necessary.
+fn quoted(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[test]
+    fn opening_and_closing() {
+        let mut acct = AccountState::with_balance(dec!(10));
+        acct.start_history();
+        acct.available += dec!(5);
+        acct.record(HistoryEntry {
+            reason: Some("refund, see ticket".to_string()),
+            ..HistoryEntry::new(1, 7, HistoryKind::Deposit, None, Some(dec!(5)), acct.balance(None))
+        });
+        let statement = statement(3, &acct).expect("history is recorded");
+        assert_eq!(statement.opening, vec![StatementBalance {currency: None, available: dec!(10), held: dec!(0), total: dec!(10)}]);
+        assert_eq!(statement.entries.len(), 1);
+        assert_eq!(statement.closing, vec![StatementBalance {currency: None, available: dec!(15), held: dec!(0), total: dec!(15)}]);
+        let csv = to_csv(&[statement]);
+        assert_eq!(csv.lines().nth(2), Some("3,1,7,deposit,,5,15,0,,,,\"refund, see ticket\""));
+        assert_eq!(csv.lines().count(), 4);
+    }
+
+    #[test]
+    fn not_recorded() {
+        let accounts = HashMap::from([(1, AccountState::with_balance(dec!(10)))]);
+        assert!(statements(&accounts, &[]).is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+//! Compact storage of account transactions.
+//!
+//! Every deposit, withdrawal and exchange is kept in its account, so that it may be disputed later - with millions
+//! of transactions this storage dominates memory usage. Most of transactions are never disputed, so they are kept
+//! as packed records (ID, amount, timestamp and currency - 32 bytes) in vector ordered by transaction ID, without
+//! hash table overhead and without allocation per transaction. Transaction which does not fit packed record
+//! (it is disputed or charged back, or it is exchange with linked leg) is kept as full `TrxEntry` in hash map.
+//! Packed record of transaction which is changed later (`get_mut`, e.g. by dispute) is moved to hash map for good,
+//! it stays there as full entry also when dispute is resolved.
+//!
+//! Target is at most 64 bytes per stored transaction in accounts after processing (including account state, spare
+//! capacity of vectors and full entries of once disputed transactions - transaction is not packed again after
+//! dispute is resolved), so that 100M stored transactions fit in 6.4 GB. Generated files (`cargo bench --bench
+//! memory`) give (accounts / `TrxStore` rebuilt / `HashMap<TTrxID, TrxEntry>` used before, bytes per stored
+//! transaction):
+//! - 1M records (777k stored transactions): 104.4 / 55.2 / 155.7,
+//! - 10M records (4.7M stored): 61.2 / 52.2 / 167.1,
+//! - 100M records (6.1M stored): 59.8 / 51.1 / 159.4.
+//!
+//! Client IDs are 16-bit, so every input has at most 65535 clients (as generated files from 330k records) and
+//! larger input means more transactions per account. At 1M records account state and hash tables are amortized
+//! over 12 transactions per account, so the target is not met; at 100M records over 92 (stored transactions do not
+//! grow in proportion, as generated chargebacks lock accounts and later transactions are rejected). Input of 100M
+//! records without locked accounts would store more transactions per account, so it would come even closer to
+//! packed records with spare capacity of vectors (about 51 bytes).
+
+//! Transactions are expected to come in order of their IDs (they are appended then), transaction with lower ID
+//! than already stored ones is inserted in the middle of vector. Transactions may be evicted from the oldest one
+//! (see `evict`) - packed records are ordered by ID and IDs of full entries are kept in ordered set, so the oldest
+//! transaction is taken from the front of either of them. Transactions under dispute are never evicted.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Bound;
+
+use serde::{Serialize, Deserialize, Serializer, Deserializer};
+
+use crate::{
+    TMoney, TTrxID, TTimestamp,
+    accounts::TrxEntry,
+    currency::Currency,
+};
+
+/// Transaction time of packed record without timestamp.
+const NO_TIMESTAMP: TTimestamp = TTimestamp::MIN;
+
+/// Not disputed transaction without linked leg.
+#[derive(Clone, Copy, Debug)]
+struct Packed {
+    /// Transaction time (`NO_TIMESTAMP` if not given)
+    timestamp: TTimestamp,
+    amount: TMoney,
+    tx: TTrxID,
+    currency: Option<Currency>,
+}
+
+/// Size of packed record is part of documented layout.
+const _: () = assert!(std::mem::size_of::<Packed>() == 32);
+
+impl Packed {
+    /// Packs entry of transaction `tx`, returns `None` if entry does not fit packed record.
+    fn new(tx: TTrxID, entry: &TrxEntry) -> Option<Self> {
+        if !entry.disputed.is_zero() || !entry.charged_back.is_zero() || entry.linked.is_some()
+            || entry.timestamp == Some(NO_TIMESTAMP) {
+            return None
+        }
+        Some(Self {tx, amount: entry.amount, timestamp: entry.timestamp.unwrap_or(NO_TIMESTAMP), currency: entry.currency})
+    }
+
+    fn entry(&self) -> TrxEntry {
+        let timestamp = Some(self.timestamp).filter(|timestamp| *timestamp != NO_TIMESTAMP);
+        TrxEntry {currency: self.currency, ..TrxEntry::new(self.amount, timestamp)}
+    }
+}
+
+/// Transactions of account indexed by transaction ID.
+#[derive(Clone, Debug, Default)]
+pub struct TrxStore {
+    /// Packed records ordered by transaction ID
+    packed: VecDeque<Packed>,
+    /// Transactions which do not fit packed record (or did not fit it once)
+    full: HashMap<TTrxID, TrxEntry>,
+    /// IDs of `full` transactions in order
+    full_ids: BTreeSet<TTrxID>,
+}
+
+impl TrxStore {
+    /// Returns number of stored transactions.
+    pub fn len(&self) -> usize {
+        self.packed.len() + self.full.len()
+    }
+
+    /// Returns `true` if there are no stored transactions.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if transaction `tx` is stored.
+    pub fn contains(&self, tx: TTrxID) -> bool {
+        self.full.contains_key(&tx) || self.position(tx).is_ok()
+    }
+
+    /// Returns (copy of) transaction `tx`.
+    pub fn get(&self, tx: TTrxID) -> Option<TrxEntry> {
+        match self.full.get(&tx) {
+            Some(entry) => Some(entry.clone()),
+            None => self.position(tx).ok().map(|pos| self.packed[pos].entry()),
+        }
+    }
+
+    /// Returns transaction `tx` for change - packed record is moved to full entries first (and is never packed again).
+    pub fn get_mut(&mut self, tx: TTrxID) -> Option<&mut TrxEntry> {
+        if !self.full.contains_key(&tx) {
+            let pos = self.position(tx).ok()?;
+            let packed = self.packed.remove(pos)?;
+            self.full.insert(tx, packed.entry());
+            self.full_ids.insert(tx);
+        }
+        self.full.get_mut(&tx)
+    }
+
+    /// Stores transaction `tx` (replaces already stored one).
+    pub fn insert(&mut self, tx: TTrxID, entry: TrxEntry) {
+        match Packed::new(tx, &entry) {
+            Some(packed) if !self.full.contains_key(&tx) => match self.position(tx) {
+                Ok(pos) => self.packed[pos] = packed,
+                Err(pos) if pos == self.packed.len() => self.packed.push_back(packed),
+                Err(pos) => self.packed.insert(pos, packed),
+            },
+            _ => {
+                if let Ok(pos) = self.position(tx) {
+                    self.packed.remove(pos);
+                }
+                self.full.insert(tx, entry);
+                self.full_ids.insert(tx);
+            },
+        }
+    }
+
+    /// Evicts the oldest (by ID) transactions which are not under dispute, while there are more than `keep` 
+    /// transactions stored or the oldest one is older than `cutoff` time (transactions without timestamp are not aged).
+    /// Returns evicted transactions in order of IDs.
+    pub fn evict(&mut self, keep: usize, cutoff: Option<TTimestamp>) -> Vec<(TTrxID, TrxEntry)> {
+        let mut evicted = Vec::new();
+        // full entries before this bound are under dispute, so they are not scanned again
+        let mut full_from = Bound::Unbounded;
+        loop {
+            let oldest_full = self.full_ids.range((full_from, Bound::Unbounded))
+                .find(|tx| !self.full[tx].is_disputed())
+                .copied();
+            full_from = match oldest_full {
+                Some(tx) => Bound::Included(tx),
+                None => self.full_ids.last().map_or(Bound::Unbounded, |tx| Bound::Excluded(*tx)),
+            };
+            let (tx, entry) = match (self.packed.front(), oldest_full) {
+                (Some(packed), Some(tx)) if packed.tx < tx => (packed.tx, packed.entry()),
+                (_, Some(tx)) => (tx, self.full[&tx].clone()),
+                (Some(packed), None) => (packed.tx, packed.entry()),
+                (None, None) => break,
+            };
+            let expired = cutoff.is_some_and(|cutoff| entry.timestamp.is_some_and(|timestamp| timestamp < cutoff));
+            if self.len() <= keep && !expired {
+                break;
+            }
+            if self.full.remove(&tx).is_some() {
+                self.full_ids.remove(&tx);
+                full_from = Bound::Excluded(tx);
+            } else {
+                self.packed.pop_front();
+            }
+            evicted.push((tx, entry));
+        }
+        evicted
+    }
+
+    /// Iterates over (copies of) stored transactions, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (TTrxID, TrxEntry)> + '_ {
+        self.packed.iter()
+            .map(|packed| (packed.tx, packed.entry()))
+            .chain(self.full.iter().map(|(tx, entry)| (*tx, entry.clone())))
+    }
+
+    /// Iterates over transactions which are (at least partially) under dispute.
+    pub fn disputed(&self) -> impl Iterator<Item = (TTrxID, &TrxEntry)> {
+        // packed records are never disputed
+        self.full.iter().filter(|(_, entry)| entry.is_disputed()).map(|(tx, entry)| (*tx, entry))
+    }
+
+    /// Returns the greatest stored transaction ID.
+    pub fn last_id(&self) -> Option<TTrxID> {
+        self.packed.back().map(|packed| packed.tx).max(self.full_ids.last().copied())
+    }
+
+    fn position(&self, tx: TTrxID) -> Result<usize, usize> {
+        match self.packed.back() {
+            // fast path for transactions coming in order of IDs
+            Some(last) if last.tx < tx => Err(self.packed.len()),
+            _ => self.packed.binary_search_by_key(&tx, |packed| packed.tx),
+        }
+    }
+}
+
+impl PartialEq for TrxStore {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(tx, entry)| other.get(tx).as_ref() == Some(&entry))
+    }
+}
+
+impl FromIterator<(TTrxID, TrxEntry)> for TrxStore {
+    fn from_iter<I: IntoIterator<Item = (TTrxID, TrxEntry)>>(iter: I) -> Self {
+        let mut entries: Vec<_> = iter.into_iter().collect();
+        entries.sort_unstable_by_key(|(tx, _)| *tx);
+        let mut store = Self::default();
+        for (tx, entry) in entries {
+            store.insert(tx, entry);
+        }
+        store
+    }
+}
+
+/// Serialized as map of transaction ID to `TrxEntry` (the same as former `HashMap`).
+impl Serialize for TrxStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for TrxStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(HashMap::<TTrxID, TrxEntry>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use super::*;
+
+    #[test]
+    fn packed_and_full() {
+        let mut store = TrxStore::default();
+        for tx in [1, 2, 5, 3] {
+            store.insert(tx, TrxEntry::new(dec!(1.5), Some(1_600_000_000_000)));
+        }
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.last_id(), Some(5));
+        assert!(store.contains(3) && !store.contains(4));
+        store.get_mut(2).expect("stored transaction").disputed = dec!(1.5);
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(2), Some(TrxEntry {disputed: dec!(1.5), ..TrxEntry::new(dec!(1.5), Some(1_600_000_000_000))}));
+        assert_eq!(store.disputed().map(|(tx, _)| tx).collect::<Vec<_>>(), [2]);
+        let mut ids: Vec<_> = store.iter().map(|(tx, _)| tx).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [1, 2, 3, 5]);
+        store.insert(2, TrxEntry::new(dec!(2), None));
+        assert_eq!(store.get(2), Some(TrxEntry::new(dec!(2), None)));
+        assert_eq!(store.len(), 4);
+    }
+
+    #[test]
+    fn eviction() {
+        let mut store = TrxStore::default();
+        for tx in 1..=5 {
+            store.insert(tx, TrxEntry::new(dec!(1), Some(tx as TTimestamp * 1000)));
+        }
+        store.get_mut(1).expect("stored transaction").disputed = dec!(1);
+        store.get_mut(3).expect("stored transaction").disputed = dec!(0); // resolved
+        let evicted: Vec<_> = store.evict(4, None).into_iter().map(|(tx, _)| tx).collect();
+        assert_eq!(evicted, [2]);   // disputed transaction 1 is kept
+        let evicted: Vec<_> = store.evict(usize::MAX, Some(4500)).into_iter().map(|(tx, _)| tx).collect();
+        assert_eq!(evicted, [3, 4]);
+        assert_eq!(store.len(), 2);
+        assert!(store.contains(1) && store.contains(5));
+    }
+
+    #[test]
+    fn equal_regardless_of_storage() {
+        let mut packed = TrxStore::default();
+        packed.insert(1, TrxEntry::new(dec!(1), None));
+        let mut full = TrxStore::default();
+        full.insert(1, TrxEntry {disputed: dec!(1), ..TrxEntry::new(dec!(1), None)});
+        assert_ne!(packed, full);
+        full.get_mut(1).expect("stored transaction").disputed = dec!(0);
+        assert_eq!(packed, full);
+        let json = serde_json::to_string(&full).expect("serialized");
+        assert_eq!(serde_json::from_str::<TrxStore>(&json).expect("deserialized"), packed);
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{Result, Context, bail};
+
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
+use crate::accounts::{AdminEntry, AdminKind};
+use super::*;
+
+/// Represents Adjust transaction - signed administrative correction of `available` balance, 
+/// accepted only from privileged input.
+pub struct Adjust {
+    client: TClientId,
+    tx: TTrxID,
+    amount: TMoney,
+    reason: String,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
+}
+
+impl TryFrom<TransactionRec> for Adjust {
+    type Error = anyhow::Error;
+    fn try_from(value: TransactionRec) -> std::result::Result<Self, Self::Error> {
+        if value.ttype != TransactionRecType::Adjust {
+            bail!("Transaction ID {} - Incompatible type expected Adjust", value.tx)
+        }
+        let Some(amount) = value.amount else {
+            bail!("Transaction ID {} - Amount is missing in Adjust transaction", value.tx)
+        };
+        let reason = match value.reason {
+            Some(reason) if !reason.is_empty() => reason,
+            _ => bail!("Transaction ID {} - Reason is missing in Adjust transaction", value.tx),
+        };
+        Ok(Self {
+            client: value.client,
+            tx: value.tx,
+            reason,
+            timestamp: value.timestamp,
+            currency: value.currency,
+            amount: amount.try_into()
+                .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount))?
+        })
+    }
+}
+
+impl TransactionInt for Adjust {
+    fn id(&self) -> TTrxID {self.tx}
+
+    fn client_id(&self) -> TClientId {self.client}
+
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {Some(self.amount)}
+
+    fn validate(&self) -> TransactionValid {
+        if self.amount.is_zero() {
+            TransactionValid::Warn("Amount == 0 in Adjust transaction")
+        } else {
+            TransactionValid::Ok
+        }
+    }
+
+    /// Performs Adjust transaction. It is performed also on locked accounts.
+    /// - if account is not registered - reject.
+    /// - if there is already registered administrative transaction with the same ID - reject.
+    /// - if account `available` property would overflow or become negative - reject.
+    /// - otherwise changes account `available` property (in its currency) of given (signed) `amount` 
+    ///   and records operation in account administrative history.
+    ///   Adjustments are not stored among transactions, so they can not be disputed.
+    ///
+    /// Adjusted amount is moved from adjustment account to client's available funds.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
+        match accounts.get_mut(&self.client) {
+            Some(acct) => {
+                if acct.admin.iter().any(|entry| entry.tx == self.tx && entry.kind != AdminKind::Fee) {
+                    bail!("Adjust transaction failed - duplicated transaction ID")
+                }
+                let balance = acct.balance(self.currency).checked_change(self.amount, TMoney::ZERO)
+                    .context("Adjust transaction failed - balance overflow")?;
+                if balance.available.is_sign_negative() && !balance.available.is_zero() {
+                    bail!("Adjust transaction failed - insufficient funds")
+                }
+                *acct.funds_mut(self.currency).available = balance.available;
+                acct.admin.push(AdminEntry {
+                    tx: self.tx,
+                    kind: AdminKind::Adjust,
+                    amount: self.amount,
+                    reason: Some(self.reason.clone()),
+                    timestamp: self.timestamp,
+                    currency: self.currency,
+                });
+                journal.transfer(rec_no, self.tx, self.currency, LedgerAccount::Adjustment, LedgerAccount::Available(self.client), self.amount);
+                Ok(())
+            }
+            None => bail!("Adjust transaction failed - client unknown")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use crate::transactions::TransactionInt;
+    use super::*;
+    use super::super::tests::create_accounts;
+
+    fn rec(amount: Option<f64>, reason: Option<&str>) -> TransactionRec {
+        TransactionRec {
+            ttype: TransactionRecType::Adjust,
+            client: 1,
+            tx: 100,
+            amount,
+            timestamp: None,
+            reason: reason.map(str::to_string),
+            currency: None,
+            to_currency: None,
+        }
+    }
+
+    #[test]
+    fn on_locked() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
+        let trx = Adjust::try_from(rec(Some(-0.5), Some("fee refund reversal"))).expect("valid Adjust record");
+        assert!(trx.commit(&mut accounts).is_ok());
+        let acct = accounts.get(&1).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(1.5));
+        assert!(acct.locked);
+        assert_eq!(acct.admin.len(), 1);
+        assert_eq!(acct.admin[0].amount, dec!(-0.5));
+        assert!(acct.transactions.is_empty());
+    }
+
+    #[test]
+    fn missing_reason() {
+        assert!(Adjust::try_from(rec(Some(1.0), None)).is_err());
+        assert!(Adjust::try_from(rec(Some(1.0), Some(""))).is_err());
+        assert!(Adjust::try_from(rec(None, Some("correction"))).is_err());
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let trx = Adjust::try_from(rec(Some(-2.5), Some("correction"))).expect("valid Adjust record");
+        assert!(trx.commit(&mut accounts).is_err());
+        assert_eq!(accounts.get(&1).expect("client 1 in test accounts").available, dec!(2.0));
+    }
+
+    #[test]
+    fn duplicated_id() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let trx = Adjust::try_from(rec(Some(1.0), Some("correction"))).expect("valid Adjust record");
+        assert!(trx.commit(&mut accounts).is_ok());
+        assert!(trx.commit(&mut accounts).is_err());
+        assert_eq!(accounts.get(&1).expect("client 1 in test accounts").available, dec!(3.0));
+    }
+}
@@ -1,12 +1,23 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, Context, bail};
 
-use crate::{TClientId, TTrxID};
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
 use super::*;
 
 /// Represents Chargeback transaction.
 pub struct Chargeback {
     client: TClientId,
     tx: TTrxID,
+    /// Charged back part of referenced transaction amount (whole disputed amount if not given).
+    amount: Option<TMoney>,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
+}
+
+impl Chargeback {
+    /// Creates chargeback of transaction `tx` (`amount` - charged back part) without currency and timestamp.
+    pub fn new(client:TClientId, tx:TTrxID, amount:Option<TMoney>) -> Self {
+        Self {client, tx, amount, timestamp: None, currency: None}
+    }
 }
 
 impl TryFrom<TransactionRec> for Chargeback {
@@ -15,7 +26,15 @@ impl TryFrom<TransactionRec> for Chargeback {
         if value.ttype != TransactionRecType::Chargeback {
             bail!("Transaction ID {} - Incompatible type expected Chargeback", value.tx)
         } else {
-            Ok(Self {client: value.client, tx: value.tx})
+            Ok(Self {
+                client: value.client,
+                tx: value.tx,
+                timestamp: value.timestamp,
+                currency: value.currency,
+                amount: value.amount.map(|amount| amount.try_into()
+                    .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount)))
+                    .transpose()?
+            })
         }
     }
 }
@@ -25,33 +44,66 @@ impl TransactionInt for Chargeback {
 
     fn client_id(&self) -> TClientId {self.client}
 
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {self.amount}
+
     fn validate(&self) -> TransactionValid {
-        TransactionValid::Ok
+        match self.amount {
+            Some(amount) if amount.is_sign_negative() || amount.is_zero() => TransactionValid::Invalid("Amount <= 0 in Chargeback transaction"),
+            _ => TransactionValid::Ok,
+        }
     }
 
     /// Performs Chargeback transaction.
     /// - if account is not registered - reject.
     /// - if account is locked - reject.
-    /// - if referenced transaction is not registered for given client - reject.
+    /// - if referenced transaction is not registered for given client or currency is given and differs from 
+    ///   its currency - reject.
     /// - if referenced transaction is not 'on dispute' state - reject.
-    /// - otherwise releases referenced transaction from 'on dispute' state, 
-    ///   releases money reserved on case of Resolve transaction and locks account.
-    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+    /// - if given `amount` exceeds disputed part of referenced transaction - reject.
+    /// - if referenced transaction is exchange and `amount` is given - reject.
+    /// - otherwise releases given `amount` (or whole disputed part) of referenced transaction from 'on dispute' state, 
+    ///   releases money reserved on case of Resolve transaction (in currency of referenced transaction, for exchange
+    ///   also in currency of its linked leg) and locks account.
+    ///
+    /// Charged back money is moved from client's held funds to chargeback loss (it is returned to card issuer).
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
         match accounts.get_mut(&self.client) {
             Some(acct) => {
                 if acct.locked {
                     bail!("Chargeback transaction failed - account locked")
                 }
-                match acct.transactions.get_mut(&self.tx) {
-                    Some((dispute, amount)) if *dispute => {
-                        acct.held -= *amount;
-                        *dispute = false;
-                        acct.locked = true;
-                        Ok(())
+                let currency = referenced_currency(acct, self.tx, self.currency, "Chargeback")?;
+                let linked = linked_leg(acct, self.tx, self.amount, "Chargeback")?;
+                if let Some(leg) = linked {
+                    acct.balance(leg.currency).checked_change(TMoney::ZERO, -leg.amount)
+                        .context("Chargeback transaction failed - balance overflow")?;
+                }
+                let funds = acct.funds_mut(currency);
+                let part = match funds.transactions.get_mut(self.tx) {
+                    Some(trx) if trx.is_disputed() => match referenced_part(self.amount, trx.disputed) {
+                        Some(part) => {
+                            change_balance(funds.available, funds.held, TMoney::ZERO, -part)
+                                .context("Chargeback transaction failed - balance overflow")?;
+                            trx.disputed -= part;
+                            trx.charged_back += part;
+                            part
+                        },
+                        None => bail!("Chargeback transaction failed - amount exceeds disputed part of transaction"),
                     },
                     Some(_) => bail!("Chargeback transaction failed - not disputed transaction"),
                     None => bail!("Chargeback transaction failed - reference transaction ID not found for given client")
+                };
+                journal.transfer(rec_no, self.tx, currency, LedgerAccount::Held(self.client), LedgerAccount::ChargebackLoss, part);
+                if let Some(leg) = linked {
+                    let funds = acct.funds_mut(leg.currency);
+                    change_balance(funds.available, funds.held, TMoney::ZERO, -leg.amount)
+                        .context("Chargeback transaction failed - balance overflow")?;
+                    journal.transfer(rec_no, self.tx, leg.currency, LedgerAccount::Held(self.client), LedgerAccount::ChargebackLoss, leg.amount);
                 }
+                acct.locked = true;
+                Ok(())
             }
             None => bail!("Chargeback transaction failed - client unknown")
         }
@@ -65,11 +117,18 @@ mod tests {
     use super::*;
     use super::super::tests::create_accounts;
 
+    impl Chargeback {
+        #[allow(dead_code)]
+        pub fn test(client:TClientId, tx:TTrxID) -> Self {
+            Self {client, tx, amount: None, timestamp: None, currency: None}
+        }
+    }
+
     #[test]
     fn on_locked() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
         accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
-        let trx = Chargeback {client: 1, tx: 1};
+        let trx = Chargeback {client: 1, tx: 1, amount: None, timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_err());
     }
     
@@ -79,7 +138,7 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
@@ -89,10 +148,14 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx2.commit(&mut accounts).is_ok());
-        let trx3 = Chargeback {client, tx};
+        let trx3 = Chargeback {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -107,7 +170,7 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = withdrawal::Withdrawal::test(client, tx, amount);
+        let trx1 = withdrawal::Withdrawal::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
@@ -117,10 +180,14 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx2.commit(&mut accounts).is_ok());
-        let trx3 = Chargeback {client, tx};
+        let trx3 = Chargeback {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -132,8 +199,8 @@ mod tests {
     #[test]
     fn unknown_client() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Chargeback {client: 10, tx: 1};
-        assert!(accounts.get(&trx.client).is_none());
+        let trx = Chargeback {client: 10, tx: 1, amount: None, timestamp: None, currency: None};
+        assert!(!accounts.contains_key(&trx.client));
         assert!(trx.commit(&mut accounts).is_err());
     }
     
@@ -143,12 +210,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx2 = Chargeback {client, tx: tx + 1};
+        let trx2 = Chargeback {client, tx: tx + 1, amount: None, timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_err());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -163,12 +230,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = withdrawal::Withdrawal::test(client, tx, amount);
+        let trx1 = withdrawal::Withdrawal::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx3 = Chargeback {client, tx};
+        let trx3 = Chargeback {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_err());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -176,4 +243,25 @@ mod tests {
         assert_eq!(old_total, new_total);
         assert!(! accounts.get(&client).expect("client 1 in test accounts").locked);
     }
+    
+    #[test]
+    fn partial_chargeback() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let client = 1;
+        let tx = 1;
+        let trx1 = deposit::Deposit::new(client, tx, dec!(1.5));
+        assert!(trx1.commit(&mut accounts).is_ok());
+        let trx2 = dispute::Dispute::test(client, tx);
+        assert!(trx2.commit(&mut accounts).is_ok());
+
+        let trx3 = Chargeback {client, tx, amount: Some(dec!(2.0)), timestamp: None, currency: None};
+        assert!(trx3.commit(&mut accounts).is_err()); // exceeds disputed part
+        let trx4 = Chargeback {client, tx, amount: Some(dec!(1.0)), timestamp: None, currency: None};
+        assert!(trx4.commit(&mut accounts).is_ok());
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(2.0));
+        assert_eq!(acct.held, dec!(0.5));
+        assert_eq!(acct.transactions.get(tx).expect("stored transaction").charged_back, dec!(1.0));
+        assert!(acct.locked);
+    }
 }
@@ -1,8 +1,6 @@
-use std::collections::hash_map::Entry;
-
 use anyhow::{Result, Context, bail};
 
-use crate::{TClientId, TMoney, TTrxID};
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
 use super::*;
 
 /// Represents Deposit transaction.
@@ -10,6 +8,15 @@ pub struct Deposit {
     client: TClientId,
     tx: TTrxID,
     amount: TMoney,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
+}
+
+impl Deposit {
+    /// Creates deposit in default currency without timestamp.
+    pub fn new(client:TClientId, tx:TTrxID, amount:TMoney) -> Self {
+        Self {client, tx, amount, timestamp: None, currency: None}
+    }
 }
 
 impl TryFrom<TransactionRec> for Deposit {
@@ -22,6 +29,8 @@ impl TryFrom<TransactionRec> for Deposit {
             Ok(Self {
                 client: value.client, 
                 tx: value.tx, 
+                timestamp: value.timestamp,
+                currency: value.currency,
                 amount: amount.try_into()
                     .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount))?
             })
@@ -36,6 +45,10 @@ impl TransactionInt for Deposit {
 
     fn client_id(&self) -> TClientId {self.client}
 
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {Some(self.amount)}
+
     fn validate(&self) -> TransactionValid {
         if self.amount.is_sign_positive() {
             TransactionValid::Ok
@@ -46,31 +59,40 @@ impl TransactionInt for Deposit {
         }
     }
 
-    /// Performs Deposit transaction.
+    /// Performs Deposit transaction (in its currency).
     /// - if account is not registered - register it with passed initial balance (`available` property).
     /// - if account is locked - reject.
     /// - if there is already registered transaction with the same ID - reject.
+    /// - if transaction with the same or greater ID was evicted by retention policy - reject.
+    /// - if account `available` property would overflow - reject.
     /// - otherwise increase account `available` property of given `amount` and stores transaction amount.
-    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+    ///
+    /// Deposited money is moved from settlement to client's available funds.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
+        let entry = TrxEntry {currency: self.currency, ..TrxEntry::new(self.amount, self.timestamp)};
         match accounts.get_mut(&self.client) {
             Some(acct) => {
                 if acct.locked {
                     bail!("Deposit transaction failed - account locked")
-                } else if let Entry::Vacant(ent) = acct.transactions.entry(self.tx) {
-                    acct.available += self.amount;
-                    ent.insert((false, self.amount));
-                    Ok(())
+                } else if acct.evicted.covers(self.tx) {
+                    bail!("Deposit transaction failed - transaction ID not greater than evicted ones")
+                } else if !acct.transactions.contains(self.tx) {
+                    let funds = acct.funds_mut(self.currency);
+                    change_balance(funds.available, funds.held, self.amount, TMoney::ZERO)
+                        .context("Deposit transaction failed - balance overflow")?;
+                    funds.transactions.insert(self.tx, entry);
                 } else {
                     bail!("Deposit transaction failed - duplicated transaction ID")
                 }
             }
             None => {
-                accounts.entry(self.client)
-                    .or_insert_with(|| AccountState::with_balance(self.amount))
-                    .transactions.insert(self.tx, (false, self.amount));
-                Ok(())
+                let funds = accounts.entry(self.client).or_default().funds_mut(self.currency);
+                *funds.available = self.amount;
+                funds.transactions.insert(self.tx, entry);
             }
         }
+        journal.transfer(rec_no, self.tx, self.currency, LedgerAccount::Settlement, LedgerAccount::Available(self.client), self.amount);
+        Ok(())
     }
 }
 
@@ -82,24 +104,18 @@ mod tests {
     use super::*;
     use super::super::tests::create_accounts;
 
-    impl Deposit {
-        pub fn test(client:TClientId, tx:TTrxID, amount:TMoney) -> Self {
-            Self {client, tx, amount}
-        }
-    }
-
     #[test]
     fn on_locked() {
         let mut accounts = create_accounts(&[dec!(0.0)]);
         accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
-        let trx = Deposit {client: 1, tx: 1, amount: dec!(1.0)};
+        let trx = Deposit {client: 1, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_err());
     }
     
     #[test]
     fn on_normal() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Deposit {client: 1, tx: 1, amount: dec!(1.0)};
+        let trx = Deposit {client: 1, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
         let old_balance = accounts.get(&trx.client).expect("client 1 in test accounts").available;
         assert!(trx.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&trx.client).expect("client 1 in test accounts").available;
@@ -109,8 +125,8 @@ mod tests {
     #[test]
     fn new_client() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Deposit {client: 10, tx: 1, amount: dec!(1.0)};
-        assert!(accounts.get(&trx.client).is_none());
+        let trx = Deposit {client: 10, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
+        assert!(!accounts.contains_key(&trx.client));
         assert!(trx.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&trx.client).expect("new client in test accounts").available;
         assert_eq!(trx.amount, new_balance);
@@ -119,11 +135,19 @@ mod tests {
     #[test]
     fn duplicated_tx_id() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx1 = Deposit {client: 1, tx: 1, amount: dec!(0.1)};
+        let trx1 = Deposit {client: 1, tx: 1, amount: dec!(0.1), timestamp: None, currency: None};
         assert!(trx1.commit(&mut accounts).is_ok());
-        let trx2 = Deposit {client: 1, tx: 1, amount: dec!(0.1)};
+        let trx2 = Deposit {client: 1, tx: 1, amount: dec!(0.1), timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_err()); // duplicated id
-        let trx3 = Deposit {client: 1, tx: 2, amount: dec!(0.1)};
+        let trx3 = Deposit {client: 1, tx: 2, amount: dec!(0.1), timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
     }
+
+    #[test]
+    fn overflow() {
+        let mut accounts = create_accounts(&[TMoney::MAX]);
+        let trx = Deposit {client: 1, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
+        assert!(trx.commit(&mut accounts).is_err());
+        assert_eq!(accounts.get(&1).expect("client 1 in test accounts").available, TMoney::MAX);
+    }
 }
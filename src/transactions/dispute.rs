@@ -1,12 +1,29 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, Context, bail};
 
-use crate::{TClientId, TTrxID};
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
 use super::*;
 
 /// Represents Dispute transaction.
 pub struct Dispute {
     client: TClientId,
     tx: TTrxID,
+    /// Disputed part of referenced transaction amount (whole not yet disputed amount if not given).
+    amount: Option<TMoney>,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
+}
+
+impl Dispute {
+    /// Creates dispute of transaction `tx` (`amount` - disputed part) without currency and timestamp.
+    pub fn new(client:TClientId, tx:TTrxID, amount:Option<TMoney>) -> Self {
+        Self {client, tx, amount, timestamp: None, currency: None}
+    }
+
+    /// Returns true if dispute of whole transaction repeats dispute of already fully disputed `trx`
+    /// (such dispute is accepted without changing balances).
+    pub fn repeats(&self, trx: &TrxEntry) -> bool {
+        self.amount.is_none() && trx.is_disputed() && trx.disputable().is_zero()
+    }
 }
 
 impl TryFrom<TransactionRec> for Dispute {
@@ -15,7 +32,15 @@ impl TryFrom<TransactionRec> for Dispute {
         if value.ttype != TransactionRecType::Dispute {
             bail!("Transaction ID {} - Incompatible type expected Dispute", value.tx)
         } else {
-            Ok(Self {client: value.client, tx: value.tx})
+            Ok(Self {
+                client: value.client,
+                tx: value.tx,
+                timestamp: value.timestamp,
+                currency: value.currency,
+                amount: value.amount.map(|amount| amount.try_into()
+                    .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount)))
+                    .transpose()?
+            })
         }
     }
 }
@@ -25,36 +50,96 @@ impl TransactionInt for Dispute {
 
     fn client_id(&self) -> TClientId {self.client}
 
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {self.amount}
+
     fn validate(&self) -> TransactionValid {
-        TransactionValid::Ok
+        match self.amount {
+            Some(amount) if amount.is_sign_negative() || amount.is_zero() => TransactionValid::Invalid("Amount <= 0 in Dispute transaction"),
+            _ => TransactionValid::Ok,
+        }
     }
 
     /// Performs Dispute transaction.
     /// - if account is not registered - reject.
     /// - if account is locked - reject.
-    /// - if referenced transaction is not registered for given client - reject.
-    /// - if referenced transaction is already in 'on dispute' state logs warning but not reject transaction.
-    /// - otherwise puts referenced transaction in 'on dispute' state 
-    ///   and decreases account `available` property of given `amount`.
-    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+    /// - if referenced transaction is not registered for given client or currency is given and differs from 
+    ///   its currency - reject.
+    /// - if whole referenced transaction is already in 'on dispute' state logs warning but not reject transaction.
+    /// - if nothing is left to dispute (referenced transaction is charged back) - reject.
+    /// - if given `amount` exceeds not yet disputed (nor charged back) part of referenced transaction - reject.
+    /// - if referenced transaction is exchange and `amount` is given - reject.
+    /// - otherwise puts given `amount` (or whole not yet disputed part) of referenced transaction in 'on dispute' state 
+    ///   and moves it from account `available` to `held` property (in currency of referenced transaction), 
+    ///   linked leg of exchange is moved the same way (in its currency), where depending on dispute `policy`:
+    ///   - `deposits-only` - if referenced transaction is withdrawal (or exchange) - reject.
+    ///   - `reject` - if account `available` property would become negative - reject.
+    ///   - `cap` - held part is limited to account `available` property, if nothing is available - reject
+    ///     (linked leg can not be capped - if account `available` property in its currency would become negative - reject).
+    ///
+    /// Disputed money is moved from client's available funds to held ones.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
         match accounts.get_mut(&self.client) {
             Some(acct) => {
                 if acct.locked {
                     bail!("Dispute transaction failed - account locked")
                 }
-                match acct.transactions.get_mut(&self.tx) {
-                    Some((dispute, amount)) if !*dispute => {
-                        acct.available -= *amount;
-                        acct.held += *amount;
-                        *dispute = true;
-                        Ok(())
-                    },
-                    Some(_) => {
-                        eprintln!("Transaction ID = {}: warning - repeated Dispute", self.tx);
-                        Ok(())
+                let currency = referenced_currency(acct, self.tx, self.currency, "Dispute")?;
+                let linked = linked_leg(acct, self.tx, self.amount, "Dispute")?;
+                let linked_available = linked.map(|leg| acct.balance(leg.currency).available);
+                if let Some(leg) = linked {
+                    acct.balance(leg.currency).checked_change(-leg.amount, leg.amount)
+                        .context("Dispute transaction failed - balance overflow")?;
+                }
+                let funds = acct.funds_mut(currency);
+                let part = match funds.transactions.get_mut(self.tx) {
+                    Some(trx) if self.repeats(trx) => return Ok(()),
+                    Some(trx) if trx.disputable().is_zero() => 
+                        bail!("Dispute transaction failed - nothing left to dispute in transaction"),
+                    Some(trx) => match referenced_part(self.amount, trx.disputable()) {
+                        Some(part) => {
+                            let part = match policy.dispute {
+                                DisputePolicy::AllowNegative => part,
+                                DisputePolicy::DepositsOnly if trx.amount.is_sign_negative() && !trx.amount.is_zero() => 
+                                    bail!("Dispute transaction failed - only deposits may be disputed"),
+                                DisputePolicy::DepositsOnly => part,
+                                // dispute of withdrawal would release funds before dispute is settled
+                                DisputePolicy::Reject | DisputePolicy::Cap if part.is_sign_negative() && !part.is_zero() && linked.is_none() =>
+                                    bail!("Dispute transaction failed - dispute of withdrawal would increase available funds"),
+                                DisputePolicy::Reject if part.is_sign_positive() && part > *funds.available => 
+                                    bail!("Dispute transaction failed - insufficient available funds"),
+                                DisputePolicy::Reject => part,
+                                DisputePolicy::Cap if part.is_sign_positive() && part > *funds.available => {
+                                    if funds.available.is_sign_negative() || funds.available.is_zero() {
+                                        bail!("Dispute transaction failed - no available funds to hold")
+                                    }
+                                    *funds.available
+                                },
+                                DisputePolicy::Cap => part,
+                            };
+                            if let (Some(leg), Some(available)) = (linked, linked_available) {
+                                if matches!(policy.dispute, DisputePolicy::Reject | DisputePolicy::Cap) && leg.amount > available {
+                                    bail!("Dispute transaction failed - insufficient available funds to reverse exchange")
+                                }
+                            }
+                            change_balance(funds.available, funds.held, -part, part)
+                                .context("Dispute transaction failed - balance overflow")?;
+                            trx.disputed += part;
+                            part
+                        },
+                        None => bail!("Dispute transaction failed - amount exceeds not disputed part of transaction"),
                     },
                     None => bail!("Dispute transaction failed - reference transaction ID not found for given client")
+                };
+                journal.transfer(rec_no, self.tx, currency, LedgerAccount::Available(self.client), LedgerAccount::Held(self.client), part);
+                if let Some(leg) = linked.filter(|_| !part.is_zero()) {
+                    let funds = acct.funds_mut(leg.currency);
+                    change_balance(funds.available, funds.held, -leg.amount, leg.amount)
+                        .context("Dispute transaction failed - balance overflow")?;
+                    journal.transfer(rec_no, self.tx, leg.currency, LedgerAccount::Available(self.client), LedgerAccount::Held(self.client), leg.amount);
                 }
+                Ok(())
             }
             None => bail!("Dispute transaction failed - client unknown")
         }
@@ -74,7 +159,7 @@ mod tests {
     impl Dispute {
         #[allow(dead_code)]
         pub fn test(client:TClientId, tx:TTrxID) -> Self {
-            Self {client, tx}
+            Self {client, tx, amount: None, timestamp: None, currency: None}
         }
     }
 
@@ -82,7 +167,7 @@ mod tests {
     fn on_locked() {
         let mut accounts = create_accounts(&[dec!(0.0)]);
         accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
-        let trx = Dispute {client: 1, tx: 1};
+        let trx = Dispute {client: 1, tx: 1, amount: None, timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_err());
     }
     
@@ -92,12 +177,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx2 = Dispute {client, tx};
+        let trx2 = Dispute {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -111,12 +196,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = withdrawal::Withdrawal::test(client, tx, amount);
+        let trx1 = withdrawal::Withdrawal::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx2 = Dispute {client, tx};
+        let trx2 = Dispute {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -127,8 +212,8 @@ mod tests {
     #[test]
     fn unknown_client() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Dispute {client: 10, tx: 1};
-        assert!(accounts.get(&trx.client).is_none());
+        let trx = Dispute {client: 10, tx: 1, amount: None, timestamp: None, currency: None};
+        assert!(!accounts.contains_key(&trx.client));
         assert!(trx.commit(&mut accounts).is_err());
     }
     
@@ -138,12 +223,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx2 = Dispute {client, tx: tx + 1};
+        let trx2 = Dispute {client, tx: tx + 1, amount: None, timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_err());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -157,14 +242,14 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx2 = Dispute {client, tx};
+        let trx2 = Dispute {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_ok());
-        let trx3 = Dispute {client, tx};
+        let trx3 = Dispute {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -172,16 +257,179 @@ mod tests {
         assert_eq!(old_total, new_total);
     }
     
+    #[test]
+    fn charged_back() {
+        let mut accounts = create_accounts(&[dec!(0.0)]);
+        let client = 1;
+        assert!(deposit::Deposit::new(client, 1, dec!(1.5)).commit(&mut accounts).is_ok());
+        assert!(Dispute::test(client, 1).commit(&mut accounts).is_ok());
+        assert!(chargeback::Chargeback::test(client, 1).commit(&mut accounts).is_ok());
+        accounts.get_mut(&client).expect("client 1 in test accounts").locked = false;
+        let old = accounts.get(&client).cloned();
+        let err = Dispute::test(client, 1).commit(&mut accounts).expect_err("nothing left to dispute");
+        assert!(err.to_string().contains("nothing left to dispute"), "{}", err);
+        assert_eq!(accounts.get(&client), old.as_ref());
+    }
+
+    #[test]
+    fn held_overflow() {
+        let mut accounts = create_accounts(&[dec!(0.0)]);
+        let client = 1;
+        let amount = dec!(5e28);
+        assert!(deposit::Deposit::new(client, 1, amount).commit(&mut accounts).is_ok());
+        assert!(withdrawal::Withdrawal::new(client, 2, amount).commit(&mut accounts).is_ok());
+        assert!(deposit::Deposit::new(client, 3, amount).commit(&mut accounts).is_ok());
+        assert!(Dispute::test(client, 1).commit(&mut accounts).is_ok());
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        let old = (acct.available, acct.held);
+        let err = Dispute::test(client, 3).commit(&mut accounts).expect_err("held balance would overflow");
+        assert!(err.to_string().contains("balance overflow"), "{}", err);
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        assert_eq!((acct.available, acct.held), old);
+    }
+    
     #[test]
     fn on_failed() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
         let client = 1;
-        let trx1 = withdrawal::Withdrawal::test(client, 1, dec!(1.0));
+        let trx1 = withdrawal::Withdrawal::new(client, 1, dec!(1.0));
         assert!(trx1.commit(&mut accounts).is_ok());
         let balance = accounts.get(&client).expect("client 1 in test accounts").available;
-        let trx2 = withdrawal::Withdrawal::test(client, 2, balance + dec!(1.0));
+        let trx2 = withdrawal::Withdrawal::new(client, 2, balance + dec!(1.0));
         assert!(trx2.commit(&mut accounts).is_err()); // over balance
-        let trx3 = Dispute {client, tx: 2};
+        let trx3 = Dispute {client, tx: 2, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_err()); // dispute to failed transaction
     }
+    
+    #[test]
+    fn partial_disputes() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let client = 1;
+        let tx = 1;
+        let trx1 = deposit::Deposit::new(client, tx, dec!(1.5));
+        assert!(trx1.commit(&mut accounts).is_ok());
+
+        let trx2 = Dispute {client, tx, amount: Some(dec!(1.0)), timestamp: None, currency: None};
+        assert!(trx2.commit(&mut accounts).is_ok());
+        let trx3 = Dispute {client, tx, amount: Some(dec!(0.75)), timestamp: None, currency: None};
+        assert!(trx3.commit(&mut accounts).is_err()); // exceeds not disputed part
+        let trx4 = Dispute {client, tx, amount: None, timestamp: None, currency: None};
+        assert!(trx4.commit(&mut accounts).is_ok()); // disputes the rest
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(2.0));
+        assert_eq!(acct.held, dec!(1.5));
+        assert_eq!(acct.transactions.get(tx).expect("stored transaction").disputed, dec!(1.5));
+    }
+
+    #[test]
+    fn partial_on_withdrawal() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let client = 1;
+        let tx = 1;
+        let trx1 = withdrawal::Withdrawal::new(client, tx, dec!(1.5));
+        assert!(trx1.commit(&mut accounts).is_ok());
+
+        let trx2 = Dispute {client, tx, amount: Some(dec!(0.5)), timestamp: None, currency: None};
+        assert!(trx2.commit(&mut accounts).is_ok());
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(1.0));
+        assert_eq!(acct.held, dec!(-0.5));
+        assert_eq!(acct.transactions.get(tx).expect("stored transaction").disputed, dec!(-0.5));
+    }
+
+    #[test]
+    fn invalid_amount() {
+        let trx = Dispute {client: 1, tx: 1, amount: Some(dec!(0.0)), timestamp: None, currency: None};
+        assert!(matches!(trx.validate(), TransactionValid::Invalid(_)));
+        let trx = Dispute {client: 1, tx: 1, amount: Some(dec!(-1.0)), timestamp: None, currency: None};
+        assert!(matches!(trx.validate(), TransactionValid::Invalid(_)));
+    }
+
+    /// Client 1 deposits 10.0 and withdraws 8.0 (tx 2), then deposit (tx 1) is disputed with passed policy.
+    fn dispute_withdrawn(policy: DisputePolicy) -> (Result<()>, AccountState) {
+        let mut accounts = create_accounts(&[dec!(0.0)]);
+        assert!(deposit::Deposit::new(1, 1, dec!(10.0)).commit(&mut accounts).is_ok());
+        assert!(withdrawal::Withdrawal::new(1, 2, dec!(8.0)).commit(&mut accounts).is_ok());
+        let trx = Dispute {client: 1, tx: 1, amount: None, timestamp: None, currency: None};
+        let result = trx.commit_with(&mut accounts, &Policy {dispute: policy, ..Policy::default()});
+        (result, accounts.remove(&1).expect("client 1 in test accounts"))
+    }
+
+    #[test]
+    fn policy_allow_negative() {
+        let (result, acct) = dispute_withdrawn(DisputePolicy::AllowNegative);
+        assert!(result.is_ok());
+        assert_eq!((acct.available, acct.held), (dec!(-8.0), dec!(10.0)));
+        assert!(acct.is_negative());
+    }
+
+    #[test]
+    fn policy_reject() {
+        let (result, acct) = dispute_withdrawn(DisputePolicy::Reject);
+        assert!(result.is_err());
+        assert_eq!((acct.available, acct.held), (dec!(2.0), dec!(0.0)));
+    }
+
+    #[test]
+    fn policy_cap() {
+        let (result, acct) = dispute_withdrawn(DisputePolicy::Cap);
+        assert!(result.is_ok());
+        assert_eq!((acct.available, acct.held), (dec!(0.0), dec!(2.0)));
+        assert_eq!(acct.transactions.get(1).expect("stored transaction").disputed, dec!(2.0));
+        assert_eq!(acct.transactions.get(1).expect("stored transaction").disputable(), dec!(8.0));
+
+        let mut accounts = HashMap::from([(1, acct)]);
+        let trx = Dispute {client: 1, tx: 1, amount: None, timestamp: None, currency: None};
+        assert!(trx.commit_with(&mut accounts, &Policy {dispute: DisputePolicy::Cap, ..Policy::default()}).is_err()); // nothing available
+    }
+
+    #[test]
+    fn policy_withdrawal() {
+        for policy in [DisputePolicy::Reject, DisputePolicy::Cap] {
+            let (result, acct) = dispute_withdrawn(policy);
+            let mut accounts = HashMap::from([(1, acct)]);
+            let (available, held) = (accounts[&1].available, accounts[&1].held);
+            assert_eq!(result.is_ok(), policy == DisputePolicy::Cap);
+            for amount in [None, Some(dec!(1.0))] {
+                let trx = Dispute {client: 1, tx: 2, amount, timestamp: None, currency: None};
+                assert!(trx.commit_with(&mut accounts, &Policy {dispute: policy, ..Policy::default()}).is_err(), "{:?}", policy);
+                assert_eq!((accounts[&1].available, accounts[&1].held), (available, held), "{:?}", policy);
+                assert!(accounts[&1].transactions.get(2).expect("stored transaction").disputed.is_zero());
+            }
+        }
+    }
+
+    #[test]
+    fn policy_deposits_only() {
+        let (result, acct) = dispute_withdrawn(DisputePolicy::DepositsOnly);
+        assert!(result.is_ok());
+        let mut accounts = HashMap::from([(1, acct)]);
+        let trx = Dispute {client: 1, tx: 2, amount: None, timestamp: None, currency: None};
+        assert!(trx.commit_with(&mut accounts, &Policy {dispute: DisputePolicy::DepositsOnly, ..Policy::default()}).is_err());
+        assert!(trx.commit_with(&mut accounts, &Policy::default()).is_ok());
+    }
+
+    #[test]
+    fn in_referenced_currency() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let eur = Some("EUR".parse().expect("currency code"));
+        let trx1 = deposit::Deposit::try_from(TransactionRec {
+            ttype: TransactionRecType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Some(1.5),
+            timestamp: None,
+            reason: None,
+            currency: eur,
+            to_currency: None,
+        }).expect("Deposit transaction from transaction record");
+        assert!(trx1.commit(&mut accounts).is_ok());
+        let trx2 = Dispute {currency: "USD".parse().ok(), ..Dispute::test(1, 1)};
+        assert!(trx2.commit(&mut accounts).is_err()); // currency differs
+        assert!(Dispute::test(1, 1).commit(&mut accounts).is_ok());
+        let acct = accounts.get(&1).expect("client 1 in test accounts");
+        assert_eq!((acct.available, acct.held), (dec!(2.0), dec!(0.0)));
+        let balance = acct.balance(eur);
+        assert_eq!((balance.available, balance.held), (dec!(0.0), dec!(1.5)));
+    }
 }
@@ -0,0 +1,197 @@
+use anyhow::{Result, Context, bail};
+
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
+use super::*;
+
+/// Represents Exchange transaction - conversion of funds between currencies of the same account.
+pub struct Exchange {
+    client: TClientId,
+    tx: TTrxID,
+    /// Exchanged amount (in `currency`)
+    amount: TMoney,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
+    to_currency: Option<Currency>,
+}
+
+impl TryFrom<TransactionRec> for Exchange {
+    type Error = anyhow::Error;
+    fn try_from(value: TransactionRec) -> std::result::Result<Self, Self::Error> {
+        if value.ttype != TransactionRecType::Exchange {
+            bail!("Transaction ID {} - Incompatible type expected Exchange", value.tx)
+        }
+        let Some(amount) = value.amount else {
+            bail!("Transaction ID {} - Amount is missing in Exchange transaction", value.tx)
+        };
+        if value.currency.is_none() && value.to_currency.is_none() {
+            bail!("Transaction ID {} - Currency is missing in Exchange transaction", value.tx)
+        }
+        Ok(Self {
+            client: value.client,
+            tx: value.tx,
+            timestamp: value.timestamp,
+            currency: value.currency,
+            to_currency: value.to_currency,
+            amount: amount.try_into()
+                .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount))?
+        })
+    }
+}
+
+impl TransactionInt for Exchange {
+    fn id(&self) -> TTrxID {self.tx}
+
+    fn client_id(&self) -> TClientId {self.client}
+
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {Some(self.amount)}
+
+    fn validate(&self) -> TransactionValid {
+        if self.currency == self.to_currency {
+            TransactionValid::Invalid("The same currencies in Exchange transaction")
+        } else if self.amount.is_sign_negative() || self.amount.is_zero() {
+            TransactionValid::Invalid("Amount <= 0 in Exchange transaction")
+        } else {
+            TransactionValid::Ok
+        }
+    }
+
+    /// Performs Exchange transaction.
+    /// - if exchange rates are not given in `policy` - reject.
+    /// - if account is not registered - reject.
+    /// - if account is locked - reject.
+    /// - if there is already registered transaction with the same ID - reject.
+    /// - if transaction with the same or greater ID was evicted by retention policy - reject.
+    /// - if there is no rate of currency pair effective at transaction time - reject.
+    /// - if account `available` property in `currency` is less then `amount` - reject.
+    /// - if account `available` property in `to_currency` would overflow - reject.
+    /// - otherwise decrease account `available` property in `currency` of given `amount`, increase it in `to_currency`
+    ///   of converted amount and stores transaction (as negative `amount` with converted amount as linked leg).
+    ///
+    /// Exchanged money is moved from client's available funds to settlement and converted amount back from settlement.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
+        let Some(rates) = &policy.exchange else {
+            bail!("Exchange transaction failed - exchange rates not loaded")
+        };
+        match accounts.get_mut(&self.client) {
+            Some(acct) => {
+                if acct.locked {
+                    bail!("Exchange transaction failed - account locked")
+                } else if acct.transactions.contains(self.tx) {
+                    bail!("Exchange transaction failed - duplicated transaction ID")
+                } else if acct.evicted.covers(self.tx) {
+                    bail!("Exchange transaction failed - transaction ID not greater than evicted ones")
+                }
+                let converted = rates.convert(self.currency, self.to_currency, self.amount, self.timestamp)
+                    .context("Exchange transaction failed - no exchange rate")?;
+                if acct.balance(self.currency).available < self.amount {
+                    bail!("Exchange transaction failed - not enough funds")
+                }
+                acct.balance(self.currency).checked_change(-self.amount, TMoney::ZERO)
+                    .context("Exchange transaction failed - balance overflow")?;
+                let funds = acct.funds_mut(self.to_currency);
+                change_balance(funds.available, funds.held, converted, TMoney::ZERO)
+                    .context("Exchange transaction failed - balance overflow")?;
+                let funds = acct.funds_mut(self.currency);
+                change_balance(funds.available, funds.held, -self.amount, TMoney::ZERO)
+                    .context("Exchange transaction failed - balance overflow")?;
+                funds.transactions.insert(self.tx, TrxEntry {
+                    currency: self.currency,
+                    linked: Some(Leg {amount: converted, currency: self.to_currency}),
+                    ..TrxEntry::new(-self.amount, self.timestamp)
+                });
+                journal.transfer(rec_no, self.tx, self.currency, LedgerAccount::Available(self.client), LedgerAccount::Settlement, self.amount);
+                journal.transfer(rec_no, self.tx, self.to_currency, LedgerAccount::Settlement, LedgerAccount::Available(self.client), converted);
+                Ok(())
+            }
+            None => bail!("Exchange transaction failed - client unknown")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use crate::transactions::TransactionInt;
+    use super::*;
+    use super::super::tests::create_accounts;
+
+    fn policy() -> Policy {
+        let mut rates = ExchangeRates::default();
+        rates.insert(None, "EUR".parse().ok(), None, dec!(0.9));
+        Policy {exchange: Some(rates), ..Policy::default()}
+    }
+
+    fn exchange(tx: TTrxID, amount: TMoney) -> Exchange {
+        Exchange {client: 1, tx, amount, timestamp: None, currency: None, to_currency: "EUR".parse().ok()}
+    }
+
+    #[test]
+    fn on_normal() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        assert!(exchange(1, dec!(1.5)).commit(&mut accounts).is_err()); // no rates
+        assert!(exchange(1, dec!(1.5)).commit_with(&mut accounts, &policy()).is_ok());
+        assert!(exchange(1, dec!(0.1)).commit_with(&mut accounts, &policy()).is_err()); // duplicated id
+        assert!(exchange(2, dec!(1.0)).commit_with(&mut accounts, &policy()).is_err()); // not enough funds
+        let acct = accounts.get(&1).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(0.5));
+        assert_eq!(acct.balance("EUR".parse().ok()).available, dec!(1.35));
+        let trx = acct.transactions.get(1).expect("stored exchange");
+        assert_eq!(trx.amount, dec!(-1.5));
+        assert_eq!(trx.linked, Some(Leg {amount: dec!(1.35), currency: "EUR".parse().ok()}));
+    }
+
+    #[test]
+    fn no_rate() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let trx = Exchange {to_currency: "USD".parse().ok(), ..exchange(1, dec!(1.0))};
+        assert!(trx.commit_with(&mut accounts, &policy()).is_err());
+        assert_eq!(accounts.get(&1).expect("client 1 in test accounts").available, dec!(2.0));
+    }
+
+    #[test]
+    fn dispute_reverses_both_legs() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let eur = "EUR".parse().ok();
+        assert!(exchange(1, dec!(1.0)).commit_with(&mut accounts, &policy()).is_ok());
+        assert!(dispute::Dispute::test(1, 1).commit(&mut accounts).is_ok());
+        let acct = accounts.get(&1).expect("client 1 in test accounts");
+        assert_eq!((acct.available, acct.held), (dec!(2.0), dec!(-1.0)));
+        assert_eq!((acct.balance(eur).available, acct.balance(eur).held), (dec!(0.0), dec!(0.9)));
+        assert_eq!(acct.disputed_in(eur), dec!(0.9));
+
+        let trx = chargeback::Chargeback::try_from(TransactionRec {
+            ttype: TransactionRecType::Chargeback,
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+            reason: None,
+            currency: None,
+            to_currency: None,
+        }).expect("Chargeback transaction from transaction record");
+        assert!(trx.commit(&mut accounts).is_ok());
+        let acct = accounts.get(&1).expect("client 1 in test accounts");
+        assert_eq!((acct.available, acct.held), (dec!(2.0), dec!(0.0)));
+        assert_eq!((acct.balance(eur).available, acct.balance(eur).held), (dec!(0.0), dec!(0.0)));
+        assert!(acct.locked);
+    }
+
+    #[test]
+    fn partial_dispute() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        assert!(exchange(1, dec!(1.0)).commit_with(&mut accounts, &policy()).is_ok());
+        let trx = dispute::Dispute::try_from(TransactionRec {
+            ttype: TransactionRecType::Dispute,
+            client: 1,
+            tx: 1,
+            amount: Some(0.5),
+            timestamp: None,
+            reason: None,
+            currency: None,
+            to_currency: None,
+        }).expect("Dispute transaction from transaction record");
+        assert!(trx.commit(&mut accounts).is_err());
+    }
+}
@@ -1,12 +1,18 @@
+//! Transaction types. Business logic of every transaction type is placed in `commit_journaled` method of its own module.
+
 use std::collections::HashMap;
+use std::str::FromStr;
 
-use anyhow::{Result, bail};
+use anyhow::{Result, Context, bail};
 use enum_dispatch::enum_dispatch;
 use serde::Deserialize;
 
 use crate::{
-    TClientId, TTrxID,
-    accounts::AccountState
+    TClientId, TMoney, TTrxID, TTimestamp,
+    accounts::{AccountState, Leg, TrxEntry, change_balance},
+    currency::Currency,
+    ledger::{Journal, LedgerAccount},
+    rates::ExchangeRates,
 };
 
 mod deposit;
@@ -14,12 +20,20 @@ mod withdrawal;
 mod dispute;
 mod resolve;
 mod chargeback;
+mod unlock;
+mod adjust;
+mod exchange;
+#[cfg(test)]
+pub(crate) mod proptests;
 
-use deposit::Deposit;
-use withdrawal::Withdrawal;
-use dispute::Dispute;
-use resolve::Resolve;
-use chargeback::Chargeback;
+pub use deposit::Deposit;
+pub use withdrawal::Withdrawal;
+pub use dispute::Dispute;
+pub use resolve::Resolve;
+pub use chargeback::Chargeback;
+pub use unlock::Unlock;
+pub use adjust::Adjust;
+pub use exchange::Exchange;
 
 /// Transaction Interface. Every transaction must implement it.
 /// `TryFrom` implementation should initialization of transaction from input record, 
@@ -32,12 +46,67 @@ pub trait TransactionInt: TryFrom<TransactionRec> {
     /// Returns client id
     fn client_id(&self) -> TClientId;
 
+    /// Returns transaction time (if given in input)
+    fn timestamp(&self) -> Option<TTimestamp>;
+
+    /// Returns transaction amount (if given in input)
+    fn amount(&self) -> Option<TMoney> {None}
+
     /// Performs additional validation of transaction consistency with possibility to raise a warning.
     /// In case of `Ok`, and `Warn` transaction is being processed, `Invalid` result cause transaction to be rejected.
     fn validate(&self) -> TransactionValid;
 
-    /// Actually performs transaction making necessary changes in passed accounts.
-    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()>;
+    /// Performs transaction with default rules (see `Policy`).
+    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+        self.commit_with(accounts, &Policy::default())
+    }
+
+    /// Performs transaction according to rules chosen for processing run, without recording postings.
+    fn commit_with(&self, accounts:&mut HashMap::<TClientId,AccountState>, policy: &Policy) -> Result<()> {
+        self.commit_journaled(accounts, policy, 0, &mut Journal::default())
+    }
+
+    /// Actually performs transaction making necessary changes in passed accounts according to rules chosen
+    /// for processing run. Money moved by transaction is recorded in `journal` as entries between ledger accounts
+    /// (postings of record `rec_no`) - only when transaction succeeds.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()>;
+}
+
+/// Rules chosen per processing run, which transactions take into account in `commit_with`.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    /// Handling of disputes which would drive available balance negative.
+    pub dispute: DisputePolicy,
+    /// Exchange rates used by exchange transactions (exchanges are rejected if not given).
+    pub exchange: Option<ExchangeRates>,
+}
+
+/// Handling of disputes which would drive available balance negative.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DisputePolicy {
+    /// Whole disputed amount is held even if available balance becomes negative.
+    #[default]
+    AllowNegative,
+    /// Dispute is rejected if available balance would become negative or if it is dispute of withdrawal
+    /// (which would increase available balance).
+    Reject,
+    /// Held amount is limited to available balance, disputes of withdrawals are rejected.
+    Cap,
+    /// Only deposits may be disputed (available balance may become negative).
+    DepositsOnly,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "allow-negative" => Ok(DisputePolicy::AllowNegative),
+            "reject" => Ok(DisputePolicy::Reject),
+            "cap" => Ok(DisputePolicy::Cap),
+            "deposits-only" => Ok(DisputePolicy::DepositsOnly),
+            _ => Err(format!("unknown dispute policy: {} (expected allow-negative, reject, cap or deposits-only)", s)),
+        }
+    }
 }
 
 /// Transaction object.
@@ -48,6 +117,9 @@ pub enum Transaction {
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    Adjust,
+    Exchange,
     TheEnd
 }
 
@@ -64,10 +136,11 @@ impl TryFrom<TransactionRec> for TheEnd {
 impl TransactionInt for TheEnd {
     fn id(&self) -> TTrxID {0}
     fn client_id(&self) -> TClientId {0}
+    fn timestamp(&self) -> Option<TTimestamp> {None}
     fn validate(&self) -> TransactionValid {
         TransactionValid::Ok
     }
-    fn commit(&self, _accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+    fn commit_journaled(&self, _accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, _rec_no: u128, _journal: &mut Journal) -> Result<()> {
         Ok(())
     }
 }
@@ -93,13 +166,16 @@ impl TryFrom<TransactionRec> for Transaction {
                 TransactionRecType::Dispute => Transaction::from(Dispute::try_from(td)?),
                 TransactionRecType::Resolve => Transaction::from(Resolve::try_from(td)?),
                 TransactionRecType::Chargeback => Transaction::from(Chargeback::try_from(td)?),
+                TransactionRecType::Unlock => Transaction::from(Unlock::try_from(td)?),
+                TransactionRecType::Adjust => Transaction::from(Adjust::try_from(td)?),
+                TransactionRecType::Exchange => Transaction::from(Exchange::try_from(td)?),
             }
         )
     }
 }
 
 /// Transaction type as may occur in input file as small caps word (first column).
-#[derive(PartialEq, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionRecType {
     Deposit,
@@ -107,6 +183,16 @@ pub enum TransactionRecType {
     Dispute,
     Resolve,
     Chargeback,
+    Unlock,
+    Adjust,
+    Exchange,
+}
+
+impl TransactionRecType {
+    /// Returns `true` for administrative transaction types, accepted only from privileged input.
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, TransactionRecType::Unlock | TransactionRecType::Adjust)
+    }
 }
 
 /// Represents transaction record as read from input file.
@@ -118,6 +204,74 @@ pub struct TransactionRec {
     pub tx: TTrxID,
     #[serde(default)]
     pub amount: Option<f64>, // Option<TMoney> is possible here, but maybe f64 will be faster
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<TTimestamp>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Currency funds are exchanged to (exchange only)
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+}
+
+/// Returns currency of transaction `tx` referenced by dispute, resolve or chargeback (named `ttype` in errors).
+/// Fails if referenced transaction is not registered or `currency` given in record differs from its currency.
+fn referenced_currency(acct: &AccountState, tx: TTrxID, currency: Option<Currency>, ttype: &str) -> Result<Option<Currency>> {
+    match acct.transactions.get(tx) {
+        Some(trx) if currency.is_some() && currency != trx.currency => 
+            bail!("{} transaction failed - currency differs from currency of referenced transaction", ttype),
+        Some(trx) => Ok(trx.currency),
+        None if acct.evicted.contains(tx) => 
+            bail!("{} transaction failed - reference expired", ttype),
+        None => bail!("{} transaction failed - reference transaction ID not found for given client", ttype),
+    }
+}
+
+/// Returns credited leg of exchange `tx` referenced by dispute, resolve or chargeback (named `ttype` in errors).
+/// Exchange may be referenced only as a whole, so it fails if `amount` is given.
+fn linked_leg(acct: &AccountState, tx: TTrxID, amount: Option<TMoney>, ttype: &str) -> Result<Option<Leg>> {
+    match acct.transactions.get(tx).and_then(|trx| trx.linked) {
+        Some(_) if amount.is_some() => bail!("{} transaction failed - exchange may be referenced only as a whole", ttype),
+        linked => Ok(linked),
+    }
+}
+
+/// Returns part of `limit` referenced by dispute, resolve or chargeback: whole `limit` if `amount` is not given,
+/// otherwise `amount` with sign of `limit` (`None` if `amount` exceeds `limit` or nothing is left to reference).
+fn referenced_part(amount: Option<TMoney>, limit: TMoney) -> Option<TMoney> {
+    match amount {
+        _ if limit.is_zero() => None,
+        None => Some(limit),
+        Some(amount) if amount > limit.abs() => None,
+        Some(amount) if limit.is_sign_negative() => Some(-amount),
+        Some(amount) => Some(amount),
+    }
+}
+
+/// Parses optional timestamp given as RFC3339 date-time or as number of seconds since Unix epoch.
+pub fn parse_timestamp(value: &str) -> Result<TTimestamp> {
+    if let Ok(secs) = value.parse::<i64>() {
+        return secs.checked_mul(1000).with_context(|| format!("timestamp out of range: {}", value));
+    }
+    if let Ok(secs) = value.parse::<f64>() {
+        if secs.is_finite() && (secs * 1000.0).abs() < TTimestamp::MAX as f64 {
+            return Ok((secs * 1000.0).round() as TTimestamp);
+        }
+        bail!("timestamp out of range: {}", value)
+    }
+    Ok(chrono::DateTime::parse_from_rfc3339(value)
+        .with_context(|| format!("invalid timestamp: {}", value))?
+        .timestamp_millis())
+}
+
+pub(crate) fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<TTimestamp>, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.is_empty() => parse_timestamp(&value).map(Some).map_err(serde::de::Error::custom),
+        _ => Ok(None),
+    }
 }
 
 mod tests {
@@ -128,6 +282,37 @@ mod tests {
         accounts::AccountState,
     };
 
+    #[test]
+    fn timestamp_formats() {
+        assert_eq!(super::parse_timestamp("1704067200").unwrap(), 1_704_067_200_000);
+        assert_eq!(super::parse_timestamp("1704067200.25").unwrap(), 1_704_067_200_250);
+        assert_eq!(super::parse_timestamp("2024-01-01T00:00:00Z").unwrap(), 1_704_067_200_000);
+        assert_eq!(super::parse_timestamp("2024-01-01T01:00:00.5+01:00").unwrap(), 1_704_067_200_500);
+        assert!(super::parse_timestamp("2024-01-01").is_err());
+        assert!(super::parse_timestamp("1e30").is_err());
+    }
+
+    #[test]
+    fn evicted_references() {
+        use rust_decimal_macros::dec;
+        use crate::{retention::Retention, transactions::{TransactionInt, deposit::Deposit, dispute::Dispute, withdrawal::Withdrawal}};
+
+        let mut accounts = create_accounts(&[dec!(0)]);
+        for tx in [1, 3, 4] {
+            assert!(Deposit::new(1, tx, dec!(1)).commit(&mut accounts).is_ok());
+        }
+        let acct = accounts.get_mut(&1).expect("client 1 in test accounts");
+        let retention = Retention {last: Some(1), ..Default::default()};
+        assert_eq!(retention.apply(1, acct, None).expect("no spill"), 2);
+        let err = Dispute::test(1, 3).commit(&mut accounts).expect_err("evicted reference");
+        assert!(err.to_string().contains("reference expired"), "{}", err);
+        let err = Dispute::test(1, 2).commit(&mut accounts).expect_err("never stored");
+        assert!(err.to_string().contains("not found"), "{}", err);
+        assert!(Deposit::new(1, 2, dec!(1)).commit(&mut accounts).is_err());
+        assert!(Withdrawal::new(1, 3, dec!(1)).commit(&mut accounts).is_err());
+        assert!(Deposit::new(1, 5, dec!(1)).commit(&mut accounts).is_ok());
+    }
+
     #[allow(dead_code)]
     pub fn create_accounts(balance: &[TMoney]) -> HashMap::<TClientId,AccountState> {
         let mut accounts = HashMap::<TClientId,AccountState>::new();
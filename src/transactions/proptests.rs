@@ -0,0 +1,211 @@
+//! Property-based tests - random transaction sequences are applied and account invariants checked after every commit.
+//! Expected balances are computed from sequence of committed transactions by simple model, independently of accounts.
+
+use std::collections::HashMap;
+
+use proptest::prelude::*;
+
+use crate::{
+    TClientId, TMoney, TTrxID,
+    accounts::AccountState,
+};
+use super::*;
+
+/// Transaction as generated by tests: (type, client, tx, amount in 1/100 units).
+pub type Op = (TransactionRecType, TClientId, TTrxID, Option<u32>);
+
+/// Strategy generating transactions for small number of clients and transaction IDs,
+/// so that disputes, resolves and chargebacks often reference existing deposits and withdrawals.
+pub fn ops(max_len: usize) -> impl Strategy<Value = Vec<Op>> {
+    let op = prop_oneof![
+        3 => (Just(TransactionRecType::Deposit), 1..5u16, 1..40u32, (0..10_000u32).prop_map(Some)),
+        2 => (Just(TransactionRecType::Withdrawal), 1..5u16, 1..40u32, (0..10_000u32).prop_map(Some)),
+        2 => (Just(TransactionRecType::Dispute), 1..5u16, 1..40u32, partial()),
+        1 => (Just(TransactionRecType::Resolve), 1..5u16, 1..40u32, partial()),
+        1 => (Just(TransactionRecType::Chargeback), 1..5u16, 1..40u32, partial()),
+    ];
+    prop::collection::vec(op, 0..max_len)
+}
+
+/// Strategy generating amount of dispute, resolve or chargeback: mostly whole transaction, sometimes its part.
+fn partial() -> impl Strategy<Value = Option<u32>> {
+    prop_oneof![
+        3 => Just(None),
+        1 => (1..5_000u32).prop_map(Some),
+    ]
+}
+
+/// Converts generated transaction into input record.
+pub fn to_rec((ttype, client, tx, amount): &Op) -> TransactionRec {
+    TransactionRec {
+        ttype: *ttype,
+        client: *client,
+        tx: *tx,
+        amount: amount.map(|cents| cents as f64 / 100.0),
+        timestamp: None,
+        reason: None,
+        currency: None,
+        to_currency: None,
+    }
+}
+
+/// Converts generated transaction into line of CSV input file.
+pub fn to_csv((ttype, client, tx, amount): &Op) -> String {
+    let ttype = match ttype {
+        TransactionRecType::Deposit => "deposit",
+        TransactionRecType::Withdrawal => "withdrawal",
+        TransactionRecType::Dispute => "dispute",
+        TransactionRecType::Resolve => "resolve",
+        TransactionRecType::Chargeback => "chargeback",
+        TransactionRecType::Unlock => "unlock",
+        TransactionRecType::Adjust => "adjust",
+        TransactionRecType::Exchange => "exchange",
+    };
+    match amount {
+        Some(cents) => format!("{},{},{},{}.{:02}\n", ttype, client, tx, cents / 100, cents % 100),
+        None => format!("{},{},{},\n", ttype, client, tx),
+    }
+}
+
+/// Applies transaction the same way as processor does (validation and commit), returns `true` if committed.
+pub fn apply(op: &Op, accounts: &mut HashMap::<TClientId,AccountState>) -> bool {
+    apply_with(op, accounts, &Policy::default())
+}
+
+/// Applies transaction as `apply` with given rules of processing run.
+pub fn apply_with(op: &Op, accounts: &mut HashMap::<TClientId,AccountState>, policy: &Policy) -> bool {
+    let transaction = match Transaction::try_from(to_rec(op)) {
+        Ok(transaction) => transaction,
+        Err(_) => return false,
+    };
+    if let TransactionValid::Invalid(_) = transaction.validate() {
+        return false;
+    }
+    transaction.commit_with(accounts, policy).is_ok()
+}
+
+/// Expected state of client account computed only from committed transactions.
+#[derive(Debug, Default)]
+struct Model {
+    /// Deposits minus withdrawals minus charged back amounts
+    total: TMoney,
+    /// Deposits and withdrawals: (signed amount, open disputed part, charged back part)
+    trxs: HashMap<TTrxID, (TMoney, TMoney, TMoney)>,
+    /// There was a chargeback on account
+    charged_back: bool,
+}
+
+impl Model {
+    /// Updates model with committed transaction.
+    fn commit(&mut self, (ttype, _, tx, cents): &Op) {
+        let amount = cents.map(|cents| TMoney::try_from(cents as f64 / 100.0).expect("amount in range"));
+        let signed = |trx: &(TMoney, TMoney, TMoney), amount: TMoney| if trx.0.is_sign_negative() {-amount} else {amount};
+        match ttype {
+            TransactionRecType::Deposit | TransactionRecType::Withdrawal => {
+                let amount = amount.expect("amount of deposit or withdrawal");
+                let amount = if *ttype == TransactionRecType::Withdrawal {-amount} else {amount};
+                self.trxs.insert(*tx, (amount, TMoney::ZERO, TMoney::ZERO));
+                self.total += amount;
+            },
+            TransactionRecType::Dispute => {
+                let trx = self.trxs.get_mut(tx).expect("disputed transaction committed before");
+                let part = amount.map_or(trx.0 - trx.1 - trx.2, |amount| signed(trx, amount));
+                trx.1 += part;
+            },
+            TransactionRecType::Resolve => {
+                let trx = self.trxs.get_mut(tx).expect("resolved transaction committed before");
+                trx.1 -= amount.map_or(trx.1, |amount| signed(trx, amount));
+            },
+            TransactionRecType::Chargeback => {
+                let trx = self.trxs.get_mut(tx).expect("charged back transaction committed before");
+                let part = amount.map_or(trx.1, |amount| signed(trx, amount));
+                trx.1 -= part;
+                trx.2 += part;
+                self.total -= part;
+                self.charged_back = true;
+            },
+            _ => unreachable!("not generated"),
+        }
+    }
+
+    /// Returns sum of open disputes.
+    fn held(&self) -> TMoney {
+        self.trxs.values().map(|trx| trx.1).sum()
+    }
+}
+
+fn check_invariants(acct: &AccountState, model: &Model, policy: &Policy) -> Result<(), TestCaseError> {
+    prop_assert_eq!(acct.total(), model.total, "total must be equal to deposits - withdrawals - charged back amounts");
+    prop_assert_eq!(acct.held, model.held(), "held must be equal to sum of open disputes");
+    if matches!(policy.dispute, DisputePolicy::DepositsOnly | DisputePolicy::Reject | DisputePolicy::Cap) {
+        // only deposits are disputed
+        prop_assert!(!acct.held.is_sign_negative() || acct.held.is_zero(), "negative held");
+    }
+    prop_assert_eq!(acct.locked, model.charged_back, "account must be locked after chargeback (and only then)");
+    for (_, trx) in acct.transactions.iter() {
+        prop_assert!(trx.disputed.abs() + trx.charged_back.abs() <= trx.amount.abs(), "disputed more than transaction amount");
+    }
+    Ok(())
+}
+
+/// Applies transactions with given rules and checks invariants of touched account after every transaction.
+fn check_sequence(ops: &[Op], policy: &Policy) -> Result<(), TestCaseError> {
+    let mut accounts = HashMap::new();
+    let mut models = HashMap::<TClientId, Model>::new();
+    for op in ops {
+        let before = accounts.get(&op.1).cloned();
+        let committed = apply_with(op, &mut accounts, policy);
+        let after = accounts.get(&op.1);
+        match (&before, after) {
+            (Some(before), Some(after)) if before.locked => {
+                prop_assert!(!committed, "transaction committed on locked account");
+                prop_assert_eq!(before, after, "locked account changed");
+            },
+            (before, after) if !committed => prop_assert_eq!(before.as_ref(), after, "rejected transaction changed account"),
+            _ => {},
+        }
+        let model = models.entry(op.1).or_default();
+        if committed {
+            model.commit(op);
+        }
+        if let Some(acct) = after {
+            check_invariants(acct, model, policy)?;
+        }
+    }
+    for (client, acct) in &accounts {
+        check_invariants(acct, &models[client], policy)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn invariants(ops in ops(200)) {
+        check_sequence(&ops, &Policy::default())?;
+    }
+
+    #[test]
+    fn invariants_deposits_only(ops in ops(200)) {
+        check_sequence(&ops, &Policy {dispute: DisputePolicy::DepositsOnly, ..Default::default()})?;
+    }
+
+    #[test]
+    fn invariants_reject(ops in ops(200)) {
+        check_sequence(&ops, &Policy {dispute: DisputePolicy::Reject, ..Default::default()})?;
+    }
+
+    #[test]
+    fn journal_balanced(ops in ops(200)) {
+        let mut accounts = HashMap::new();
+        let mut journal = Journal::new(true);
+        for (rec_no, op) in ops.iter().enumerate() {
+            let Ok(transaction) = Transaction::try_from(to_rec(op)) else {continue};
+            if let TransactionValid::Invalid(_) = transaction.validate() {
+                continue;
+            }
+            let _ = transaction.commit_journaled(&mut accounts, &Policy::default(), rec_no as u128 + 1, &mut journal);
+        }
+        let verification = journal.verify(&accounts);
+        prop_assert!(verification.is_ok(), "{}", verification);
+    }
+}
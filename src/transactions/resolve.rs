@@ -1,12 +1,23 @@
-use anyhow::{Result, bail};
+use anyhow::{Result, Context, bail};
 
-use crate::{TClientId, TTrxID};
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
 use super::*;
 
 /// Represents Resolve transaction.
 pub struct Resolve {
     client: TClientId,
     tx: TTrxID,
+    /// Resolved part of referenced transaction amount (whole disputed amount if not given).
+    amount: Option<TMoney>,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
+}
+
+impl Resolve {
+    /// Creates resolve of transaction `tx` (`amount` - resolved part) without currency and timestamp.
+    pub fn new(client:TClientId, tx:TTrxID, amount:Option<TMoney>) -> Self {
+        Self {client, tx, amount, timestamp: None, currency: None}
+    }
 }
 
 impl TryFrom<TransactionRec> for Resolve {
@@ -15,7 +26,15 @@ impl TryFrom<TransactionRec> for Resolve {
         if value.ttype != TransactionRecType::Resolve {
             bail!("Transaction ID {} - Incompatible type expected Resolve", value.tx)
         } else {
-            Ok(Self {client: value.client, tx: value.tx})
+            Ok(Self {
+                client: value.client,
+                tx: value.tx,
+                timestamp: value.timestamp,
+                currency: value.currency,
+                amount: value.amount.map(|amount| amount.try_into()
+                    .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount)))
+                    .transpose()?
+            })
         }
     }
 }
@@ -25,32 +44,63 @@ impl TransactionInt for Resolve {
 
     fn client_id(&self) -> TClientId {self.client}
 
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {self.amount}
+
     fn validate(&self) -> TransactionValid {
-        TransactionValid::Ok
+        match self.amount {
+            Some(amount) if amount.is_sign_negative() || amount.is_zero() => TransactionValid::Invalid("Amount <= 0 in Resolve transaction"),
+            _ => TransactionValid::Ok,
+        }
     }
 
     /// Performs Resolve transaction.
     /// - if account is not registered - reject.
     /// - if account is locked - reject.
-    /// - if referenced transaction is not registered for given client - reject.
+    /// - if referenced transaction is not registered for given client or currency is given and differs from 
+    ///   its currency - reject.
     /// - if referenced transaction is not 'on dispute' state - reject.
-    /// - otherwise releases referenced transaction from 'on dispute' state and re-apply transaction.
-    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+    /// - if given `amount` exceeds disputed part of referenced transaction - reject.
+    /// - if referenced transaction is exchange and `amount` is given - reject.
+    /// - otherwise releases given `amount` (or whole disputed part) of referenced transaction from 'on dispute' state 
+    ///   and re-apply it (in currency of referenced transaction), linked leg of exchange is re-applied the same way.
+    ///
+    /// Resolved money is moved from client's held funds back to available ones.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
         match accounts.get_mut(&self.client) {
             Some(acct) => {
                 if acct.locked {
                     bail!("Resolve transaction failed - account locked")
                 }
-                match acct.transactions.get_mut(&self.tx) {
-                    Some((dispute, amount)) if *dispute => {
-                        acct.available += *amount;
-                        acct.held -= *amount;
-                        *dispute = false;
-                        Ok(())
+                let currency = referenced_currency(acct, self.tx, self.currency, "Resolve")?;
+                let linked = linked_leg(acct, self.tx, self.amount, "Resolve")?;
+                if let Some(leg) = linked {
+                    acct.balance(leg.currency).checked_change(leg.amount, -leg.amount)
+                        .context("Resolve transaction failed - balance overflow")?;
+                }
+                let funds = acct.funds_mut(currency);
+                let part = match funds.transactions.get_mut(self.tx) {
+                    Some(trx) if trx.is_disputed() => match referenced_part(self.amount, trx.disputed) {
+                        Some(part) => {
+                            change_balance(funds.available, funds.held, part, -part)
+                                .context("Resolve transaction failed - balance overflow")?;
+                            trx.disputed -= part;
+                            part
+                        },
+                        None => bail!("Resolve transaction failed - amount exceeds disputed part of transaction"),
                     },
                     Some(_) => bail!("Resolve transaction failed - not disputed transaction"),
                     None => bail!("Resolve transaction failed - reference transaction ID not found for given client")
+                };
+                journal.transfer(rec_no, self.tx, currency, LedgerAccount::Held(self.client), LedgerAccount::Available(self.client), part);
+                if let Some(leg) = linked {
+                    let funds = acct.funds_mut(leg.currency);
+                    change_balance(funds.available, funds.held, leg.amount, -leg.amount)
+                        .context("Resolve transaction failed - balance overflow")?;
+                    journal.transfer(rec_no, self.tx, leg.currency, LedgerAccount::Held(self.client), LedgerAccount::Available(self.client), leg.amount);
                 }
+                Ok(())
             }
             None => bail!("Resolve transaction failed - client unknown")
         }
@@ -68,7 +118,7 @@ mod tests {
     fn on_locked() {
         let mut accounts = create_accounts(&[dec!(0.0)]);
         accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
-        let trx = Resolve {client: 1, tx: 1};
+        let trx = Resolve {client: 1, tx: 1, amount: None, timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_err());
     }
     
@@ -78,7 +128,7 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
@@ -96,10 +146,14 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx2.commit(&mut accounts).is_ok());
-        let trx3 = Resolve {client, tx};
+        let trx3 = Resolve {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -113,7 +167,7 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = withdrawal::Withdrawal::test(client, tx, amount);
+        let trx1 = withdrawal::Withdrawal::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
@@ -123,10 +177,14 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx2.commit(&mut accounts).is_ok());
-        let trx3 = Resolve {client, tx};
+        let trx3 = Resolve {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -137,8 +195,8 @@ mod tests {
     #[test]
     fn unknown_client() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Resolve {client: 10, tx: 1};
-        assert!(accounts.get(&trx.client).is_none());
+        let trx = Resolve {client: 10, tx: 1, amount: None, timestamp: None, currency: None};
+        assert!(!accounts.contains_key(&trx.client));
         assert!(trx.commit(&mut accounts).is_err());
     }
     
@@ -148,12 +206,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx2 = Resolve {client, tx: tx + 1};
+        let trx2 = Resolve {client, tx: tx + 1, amount: None, timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_err());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -167,7 +225,7 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = deposit::Deposit::test(client, tx, amount);
+        let trx1 = deposit::Deposit::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
@@ -177,12 +235,16 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx2.commit(&mut accounts).is_ok());
-        let trx3 = Resolve {client, tx};
+        let trx3 = Resolve {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
-        let trx4 = Resolve {client, tx};
+        let trx4 = Resolve {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx4.commit(&mut accounts).is_err());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -196,12 +258,12 @@ mod tests {
         let client = 1;
         let tx = 1;
         let amount = dec!(1.5);
-        let trx1 = withdrawal::Withdrawal::test(client, tx, amount);
+        let trx1 = withdrawal::Withdrawal::new(client, tx, amount);
         assert!(trx1.commit(&mut accounts).is_ok());
         
         let old_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let old_total = accounts.get(&client).expect("client 1 in test accounts").total();
-        let trx3 = Resolve {client, tx};
+        let trx3 = Resolve {client, tx, amount: None, timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_err());
         let new_balance = accounts.get(&client).expect("client 1 in test accounts").available;
         let new_total = accounts.get(&client).expect("client 1 in test accounts").total();
@@ -213,10 +275,10 @@ mod tests {
     fn several_disputes() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
         let client = 1; // initial balance dec!(2.0)
-        let trx = deposit::Deposit::test(client, 1, dec!(10.1));
+        let trx = deposit::Deposit::new(client, 1, dec!(10.1));
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(12.1), accounts.get(&client).expect("client 1 in test accounts").total());
-        let trx = deposit::Deposit::test(client, 2, dec!(10.2));
+        let trx = deposit::Deposit::new(client, 2, dec!(10.2));
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(22.3), accounts.get(&client).expect("client 1 in test accounts").total());
         
@@ -225,7 +287,11 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx: 1,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(22.3), accounts.get(&client).expect("client 1 in test accounts").total());
@@ -234,15 +300,44 @@ mod tests {
                 ttype:TransactionRecType::Dispute,
                 client,
                 tx: 2,
-                amount: None
+                amount: None,
+                timestamp: None,
+                reason: None,
+                currency: None,
+                to_currency: None,
             }).expect("Dispute transaction from transaction record");
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(22.3), accounts.get(&client).expect("client 1 in test accounts").total());
-        let trx = Resolve {client, tx: 2};
+        let trx = Resolve {client, tx: 2, amount: None, timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(22.3), accounts.get(&client).expect("client 1 in test accounts").total());
-        let trx = Resolve {client, tx: 1};
+        let trx = Resolve {client, tx: 1, amount: None, timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(22.3), accounts.get(&client).expect("client 1 in test accounts").total());
     }
+    
+    #[test]
+    fn partial_resolves() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let client = 1;
+        let tx = 1;
+        let trx1 = deposit::Deposit::new(client, tx, dec!(1.5));
+        assert!(trx1.commit(&mut accounts).is_ok());
+        let trx2 = dispute::Dispute::test(client, tx);
+        assert!(trx2.commit(&mut accounts).is_ok());
+
+        let trx3 = Resolve {client, tx, amount: Some(dec!(1.0)), timestamp: None, currency: None};
+        assert!(trx3.commit(&mut accounts).is_ok());
+        let trx4 = Resolve {client, tx, amount: Some(dec!(1.0)), timestamp: None, currency: None};
+        assert!(trx4.commit(&mut accounts).is_err()); // exceeds disputed part
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(3.0));
+        assert_eq!(acct.held, dec!(0.5));
+        let trx5 = Resolve {client, tx, amount: None, timestamp: None, currency: None};
+        assert!(trx5.commit(&mut accounts).is_ok()); // resolves the rest
+        let acct = accounts.get(&client).expect("client 1 in test accounts");
+        assert_eq!(acct.available, dec!(3.5));
+        assert_eq!(acct.held, dec!(0.0));
+        assert!(!acct.transactions.get(tx).expect("stored transaction").is_disputed());
+    }
 }
@@ -0,0 +1,101 @@
+use anyhow::{Result, bail};
+
+use crate::{TClientId, TTrxID, TTimestamp};
+use crate::accounts::{AdminEntry, AdminKind};
+use super::*;
+
+/// Represents Unlock transaction - administrative operation accepted only from privileged input.
+pub struct Unlock {
+    client: TClientId,
+    tx: TTrxID,
+    reason: Option<String>,
+    timestamp: Option<TTimestamp>,
+}
+
+impl TryFrom<TransactionRec> for Unlock {
+    type Error = anyhow::Error;
+    fn try_from(value: TransactionRec) -> Result<Self, Self::Error> {
+        if value.ttype != TransactionRecType::Unlock {
+            bail!("Transaction ID {} - Incompatible type expected Unlock", value.tx)
+        } else {
+            Ok(Self {client: value.client, tx: value.tx, reason: value.reason, timestamp: value.timestamp})
+        }
+    }
+}
+
+impl TransactionInt for Unlock {
+    fn id(&self) -> TTrxID {self.tx}
+
+    fn client_id(&self) -> TClientId {self.client}
+
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn validate(&self) -> TransactionValid {
+        TransactionValid::Ok
+    }
+
+    /// Performs Unlock transaction.
+    /// - if account is not registered - reject.
+    /// - if account is not locked - reject.
+    /// - if there is already registered administrative transaction with the same ID - reject.
+    /// - otherwise unlocks account and records operation in account administrative history.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, _rec_no: u128, _journal: &mut Journal) -> Result<()> {
+        match accounts.get_mut(&self.client) {
+            Some(acct) => {
+                if !acct.locked {
+                    bail!("Unlock transaction failed - account not locked")
+                }
+                if acct.admin.iter().any(|entry| entry.tx == self.tx && entry.kind != AdminKind::Fee) {
+                    bail!("Unlock transaction failed - duplicated transaction ID")
+                }
+                acct.locked = false;
+                acct.admin.push(AdminEntry {
+                    tx: self.tx,
+                    kind: AdminKind::Unlock,
+                    amount: TMoney::ZERO,
+                    reason: self.reason.clone(),
+                    timestamp: self.timestamp,
+                    currency: None,
+                });
+                Ok(())
+            }
+            None => bail!("Unlock transaction failed - client unknown")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use crate::transactions::TransactionInt;
+    use super::*;
+    use super::super::tests::create_accounts;
+
+    #[test]
+    fn on_locked() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
+        let trx = Unlock {client: 1, tx: 100, reason: Some("ticket 42".to_string()), timestamp: None};
+        assert!(trx.commit(&mut accounts).is_ok());
+        let acct = accounts.get(&1).expect("client 1 in test accounts");
+        assert!(!acct.locked);
+        assert_eq!(acct.admin.len(), 1);
+        assert_eq!(acct.admin[0].kind, AdminKind::Unlock);
+        assert_eq!(acct.admin[0].reason.as_deref(), Some("ticket 42"));
+    }
+
+    #[test]
+    fn on_not_locked() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let trx = Unlock {client: 1, tx: 100, reason: None, timestamp: None};
+        assert!(trx.commit(&mut accounts).is_err());
+        assert!(accounts.get(&1).expect("client 1 in test accounts").admin.is_empty());
+    }
+
+    #[test]
+    fn unknown_client() {
+        let mut accounts = create_accounts(&[dec!(2.0)]);
+        let trx = Unlock {client: 10, tx: 100, reason: None, timestamp: None};
+        assert!(trx.commit(&mut accounts).is_err());
+    }
+}
@@ -1,8 +1,7 @@
-use std::collections::hash_map::Entry;
 
 use anyhow::{Result, Context, bail};
 
-use crate::{TClientId, TMoney, TTrxID};
+use crate::{TClientId, TMoney, TTrxID, TTimestamp, currency::Currency};
 use super::*;
 
 /// Represents Withdrawal transaction.
@@ -10,13 +9,14 @@ pub struct Withdrawal {
     client: TClientId,
     tx: TTrxID,
     amount: TMoney,
+    timestamp: Option<TTimestamp>,
+    currency: Option<Currency>,
 }
 
-#[cfg(test)]
 impl Withdrawal {
-    #[allow(dead_code)]
-    pub fn test(client:TClientId, tx:TTrxID, amount:TMoney) -> Self {
-        Self {client, tx, amount}
+    /// Creates withdrawal in default currency without timestamp.
+    pub fn new(client:TClientId, tx:TTrxID, amount:TMoney) -> Self {
+        Self {client, tx, amount, timestamp: None, currency: None}
     }
 }
 
@@ -31,6 +31,8 @@ impl TryFrom<TransactionRec> for Withdrawal {
                 
                 client: value.client, 
                 tx: value.tx, 
+                timestamp: value.timestamp,
+                currency: value.currency,
                 amount: amount.try_into()
                     .with_context(|| format!("Transaction ID {} - Parsing float value: {}", value.tx, amount))?
             })
@@ -45,6 +47,10 @@ impl TransactionInt for Withdrawal {
 
     fn client_id(&self) -> TClientId {self.client}
 
+    fn timestamp(&self) -> Option<TTimestamp> {self.timestamp}
+
+    fn amount(&self) -> Option<TMoney> {Some(self.amount)}
+
     fn validate(&self) -> TransactionValid {
         if self.amount.is_sign_positive() {
             TransactionValid::Ok
@@ -55,21 +61,29 @@ impl TransactionInt for Withdrawal {
         }
     }
 
-    /// Performs Withdrawal transaction.
+    /// Performs Withdrawal transaction (in its currency).
     /// - if account is not registered - reject.
     /// - if account is locked - reject.
     /// - if there is already registered transaction with the same ID - reject.
+    /// - if transaction with the same or greater ID was evicted by retention policy - reject.
     /// - if account's `available` property is less then `amount` - reject.
     /// - otherwise decrease account `available` property of given `amount` and stores transaction amount (as negative value).
-    fn commit(&self, accounts:&mut HashMap::<TClientId,AccountState>) -> Result<()> {
+    ///
+    /// Withdrawn money is moved from client's available funds to settlement.
+    fn commit_journaled(&self, accounts:&mut HashMap::<TClientId,AccountState>, _policy: &Policy, rec_no: u128, journal: &mut Journal) -> Result<()> {
         match accounts.get_mut(&self.client) {
             Some(acct) => {
                 if acct.locked {
                     bail!("Withdrawal transaction failed - account locked")
-                } else if acct.available >= self.amount {
-                    if let Entry::Vacant(ent) = acct.transactions.entry(self.tx) {
-                        acct.available -= self.amount;
-                        ent.insert((false, -self.amount));
+                } else if acct.evicted.covers(self.tx) {
+                    bail!("Withdrawal transaction failed - transaction ID not greater than evicted ones")
+                } else if acct.balance(self.currency).available >= self.amount {
+                    let funds = acct.funds_mut(self.currency);
+                    if !funds.transactions.contains(self.tx) {
+                        change_balance(funds.available, funds.held, -self.amount, TMoney::ZERO)
+                            .context("Withdrawal transaction failed - balance overflow")?;
+                        funds.transactions.insert(self.tx, TrxEntry {currency: self.currency, ..TrxEntry::new(-self.amount, self.timestamp)});
+                        journal.transfer(rec_no, self.tx, self.currency, LedgerAccount::Available(self.client), LedgerAccount::Settlement, self.amount);
                         Ok(())
                     } else {
                         bail!("Withdrawal transaction failed - duplicated transaction ID")
@@ -94,14 +108,14 @@ mod tests {
     fn on_locked() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
         accounts.get_mut(&1).expect("client 1 in test accounts").locked = true;
-        let trx = Withdrawal {client: 1, tx: 1, amount: dec!(1.0)};
+        let trx = Withdrawal {client: 1, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_err());
     }
     
     #[test]
     fn on_normal() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Withdrawal {client: 1, tx: 1, amount: dec!(1.0)};
+        let trx = Withdrawal {client: 1, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
         let old_balance = accounts.get(&trx.client).expect("client 1 in test accounts").available;
         assert!(trx.commit(&mut accounts).is_ok());
         let new_balance = accounts.get(&trx.client).expect("client 1 in test accounts").available;
@@ -111,15 +125,15 @@ mod tests {
     #[test]
     fn unknown_client() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx = Withdrawal {client: 10, tx: 1, amount: dec!(1.0)};
-        assert!(accounts.get(&trx.client).is_none());
+        let trx = Withdrawal {client: 10, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
+        assert!(!accounts.contains_key(&trx.client));
         assert!(trx.commit(&mut accounts).is_err());
     }
     
     #[test]
     fn over_balance() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let mut trx = Withdrawal {client: 1, tx: 1, amount: dec!(1.0)};
+        let mut trx = Withdrawal {client: 1, tx: 1, amount: dec!(1.0), timestamp: None, currency: None};
         let old_balance = accounts.get(&trx.client).expect("client 1 in test accounts").available;
         trx.amount = old_balance + dec!(0.1);
         assert!(trx.commit(&mut accounts).is_err());
@@ -128,11 +142,11 @@ mod tests {
     #[test]
     fn duplicated_tx_id() {
         let mut accounts = create_accounts(&[dec!(2.0)]);
-        let trx1 = Withdrawal {client: 1, tx: 1, amount: dec!(0.1)};
+        let trx1 = Withdrawal {client: 1, tx: 1, amount: dec!(0.1), timestamp: None, currency: None};
         assert!(trx1.commit(&mut accounts).is_ok());
-        let trx2 = Withdrawal {client: 1, tx: 1, amount: dec!(0.1)};
+        let trx2 = Withdrawal {client: 1, tx: 1, amount: dec!(0.1), timestamp: None, currency: None};
         assert!(trx2.commit(&mut accounts).is_err()); // duplicated id
-        let trx3 = Withdrawal {client: 1, tx: 2, amount: dec!(0.1)};
+        let trx3 = Withdrawal {client: 1, tx: 2, amount: dec!(0.1), timestamp: None, currency: None};
         assert!(trx3.commit(&mut accounts).is_ok());
     }
     
@@ -141,13 +155,13 @@ mod tests {
         let mut accounts = create_accounts(&[dec!(0.0)]);
         let client = 1; 
         let mut id = 1;
-        let trx = deposit::Deposit::test(client, id, dec!(10.1)); id += 1;
+        let trx = deposit::Deposit::new(client, id, dec!(10.1)); id += 1;
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(10.1), accounts.get(&client).expect("client 1 in test accounts").total());
-        let trx = deposit::Deposit::test(client, id, dec!(10.2)); id += 1;
+        let trx = deposit::Deposit::new(client, id, dec!(10.2)); id += 1;
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(20.3), accounts.get(&client).expect("client 1 in test accounts").total());
-        let trx = Withdrawal {client, tx: id, amount: dec!(0.33)};
+        let trx = Withdrawal {client, tx: id, amount: dec!(0.33), timestamp: None, currency: None};
         assert!(trx.commit(&mut accounts).is_ok());
         assert_eq!(dec!(19.97), accounts.get(&client).expect("client 1 in test accounts").total());
     }
@@ -0,0 +1,158 @@
+use argh::FromArgs;
+
+use toy_trx_engine::{Args, process, process_with_stats};
+
+const SAMPLES: &[&str] = &[
+    "tests/samples/s_dep_with.csv",
+    "tests/samples/s_dep_dis_with_res.csv",
+    "tests/samples/s_dep_dis_with_chb.csv",
+    "tests/samples/s_dep_dis_res_chb.csv",
+    "tests/samples/i_ooo.csv",
+    "tests/samples/s_dispute_window.csv",
+    "tests/samples/s_timestamps.csv",
+    "tests/samples/s_partial.csv",
+    "tests/samples/s_admin.csv",
+    "tests/samples/s_dispute_policy.csv",
+    "tests/samples/s_fees.csv",
+    "tests/samples/s_currencies.csv",
+    "tests/samples/s_exchange.csv",
+    "tests/samples/s_retention.csv",
+    "tests/samples/s_dispute_expiry.csv",
+];
+
+#[async_std::test]
+async fn simple_engine() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let multi = Args::from_args(&[&arg0], &[sample, "--comments"]).expect("correxct command line");
+        let simple = Args::from_args(&[&arg0], &[sample, "--comments", "--engine", "simple"]).expect("correxct command line");
+        let (rec, accounts) = process(&multi).await.expect("success");
+        let (ref_rec, ref_accounts) = process(&simple).await.expect("success");
+        assert_eq!(rec, ref_rec, "{}", sample);
+        assert_eq!(accounts, ref_accounts, "{}", sample);
+    }
+}
+
+#[async_std::test]
+async fn verify() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let arg = Args::from_args(&[&arg0], &[sample, "--comments", "--verify", "--wrk-num", "2"]).expect("correxct command line");
+        process(&arg).await.expect("engines results match");
+    }
+}
+
+#[async_std::test]
+async fn verify_dispute_window() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let arg = Args::from_args(&[&arg0], &[sample, "--comments", "--verify", "--dispute-window", "1"]).expect("correxct command line");
+        process(&arg).await.expect("engines results match");
+    }
+}
+
+#[async_std::test]
+async fn verify_batching() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        for batch_size in ["1", "3"] {
+            let arg = Args::from_args(&[&arg0], &[sample, "--comments", "--verify", "--wrk-num", "3", "--batch-size", batch_size])
+                .expect("correxct command line");
+            process(&arg).await.expect("engines results match");
+        }
+    }
+}
+
+#[async_std::test]
+async fn verify_assignment() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        for assignment in ["round-robin", "hash", "load"] {
+            let arg = Args::from_args(&[&arg0], &[sample, "--comments", "--verify", "--wrk-num", "3", "--assignment", assignment])
+                .expect("correxct command line");
+            let (stats, _) = process_with_stats(&arg).await.expect("engines results match");
+            assert_eq!(stats.worker_processed.iter().sum::<u128>(), stats.processed, "{}", sample);
+            assert_eq!(stats.assignment.map(|assignment| assignment.to_string()).as_deref(), Some(assignment));
+        }
+    }
+}
+
+#[async_std::test]
+async fn verify_retention() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let arg = Args::from_args(&[&arg0], &[sample, "--comments", "--verify", "--retain-last", "1"]).expect("correxct command line");
+        process(&arg).await.expect("engines results match");
+    }
+}
+
+#[async_std::test]
+async fn verify_dispute_policy() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let arg = Args::from_args(&[&arg0], &[sample, "--comments", "--verify", "--dispute-policy", "cap"]).expect("correxct command line");
+        process(&arg).await.expect("engines results match");
+    }
+}
+
+#[async_std::test]
+async fn verify_fees() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let arg = Args::from_args(
+            &[&arg0], 
+            &[sample, "--comments", "--verify", "--wrk-num", "3", "--fee-schedule", "tests/samples/f_fees.csv", "--fee-account", "9999", "--dispute-window", "1", "--dispute-expiry", "chargeback"]
+        ).expect("correxct command line");
+        process(&arg).await.expect("engines results match");
+    }
+}
+
+#[async_std::test]
+async fn verify_ledger() {
+    let arg0 = std::env::args().next().unwrap();
+    for sample in SAMPLES {
+        let arg = Args::from_args(
+            &[&arg0], 
+            &[
+                sample, "--comments", "--verify", "--wrk-num", "3", "--privileged", "--history",
+                "--fee-schedule", "tests/samples/f_fees.csv", "--fee-account", "9999", "--dispute-window", "2", "--dispute-expiry", "chargeback",
+                "--base-currency", "usd", "--exchange-rates", "tests/samples/r_rates.csv",
+                "verify-ledger"
+            ]
+        ).expect("correxct command line");
+        let (stats, accounts) = process_with_stats(&arg).await.expect("engines results match");
+        let verification = stats.journal.verify(&accounts);
+        assert!(verification.is_ok(), "{}: {}", sample, verification);
+    }
+}
+
+#[test]
+fn unknown_engine() {
+    let arg0 = std::env::args().next().unwrap();
+    assert!(Args::from_args(&[&arg0], &[SAMPLES[0], "--engine", "quantum"]).is_err());
+}
+
+#[test]
+fn unknown_assignment() {
+    let arg0 = std::env::args().next().unwrap();
+    assert!(Args::from_args(&[&arg0], &[SAMPLES[0], "--assignment", "random"]).is_err());
+}
+
+#[test]
+fn verify_diagnostics_once() {
+    let path = std::env::temp_dir().join(format!("toy_trx_engine_diagnostics_{}.csv", std::process::id()));
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,5.0\ndispute,1,1,\ndispute,1,1,\n").expect("input file written");
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_toy_trx_engine"))
+        .arg(&path)
+        .args(["--verify", "--wrk-num", "3"])
+        .output();
+    std::fs::remove_file(&path).expect("input file removed");
+    let output = output.expect("engine run");
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    assert_eq!(lines, [
+        "Record# 2, Transaction ID = 2 - failed: Withdrawal transaction failed - not enough funds",
+        "Record# 4, Transaction ID = 1 - warning - repeated Dispute",
+    ]);
+}
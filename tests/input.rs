@@ -1,7 +1,11 @@
 use argh::FromArgs;
 use rust_decimal_macros::dec;
 
-use toy_trx_engine::{Args, process};
+use toy_trx_engine::{
+    Args, process,
+    generator::{GenConfig, generate},
+    parser::BLOCK_SIZE,
+};
 
 #[async_std::test]
 async fn amt_formats() {
@@ -67,3 +71,22 @@ async fn out_of_order() {
     let acct_no = accounts.len();
     assert_eq!(acct_no, 5);
 }
+
+#[async_std::test]
+async fn parallel_parsing() {
+    let path = std::env::temp_dir().join(format!("toy_trx_engine_parallel_{}.csv", std::process::id()));
+    let cfg = GenConfig {size: 200_000, clients: 1000, invalid_ratio: 0.01, ..Default::default()};
+    let mut out = std::io::BufWriter::new(std::fs::File::create(&path).expect("input file created"));
+    generate(&cfg, &mut out).expect("generated input");
+    drop(out);
+    assert!(std::fs::metadata(&path).expect("input file").len() > 2 * BLOCK_SIZE);
+    let path = path.to_str().expect("temp path is valid unicode").to_string();
+    let arg0 = std::env::args().next().unwrap();
+    let sequential = Args::from_args(&[&arg0], &[&path]).expect("correxct command line");
+    let parallel = Args::from_args(&[&arg0], &[&path, "--parse-threads", "4", "--verify"]).expect("correxct command line");
+    let (rec, accounts) = process(&sequential).await.expect("success");
+    let (par_rec, par_accounts) = process(&parallel).await.expect("success");
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(rec, par_rec);
+    assert_eq!(accounts, par_accounts);
+}
@@ -1,10 +1,18 @@
+use std::collections::HashMap;
+use std::path::Path;
+
 use argh::FromArgs;
 use rust_decimal_macros::dec;
 
-use toy_trx_engine::{Args, process};
+use toy_trx_engine::{Args, TClientId, process, process_with_stats, reconcile, statement, accounts::{self, AccountState, HistoryKind}};
 
 // TODO: Write and use function that count lines in test file.
 
+async fn assert_reconciled(expected: &str, accounts: &HashMap::<TClientId,AccountState>) {
+    let report = reconcile::reconcile_file(Path::new(expected), accounts).await.expect("expected balances file");
+    assert!(report.is_ok(), "{}", report);
+}
+
 #[async_std::test]
 async fn dep_with() {
     let arg0 = std::env::args().next().unwrap();
@@ -14,10 +22,7 @@ async fn dep_with() {
     ).expect("correxct command line");
     let (rec, accounts) = process(&arg).await.expect("success");
     assert_eq!(rec, 3);
-    let total1 = accounts.get(&1).expect("client 2 in test file").total();
-    let total2 = accounts.get(&2).expect("client 2 in test file").total();
-    assert_eq!(total1, dec!(4.5));
-    assert_eq!(total2, dec!(10.0));
+    assert_reconciled("tests/samples/e_dep_with.csv", &accounts).await;
 }
 
 #[async_std::test]
@@ -32,10 +37,7 @@ async fn dep_dis_with_res() {
     ).expect("correxct command line");
     let (rec, accounts) = process(&arg).await.expect("success");
     assert_eq!(rec, 5); // trx#4 and 7 should fail
-    let total1 = accounts.get(&1).expect("client 2 in test file").total();
-    let total2 = accounts.get(&2).expect("client 2 in test file").total();
-    assert_eq!(total1, dec!(7.5));
-    assert_eq!(total2, dec!(10.0));
+    assert_reconciled("tests/samples/e_dep_dis_with_res.csv", &accounts).await;
 }
 
 #[async_std::test]
@@ -50,11 +52,7 @@ async fn dep_dis_with_chb() {
     ).expect("correxct command line");
     let (rec, accounts) = process(&arg).await.expect("success");
     assert_eq!(rec, 4); // trx#4 and 6 should fail
-    let total1 = accounts.get(&1).expect("client 2 in test file").total();
-    let total2 = accounts.get(&2).expect("client 2 in test file").total();
-    assert_eq!(total1, dec!(0.0));
-    assert_eq!(total2, dec!(10.0));
-    assert!(accounts.get(&1).expect("client 2 in test file").locked);
+    assert_reconciled("tests/samples/e_dep_dis_with_chb.csv", &accounts).await;
 }
 
 #[async_std::test]
@@ -69,7 +67,346 @@ async fn dep_dis_res_chb() {
     ).expect("correxct command line");
     let (rec, accounts) = process(&arg).await.expect("success");
     assert_eq!(rec, 8); // all trx should succeeded
-    let total = accounts.get(&1).expect("client 2 in test file").total();
-    assert_eq!(total, dec!(26.0));
-    assert!(accounts.get(&1).expect("client 2 in test file").locked);
+    assert_reconciled("tests/samples/e_dep_dis_res_chb.csv", &accounts).await;
+}
+
+#[async_std::test]
+async fn partial_disputes() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_partial.csv",
+            "--comments"
+        ]
+    ).expect("correxct command line");
+    let (rec, accounts) = process(&arg).await.expect("success");
+    assert_eq!(rec, 7); // dispute exceeding not disputed part should fail
+    assert_reconciled("tests/samples/e_partial.csv", &accounts).await;
+}
+
+#[async_std::test]
+async fn admin_privileged() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_admin.csv",
+            "--comments",
+            "--privileged"
+        ]
+    ).expect("correxct command line");
+    let (rec, accounts) = process(&arg).await.expect("success");
+    assert_eq!(rec, 8); // deposit to locked account and adjust without reason should fail
+    assert_reconciled("tests/samples/e_admin.csv", &accounts).await;
+    assert_eq!(accounts[&1].admin.len(), 2);
+    assert_eq!(accounts[&1].admin[1].reason.as_deref(), Some("ticket OPS-17"));
+}
+
+#[async_std::test]
+async fn admin_not_privileged() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_admin.csv",
+            "--comments"
+        ]
+    ).expect("correxct command line");
+    let (rec, accounts) = process(&arg).await.expect("success");
+    assert_eq!(rec, 4); // administrative records should be skipped
+    assert!(accounts[&1].locked);
+    assert_eq!(accounts[&2].available, dec!(5));
+    assert!(accounts.values().all(|acct| acct.admin.is_empty()));
+}
+
+#[async_std::test]
+async fn dispute_policies() {
+    let arg0 = std::env::args().next().unwrap();
+    // policy, processed, client 1 (available, held), client 2 (available, held)
+    let cases = [
+        ("allow-negative", 6, (dec!(-8), dec!(10)), (dec!(5), dec!(-1))),
+        ("reject", 4, (dec!(2), dec!(0)), (dec!(4), dec!(0))),
+        ("cap", 5, (dec!(0), dec!(2)), (dec!(4), dec!(0))),
+        ("deposits-only", 5, (dec!(-8), dec!(10)), (dec!(4), dec!(0))),
+    ];
+    for (policy, processed, client1, client2) in cases {
+        let arg = Args::from_args(
+            &[&arg0],
+            &[
+                "tests/samples/s_dispute_policy.csv",
+                "--comments",
+                "--dispute-policy", policy
+            ]
+        ).expect("correxct command line");
+        let (rec, accounts) = process(&arg).await.expect("success");
+        assert_eq!(rec, processed, "{}", policy);
+        assert_eq!((accounts[&1].available, accounts[&1].held), client1, "{}", policy);
+        assert_eq!((accounts[&2].available, accounts[&2].held), client2, "{}", policy);
+        let negative = if client1.0 < dec!(0) {vec![1]} else {vec![]};
+        assert_eq!(accounts::negative_accounts(&accounts), negative, "{}", policy);
+    }
+}
+
+#[async_std::test]
+async fn fees() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_fees.csv",
+            "--comments",
+            "--fee-schedule", "tests/samples/f_fees.csv",
+            "--fee-account", "9"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 7);
+    assert_eq!(stats.fees, dec!(16.5));
+    assert_reconciled("tests/samples/e_fees.csv", &accounts).await;
+    assert_eq!(accounts[&1].admin.len(), 2);
+    assert_eq!(accounts[&2].admin[0].amount, dec!(-15));
+}
+
+#[async_std::test]
+async fn missing_fee_schedule() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_fees.csv", "--comments", "--fee-schedule", "tests/samples/no_such_file.csv", "--fee-account", "9"]
+    ).expect("correxct command line");
+    assert!(process_with_stats(&arg).await.is_err());
+}
+
+#[async_std::test]
+async fn missing_fee_account() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_fees.csv", "--comments", "--fee-schedule", "tests/samples/f_fees.csv"]
+    ).expect("correxct command line");
+    assert!(process_with_stats(&arg).await.is_err());
+    let arg = Args::from_args(&[&arg0], &["tests/samples/s_fees.csv", "--comments", "--fee-account", "9"])
+        .expect("correxct command line");
+    assert!(process_with_stats(&arg).await.is_err());
+}
+
+#[async_std::test]
+async fn dispute_window_resolve() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dispute_window.csv",
+            "--comments",
+            "--dispute-window", "2"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 8);
+    assert_eq!(stats.auto_resolved, 1);
+    assert_eq!(stats.auto_charged_back, 0);
+    assert_reconciled("tests/samples/e_dispute_window_res.csv", &accounts).await;
+}
+
+#[async_std::test]
+async fn dispute_window_chargeback() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dispute_window.csv",
+            "--comments",
+            "--dispute-window", "2",
+            "--dispute-expiry", "chargeback"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 7); // last deposit should fail - account locked by auto-chargeback
+    assert_eq!(stats.auto_resolved, 0);
+    assert_eq!(stats.auto_charged_back, 1);
+    assert_reconciled("tests/samples/e_dispute_window_chb.csv", &accounts).await;
+}
+
+#[async_std::test]
+async fn dispute_expiry_at_end() {
+    let arg0 = std::env::args().next().unwrap();
+    for (action, client1) in [("resolve", (dec!(10), dec!(0), false)), ("chargeback", (dec!(0), dec!(0), true))] {
+        let arg = Args::from_args(
+            &[&arg0],
+            &[
+                "tests/samples/s_dispute_expiry.csv",
+                "--comments",
+                "--dispute-window", "60",
+                "--dispute-window-unit", "seconds",
+                "--dispute-expiry", action
+            ]
+        ).expect("correxct command line");
+        let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+        assert_eq!(stats.processed, 6, "{}", action);
+        assert_eq!(stats.auto_resolved + stats.auto_charged_back, 1, "{}", action);
+        assert_eq!((accounts[&1].available, accounts[&1].held, accounts[&1].locked), client1, "{}", action);
+        assert_eq!((accounts[&2].available, accounts[&2].held), (dec!(0), dec!(5)), "{}", action);
+    }
+
+    // window of client transactions used up by the last record
+    let path = std::env::temp_dir().join(format!("toy_trx_engine_expiry_{}.csv", std::process::id()));
+    std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\ndeposit,1,2,1.0\ndeposit,1,3,1.0\n").expect("input file written");
+    let arg = Args::from_args(&[&arg0], &[path.to_str().expect("temp path is valid unicode"), "--dispute-window", "2", "--verify"])
+        .expect("correxct command line");
+    let result = process_with_stats(&arg).await;
+    std::fs::remove_file(&path).expect("input file removed");
+    let (stats, accounts) = result.expect("success");
+    assert_eq!(stats.auto_resolved, 1);
+    assert_eq!((accounts[&1].available, accounts[&1].held), (dec!(12), dec!(0)));
+}
+
+#[async_std::test]
+async fn timestamps_reject() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_timestamps.csv",
+            "--comments",
+            "--ts-order", "reject",
+            "--dispute-window", "60",
+            "--dispute-window-unit", "seconds"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 6);
+    assert_eq!(stats.out_of_order, 1);
+    assert_eq!(stats.auto_resolved, 1);
+    assert_reconciled("tests/samples/e_timestamps.csv", &accounts).await;
+    assert_eq!(accounts[&1].transactions.get(1).expect("stored transaction").timestamp, Some(1_704_067_200_000));
+    assert_eq!(accounts[&1].transactions.get(6).expect("stored transaction").timestamp, Some(1_704_067_320_500));
+}
+
+#[async_std::test]
+async fn timestamps_warn() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_timestamps.csv", "--comments", "--ts-order", "warn"]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 7);
+    assert_eq!(stats.out_of_order, 1);
+    assert_eq!(stats.auto_resolved, 0);
+    assert_eq!(accounts[&1].held, dec!(10));
+    assert_eq!(accounts[&2].available, dec!(6));
+}
+
+#[async_std::test]
+async fn mismatch() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_dep_with.csv"]
+    ).expect("correxct command line");
+    let (_, accounts) = process(&arg).await.expect("success");
+    let report = reconcile::reconcile_file(Path::new("tests/samples/e_dep_dis_with_chb.csv"), &accounts).await.expect("expected balances file");
+    assert_eq!(report.counts(), (0, 0, 3)); // client 1: available, total, locked
+}
+
+#[async_std::test]
+async fn currencies() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_currencies.csv", "--comments", "--base-currency", "usd"]
+    ).expect("correxct command line");
+    let (rec, accounts) = process(&arg).await.expect("success");
+    assert_eq!(rec, 7); // over balance withdrawal and resolve in other currency should fail
+    let eur = "EUR".parse().expect("currency code");
+    let acct = &accounts[&1];
+    assert_eq!((acct.available, acct.held), (dec!(100), dec!(0)));
+    let balance = acct.balance(Some(eur));
+    assert_eq!((balance.available, balance.held), (dec!(-10), dec!(30)));
+    assert!(acct.locked);
+    assert!(acct.is_negative());
+    assert_eq!(accounts[&2].balances().count(), 2);
+    assert_eq!(accounts[&2].available, dec!(20));
+}
+
+#[async_std::test]
+async fn exchange() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_exchange.csv",
+            "--comments",
+            "--base-currency", "usd",
+            "--exchange-rates", "tests/samples/r_rates.csv",
+            "--exchange-spread", "1",
+        ]
+    ).expect("correxct command line");
+    let (rec, accounts) = process(&arg).await.expect("success");
+    assert_eq!(rec, 7); // exchange without rate should fail
+    let eur = "EUR".parse().ok();
+    let acct = &accounts[&1];
+    assert_eq!((acct.available, acct.held), (dec!(100), dec!(0)));
+    assert_eq!(acct.balance(eur).total(), dec!(0));
+    assert!(acct.locked);
+    let acct = &accounts[&2];
+    assert_eq!(acct.available, dec!(60.89)); // 50 + 10 * 1.1 * 0.99
+    assert_eq!(acct.balance(eur).available, dec!(29.6)); // 50 * 0.8 * 0.99 - 10
+}
+
+#[async_std::test]
+async fn statement() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_dep_dis_with_chb.csv", "--comments", "--opening-balances", "tests/samples/o_balances.csv", "statement", "--client", "1"]
+    ).expect("correxct command line");
+    let (_, accounts) = process(&arg).await.expect("success");
+    let statements = statement::statements(&accounts, &[1, 99]);
+    assert_eq!(statements.len(), 1);
+    let statement = &statements[0];
+    assert_eq!(statement.opening[0].available, dec!(100));
+    let kinds: Vec<_> = statement.entries.iter().map(|entry| entry.kind).collect();
+    assert_eq!(kinds, [HistoryKind::Deposit, HistoryKind::Dispute, HistoryKind::Withdrawal, HistoryKind::Chargeback]);
+    let chargeback = &statement.entries[3];
+    assert_eq!((chargeback.rec_no, chargeback.charged_back), (5, Some(dec!(10))));
+    assert_eq!(statement.closing[0].total, accounts[&1].total());
+    assert!(statement.locked);
+}
+
+#[async_std::test]
+async fn retention() {
+    let arg0 = std::env::args().next().unwrap();
+    let spill = std::env::temp_dir().join(format!("toy_trx_engine_spill_{}.csv", std::process::id()));
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_retention.csv", "--comments", "--retain-last", "2", "--retention-spill", spill.to_str().expect("temp path is valid unicode")]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 7); // dispute of evicted transaction should fail
+    assert_eq!(stats.evicted, 3);
+    let acct = &accounts[&1];
+    assert_eq!((acct.available, acct.held), (dec!(15), dec!(0)));
+    assert_eq!(acct.transactions.len(), 2);
+    assert!((1..=3).all(|tx| acct.evicted.contains(tx)) && !acct.evicted.contains(4));
+    let spilled = std::fs::read_to_string(&spill).expect("spill file written");
+    let _ = std::fs::remove_file(&spill);
+    let spilled: Vec<_> = spilled.lines().skip(1).map(|line| line.split(',').take(3).collect::<Vec<_>>().join(",")).collect();
+    assert_eq!(spilled, ["1,1,1", "1,3,3", "1,2,2"]);
+}
+
+#[async_std::test]
+async fn balance_overflow() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &["tests/samples/s_overflow.csv", "--comments", "--wrk-num", "2"]
+    ).expect("correxct command line");
+    let (rec, accounts) = process(&arg).await.expect("success");
+    assert_eq!(rec, 5); // second dispute should fail without crashing the worker
+    let acct = &accounts[&1];
+    assert_eq!(acct.available, dec!(0));
+    assert!(acct.held > dec!(4.9e28)); // amount is parsed through f64
+    assert!(accounts.contains_key(&2));
 }
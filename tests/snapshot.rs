@@ -0,0 +1,144 @@
+use argh::FromArgs;
+use rust_decimal_macros::dec;
+
+use toy_trx_engine::{Args, process, process_with_stats, snapshot, ledger::LedgerAccount};
+
+#[async_std::test]
+async fn day_after_day() {
+    let snapshot = std::env::temp_dir().join(format!("toy_trx_engine_snapshot_{}.json", std::process::id()));
+    let snapshot = snapshot.to_str().expect("temp path is valid unicode");
+    let arg0 = std::env::args().next().unwrap();
+
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dep_with.csv",
+            "--snapshot-out", snapshot
+        ]
+    ).expect("correxct command line");
+    let (rec, _) = process(&arg).await.expect("success");
+    assert_eq!(rec, 3);
+
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_snapshot_day2.csv",
+            "--comments",
+            "--snapshot-in", snapshot
+        ]
+    ).expect("correxct command line");
+    let result = process(&arg).await;
+    std::fs::remove_file(snapshot).expect("snapshot file removed");
+    let (rec, accounts) = result.expect("success");
+    assert_eq!(rec, 2); // withdrawal should fail - deposit from previous day is disputed
+    let acct1 = accounts.get(&1).expect("client 1 in snapshot");
+    assert_eq!(acct1.available, dec!(-5.5));
+    assert_eq!(acct1.held, dec!(10.0));
+    assert_eq!(acct1.total(), dec!(4.5));
+    let total2 = accounts.get(&2).expect("client 2 in snapshot").total();
+    assert_eq!(total2, dec!(11.0));
+}
+
+#[async_std::test]
+async fn missing_snapshot() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dep_with.csv",
+            "--snapshot-in", "tests/samples/no_such_snapshot.json"
+        ]
+    ).expect("correxct command line");
+    assert!(process(&arg).await.is_err());
+}
+
+#[async_std::test]
+async fn opening_balances() {
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dep_with.csv",
+            "--opening-balances", "tests/samples/o_balances.csv"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.processed, 3);
+    assert_eq!(stats.seeded, 2);
+    let total1 = accounts.get(&1).expect("client 1 in test file").total();
+    let total2 = accounts.get(&2).expect("client 2 in test file").total();
+    let acct3 = accounts.get(&3).expect("client 3 in opening balances");
+    assert_eq!(total1, dec!(104.5));
+    assert_eq!(total2, dec!(10.0));
+    assert_eq!(acct3.total(), dec!(7.25));
+    assert!(acct3.locked);
+}
+
+#[async_std::test]
+async fn interest_in_snapshot() {
+    let snapshot = std::env::temp_dir().join(format!("toy_trx_engine_interest_{}.json", std::process::id()));
+    let arg0 = std::env::args().next().unwrap();
+
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dep_with.csv",
+            "--snapshot-out", snapshot.to_str().expect("temp path is valid unicode"),
+            "accrue-interest",
+            "--rate", "1",
+            "--tier", "5:2",
+            "--min-balance", "1"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    assert_eq!(stats.interest_accounts, 2);
+    assert_eq!(stats.interest, dec!(0.195));
+    assert_eq!(accounts[&1].available, dec!(4.545));
+
+    let result = snapshot::load(&snapshot).await;
+    std::fs::remove_file(&snapshot).expect("snapshot file removed");
+    let saved = result.expect("snapshot saved");
+    assert_eq!(saved, accounts);
+    assert_eq!(saved[&2].available, dec!(10.15)); // 5 * 1% + 5 * 2%
+    assert_eq!(saved[&2].transactions.get(4).expect("stored transaction").amount, dec!(0.15)); // next to the greatest stored transaction ID
+}
+
+#[async_std::test]
+async fn invalid_interest() {
+    let snapshot = std::env::temp_dir().join(format!("toy_trx_engine_invalid_interest_{}.json", std::process::id()));
+    let arg0 = std::env::args().next().unwrap();
+    for settings in [["--rate", "-1", "--tier", "5:2"], ["--rate", "1", "--tier", "-5:2"], ["--rate", "1", "--tier", "5:-2"]] {
+        let mut args = vec!["tests/samples/s_dep_with.csv", "--snapshot-out", snapshot.to_str().expect("temp path is valid unicode"), "accrue-interest"];
+        args.extend(settings);
+        let arg = Args::from_args(&[&arg0], &args).expect("correxct command line");
+        assert!(process_with_stats(&arg).await.is_err(), "{:?}", settings);
+        assert!(!snapshot.exists(), "{:?}", settings);
+    }
+}
+
+#[async_std::test]
+async fn journal_with_opening_and_interest() {
+    let journal = std::env::temp_dir().join(format!("toy_trx_engine_journal_{}.csv", std::process::id()));
+    let arg0 = std::env::args().next().unwrap();
+    let arg = Args::from_args(
+        &[&arg0],
+        &[
+            "tests/samples/s_dep_with.csv",
+            "--opening-balances", "tests/samples/o_balances.csv",
+            "--journal", journal.to_str().expect("temp path is valid unicode"),
+            "accrue-interest",
+            "--rate", "1"
+        ]
+    ).expect("correxct command line");
+    let (stats, accounts) = process_with_stats(&arg).await.expect("success");
+    let verification = stats.journal.verify(&accounts);
+    assert!(verification.is_ok(), "{}", verification);
+    assert!(stats.journal.postings.iter().any(|posting| posting.account == LedgerAccount::Opening));
+    assert!(stats.journal.postings.iter().any(|posting| posting.account == LedgerAccount::InterestExpense));
+
+    let result = std::fs::read_to_string(&journal);
+    std::fs::remove_file(&journal).expect("journal file removed");
+    let saved = result.expect("journal saved");
+    assert_eq!(saved, stats.journal.to_csv());
+    assert!(saved.starts_with("rec_no,tx,account,currency,amount\n0,0,opening,,"));
+}